@@ -0,0 +1,98 @@
+// Run with cargo bench. Timed by hand rather than with the unstable test harness, so it builds on stable
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use voi_synth::{Synth, Buffer, NodeContainer, EvaluationMode};
+use voi_synth::context::EvaluationContext;
+
+// Same shape as test_perf in the testbed
+fn oscillator_bank() -> Synth {
+	let mut synth = Synth::new();
+	synth.set_gain(0.3);
+
+	let freq_param = synth.new_parameter();
+	let freq = synth.new_lowpass(freq_param, 10.0);
+
+	let mut osc = synth.new_sine(freq);
+
+	for i in 0..16 {
+		let freq = synth.new_multiply(freq, 1.0 + i as f32 / 30.0);
+		let s = synth.new_saw(freq);
+		let s = synth.new_multiply(s, 0.5);
+		osc = synth.new_add(osc, s);
+	}
+
+	let osc = synth.new_clamp(osc, -1.0, 1.0);
+	let [left, right] = synth.new_pan(osc, 0.3);
+	synth.set_outputs(&[left, right]);
+
+	synth.get_parameter(freq_param).set_value(440.0);
+	synth
+}
+
+// A single sample feedback loop through a store, followed by a chain that can be evaluated in blocks
+fn feedback_into_chain() -> Synth {
+	let mut synth = Synth::new();
+
+	let feedback = synth.new_value_store();
+	let fm = synth.new_multiply(feedback, 180.0);
+	let freq = synth.new_add(220.0, fm);
+	let osc = synth.new_sine(freq);
+	synth.new_store_write(feedback, osc);
+
+	let mut chain = osc;
+	for i in 0..16 {
+		let freq = synth.new_multiply(freq, 1.0 + i as f32 / 20.0);
+		let s = synth.new_triangle(freq);
+		chain = synth.new_mix(chain, s, 0.5);
+	}
+
+	let chain = synth.new_lowpass(chain, 2000.0);
+	synth.set_output(chain);
+	synth
+}
+
+const WARMUP_ITERATIONS: u32 = 50;
+const MIN_BENCH_TIME: Duration = Duration::from_secs(1);
+
+// Average time to fill one buffer
+fn bench_synth(mut synth: Synth, mode: EvaluationMode) -> Duration {
+	synth.set_evaluation_mode(mode);
+
+	let mut eval_ctx = EvaluationContext::new(44100.0);
+	let mut buffer = Buffer::with_channels(1024, 2);
+
+	let mut fill = || {
+		buffer.clear();
+		synth.evaluate_into_buffer(&mut buffer, &mut eval_ctx);
+		black_box(&buffer.data);
+	};
+
+	for _ in 0..WARMUP_ITERATIONS {
+		fill();
+	}
+
+	let begin = Instant::now();
+	let mut iterations = 0;
+
+	while begin.elapsed() < MIN_BENCH_TIME {
+		fill();
+		iterations += 1;
+	}
+
+	begin.elapsed() / iterations
+}
+
+fn bench_case(name: &str, build: fn() -> Synth) {
+	let per_sample = bench_synth(build(), EvaluationMode::PerSample);
+	let block = bench_synth(build(), EvaluationMode::Block);
+
+	println!("{:16} per sample: {:>9.1?}/iter   block: {:>9.1?}/iter   speedup: {:.2}x",
+		name, per_sample, block, per_sample.as_secs_f64() / block.as_secs_f64());
+}
+
+fn main() {
+	bench_case("oscillator_bank", oscillator_bank);
+	bench_case("feedback", feedback_into_chain);
+}
@@ -0,0 +1,110 @@
+use crate::SynthResult;
+use crate::loader::{AudioData, Endianness, decode_pcm, decode_float};
+
+use failure::{err_msg, bail, ensure};
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+	u16::from_be_bytes([bytes[offset], bytes[offset+1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+	u32::from_be_bytes([bytes[offset], bytes[offset+1], bytes[offset+2], bytes[offset+3]])
+}
+
+// Sample rates in aiff are stored as 80 bit IEEE 754 extended precision floats
+fn read_extended(bytes: &[u8]) -> f64 {
+	let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+	let exponent = (((bytes[0] & 0x7F) as i32) << 8) | bytes[1] as i32;
+
+	let mut mantissa_bytes = [0u8; 8];
+	mantissa_bytes.copy_from_slice(&bytes[2..10]);
+	let mantissa = u64::from_be_bytes(mantissa_bytes);
+
+	if exponent == 0 && mantissa == 0 {
+		return 0.0;
+	}
+
+	sign * mantissa as f64 * 2.0f64.powi(exponent - 16383 - 63)
+}
+
+// Decodes the contents of an AIFF or AIFF-C file.
+// AIFF-C is only supported for uncompressed ('NONE', 'sowt') and float ('fl32', 'fl64') data
+pub(crate) fn decode_aiff(bytes: &[u8]) -> SynthResult<AudioData> {
+	ensure!(bytes.len() >= 12 && &bytes[0..4] == b"FORM", "Not an aiff file");
+
+	let is_aifc = match &bytes[8..12] {
+		b"AIFF" => false,
+		b"AIFC" => true,
+		_ => bail!("Not an aiff file"),
+	};
+
+	let mut common = None;
+	let mut sound_data = None;
+
+	let mut offset = 12;
+	while offset + 8 <= bytes.len() {
+		let chunk_id = &bytes[offset..offset+4];
+		let chunk_size = read_u32(bytes, offset+4) as usize;
+		let body_start = offset + 8;
+		let body_end = (body_start + chunk_size).min(bytes.len());
+		let body = &bytes[body_start..body_end];
+
+		match chunk_id {
+			b"COMM" => {
+				ensure!(body.len() >= 18, "Aiff COMM chunk is truncated");
+
+				let channels = read_u16(body, 0);
+				let frames = read_u32(body, 2);
+				let bits = read_u16(body, 6);
+				let sample_rate = read_extended(&body[8..18]);
+
+				let compression = if is_aifc {
+					ensure!(body.len() >= 22, "Aiff-c COMM chunk is truncated");
+					let mut id = [0u8; 4];
+					id.copy_from_slice(&body[18..22]);
+					id
+				} else {
+					*b"NONE"
+				};
+
+				common = Some((channels, frames, bits, sample_rate, compression));
+			}
+
+			b"SSND" => {
+				ensure!(body.len() >= 8, "Aiff SSND chunk is truncated");
+				let data_offset = read_u32(body, 0) as usize;
+				ensure!(8 + data_offset <= body.len(), "Aiff SSND chunk has invalid data offset");
+				sound_data = Some(&body[8 + data_offset..]);
+			}
+
+			_ => {}
+		}
+
+		// Chunks are word aligned
+		offset = body_start + chunk_size + (chunk_size & 1);
+	}
+
+	let (channels, frames, bits, sample_rate, compression) = common.ok_or_else(|| err_msg("Aiff file has no COMM chunk"))?;
+	let data = sound_data.ok_or_else(|| err_msg("Aiff file has no SSND chunk"))?;
+
+	ensure!(channels > 0, "Aiff file has no channels");
+	ensure!(sample_rate > 0.0, "Aiff file has an invalid sample rate: {}", sample_rate);
+
+	let mut samples = match &compression {
+		b"NONE" | b"twos" => decode_pcm(data, bits, Endianness::Big, false)?,
+		b"sowt" => decode_pcm(data, bits, Endianness::Little, false)?,
+		b"fl32" | b"FL32" => decode_float(data, 32, Endianness::Big)?,
+		b"fl64" | b"FL64" => decode_float(data, 64, Endianness::Big)?,
+		_ => bail!("Unsupported aiff-c compression type: '{}'", String::from_utf8_lossy(&compression)),
+	};
+
+	let channels = channels as usize;
+	samples.truncate(frames as usize * channels);
+	samples.truncate(samples.len() / channels * channels);
+
+	Ok(AudioData {
+		sample_rate: sample_rate as f32,
+		channels,
+		samples,
+	})
+}
@@ -0,0 +1,272 @@
+use crate::synth::{Synth, StoreID};
+use crate::node::{Node, NodeID, Input};
+use crate::node::{saw_shape, square_shape, triangle_shape, bl_saw_shape, bl_square_shape, bl_triangle_shape, phase_step, lowpass_step, highpass_step, remap, pan_gain};
+use crate::parameter::{Parameter, ParameterID};
+use crate::context::EvaluationContext;
+
+use crate::lerp;
+
+use std::ops::Range;
+
+// Number of samples each node processes at a time in block mode.
+// Arenas are laid out with a stride of BLOCK_SIZE per node, even for partial blocks
+pub const BLOCK_SIZE: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvaluationMode {
+	// Every node is evaluated once per sample
+	PerSample,
+	// Nodes process BLOCK_SIZE samples at a time, falling back to per sample evaluation
+	// only for feedback loops and nodes that don't have a block kernel
+	Block,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Source {
+	Literal(f32),
+	// Slice of an earlier node in the block arena
+	Node(usize),
+	// A store that is never written, so is constant over the block
+	Store(usize),
+	Parameter(usize),
+}
+
+#[derive(Clone, Debug)]
+pub(crate) enum Step {
+	Kernel {
+		node: usize,
+		inputs: Vec<Source>,
+	},
+
+	// Nodes that must be evaluated a sample at a time
+	Span {
+		range: Range<usize>,
+		// Nodes before the span that it reads
+		mirror_nodes: Vec<usize>,
+		// Stores read by the span, and their last writer before the span
+		mirror_stores: Vec<(usize, usize)>,
+	},
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct BlockPlan {
+	pub(crate) steps: Vec<Step>,
+
+	// Every written store, and its last writer
+	pub(crate) store_writers: Vec<(usize, usize)>,
+
+	// Stand in parameters for spans, set to the value each parameter had at each sample
+	pub(crate) span_parameters: Vec<Parameter>,
+}
+
+fn has_kernel(node: &Node) -> bool {
+	!matches!(node, Node::Sampler{..} | Node::Sequencer{..} | Node::ParameterSampler(_)
+		| Node::EnvAR(_) | Node::EnvADSR(_) | Node::BusInput(_) | Node::Wavetable{..})
+}
+
+impl BlockPlan {
+	pub(crate) fn new(synth: &Synth) -> Self {
+		let instructions = &synth.instructions;
+		let num_nodes = instructions.len();
+
+		let mut writers = vec![Vec::new(); synth.value_store.len()];
+		for (idx, node) in instructions.iter().enumerate() {
+			if let Node::StoreWrite(StoreID(store), _) = node {
+				writers[*store as usize].push(idx);
+			}
+		}
+
+		let last_writer_before = |store: usize, idx: usize| {
+			writers[store].iter().rev().cloned().find(|&w| w < idx)
+		};
+
+		// Find ranges of nodes that read values from later in the graph,
+		// or from the previous sample, and so can't be evaluated a block at a time
+		let mut spans: Vec<Range<usize>> = Vec::new();
+
+		for (idx, node) in instructions.iter().enumerate() {
+			let mut end = idx;
+
+			if !has_kernel(node) {
+				end = idx + 1;
+			}
+
+			node.visit_inputs(|input| match input {
+				Input::Node(NodeID(dep)) if dep as usize >= idx => {
+					end = end.max((dep as usize).min(num_nodes - 1) + 1);
+				}
+
+				Input::Store(StoreID(store)) => {
+					let store = store as usize;
+					if last_writer_before(store, idx).is_none() {
+						if let Some(&last) = writers[store].last() {
+							end = end.max(last + 1);
+						}
+					}
+				}
+
+				_ => {}
+			});
+
+			if end > idx {
+				spans.push(idx..end);
+			}
+		}
+
+		// Spans are discovered in order of start, so overlapping spans are adjacent
+		let mut merged: Vec<Range<usize>> = Vec::new();
+		for span in spans {
+			match merged.last_mut() {
+				Some(last) if span.start < last.end => last.end = last.end.max(span.end),
+				_ => merged.push(span),
+			}
+		}
+
+		let mut steps = Vec::new();
+		let mut spans = merged.into_iter().peekable();
+		let mut idx = 0;
+
+		while idx < num_nodes {
+			if spans.peek().is_some_and(|s| s.start == idx) {
+				let range = spans.next().unwrap();
+
+				let mut mirror_nodes = Vec::new();
+				let mut mirror_stores = Vec::new();
+
+				for node in &instructions[range.clone()] {
+					node.visit_inputs(|input| match input {
+						Input::Node(NodeID(dep)) if (dep as usize) < range.start && !mirror_nodes.contains(&(dep as usize)) => {
+							mirror_nodes.push(dep as usize);
+						}
+
+						Input::Store(StoreID(store)) => {
+							let store = store as usize;
+							if let Some(writer) = last_writer_before(store, range.start) {
+								if !mirror_stores.contains(&(store, writer)) {
+									mirror_stores.push((store, writer));
+								}
+							}
+						}
+
+						_ => {}
+					});
+				}
+
+				idx = range.end;
+				steps.push(Step::Span { range, mirror_nodes, mirror_stores });
+				continue
+			}
+
+			let mut inputs = Vec::new();
+			instructions[idx].visit_inputs(|input| inputs.push(match input {
+				Input::Literal(v) => Source::Literal(v),
+				Input::Node(NodeID(dep)) => Source::Node(dep as usize),
+				Input::Parameter(ParameterID{id, ..}) => Source::Parameter(id as usize),
+				Input::Store(StoreID(store)) => match last_writer_before(store as usize, idx) {
+					Some(writer) => Source::Node(writer),
+					None => Source::Store(store as usize),
+				}
+			}));
+
+			steps.push(Step::Kernel { node: idx, inputs });
+			idx += 1;
+		}
+
+		let store_writers = writers.iter().enumerate()
+			.filter_map(|(store, w)| w.last().map(|&last| (store, last)))
+			.collect();
+
+		BlockPlan {
+			steps,
+			store_writers,
+			span_parameters: synth.parameters.clone(),
+		}
+	}
+}
+
+
+fn resolve<'a>(source: Source, prior: &'a [f32], params: &'a [f32], value_store: &[f32],
+	scratch: &'a mut [f32; BLOCK_SIZE], len: usize) -> &'a [f32]
+{
+	let fill = |scratch: &'a mut [f32; BLOCK_SIZE], value: f32| {
+		for s in scratch[..len].iter_mut() { *s = value; }
+		&scratch[..len]
+	};
+
+	match source {
+		Source::Literal(v) => fill(scratch, v),
+		Source::Store(store) => fill(scratch, value_store[store]),
+		Source::Node(idx) => &prior[idx*BLOCK_SIZE .. idx*BLOCK_SIZE + len],
+		Source::Parameter(idx) => &params[idx*BLOCK_SIZE .. idx*BLOCK_SIZE + len],
+	}
+}
+
+// Evaluates a node with a block kernel into out. prior contains the block arena up to the node
+pub(crate) fn evaluate_kernel(node: &mut Node, sources: &[Source], prior: &[f32], params: &[f32],
+	value_store: &[f32], out: &mut [f32], eval_ctx: &EvaluationContext)
+{
+	let len = out.len();
+
+	let mut scratch = [[0.0f32; BLOCK_SIZE]; 3];
+	let mut inputs: [&[f32]; 3] = [&[]; 3];
+
+	for ((input, &source), scratch) in inputs.iter_mut().zip(sources).zip(scratch.iter_mut()) {
+		*input = resolve(source, prior, params, value_store, scratch, len);
+	}
+
+	let [a, b, c] = inputs;
+	let sample_rate = eval_ctx.sample_rate;
+	let dt = eval_ctx.sample_dt;
+
+	match node {
+		Node::Sine(phase) => for (o, &f) in out.iter_mut().zip(a) { *o = phase.advance_with(f, sample_rate).sin() },
+		Node::Saw(phase) => for (o, &f) in out.iter_mut().zip(a) { *o = saw_shape(phase.advance_with(f, sample_rate)) },
+		Node::Square(phase) => for (o, &f) in out.iter_mut().zip(a) { *o = square_shape(phase.advance_with(f, sample_rate)) },
+		Node::Triangle(phase) => for (o, &f) in out.iter_mut().zip(a) { *o = triangle_shape(phase.advance_with(f, sample_rate)) },
+
+		Node::BandLimitedTriangle(phase) => for (o, &f) in out.iter_mut().zip(a) {
+			*o = bl_triangle_shape(phase.advance_with(f, sample_rate), phase_step(f, sample_rate));
+		}
+
+		Node::BandLimitedSquare(phase) => for (o, &f) in out.iter_mut().zip(a) {
+			*o = bl_square_shape(phase.advance_with(f, sample_rate), phase_step(f, sample_rate));
+		}
+
+		Node::BandLimitedSaw(phase) => for (o, &f) in out.iter_mut().zip(a) {
+			*o = bl_saw_shape(phase.advance_with(f, sample_rate), phase_step(f, sample_rate));
+		}
+
+		Node::LowPass{prev_result, ..} => for ((o, &s), &f) in out.iter_mut().zip(a).zip(b) {
+			*o = lowpass_step(prev_result, s, f, dt);
+		}
+
+		Node::HighPass{prev_sample_diff, ..} => for ((o, &s), &f) in out.iter_mut().zip(a).zip(b) {
+			*o = highpass_step(prev_sample_diff, s, f, dt);
+		}
+
+		Node::Clamp{..} => for (((o, &s), &lb), &ub) in out.iter_mut().zip(a).zip(b).zip(c) {
+			*o = s.max(lb).min(ub);
+		}
+
+		Node::Remap{in_lb, in_ub, out_lb, out_ub, ..} => for (o, &s) in out.iter_mut().zip(a) {
+			*o = remap(s, *in_lb, *in_ub, *out_lb, *out_ub);
+		}
+
+		Node::Pan{channel, ..} => for ((o, &s), &p) in out.iter_mut().zip(a).zip(b) {
+			*o = s * pan_gain(p, *channel);
+		}
+
+		Node::Mix{..} => for (((o, &x), &y), &m) in out.iter_mut().zip(a).zip(b).zip(c) { *o = lerp(x, y, m) },
+
+		Node::Add(..) => for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) { *o = x + y },
+		Node::Subtract(..) => for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) { *o = x - y },
+		Node::Multiply(..) => for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) { *o = x * y },
+		Node::Divide(..) => for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) { *o = x / y },
+		Node::Power(..) => for ((o, &x), &y) in out.iter_mut().zip(a).zip(b) { *o = x.powf(y) },
+
+		// The store itself is updated at the end of the block
+		Node::StoreWrite(..) => out.copy_from_slice(a),
+
+		_ => unreachable!("Node without a block kernel planned as a kernel"),
+	}
+}
@@ -1,19 +1,111 @@
 use crate::context::EvaluationContext;
+use crate::SynthResult;
 
-#[derive(Clone, Copy, Debug)]
+use crate::lerp;
+
+use failure::{bail, ensure};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub(crate) enum BufferUsageType {
 	Local, Shared,
 }
 
-#[derive(Clone, Copy, Debug)]
-pub struct BufferID(pub(crate) BufferUsageType, pub(crate) u16);
+// Usage, index and generation. The generation of a shared buffer changes whenever it is freed,
+// so ids that outlive their buffer are detected rather than reading whatever reuses the slot.
+// Generations never wrap, since slots are retired once they run out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BufferID(pub(crate) BufferUsageType, pub(crate) u16, pub(crate) u16);
+
+pub const MAX_BUFFERS: usize = u16::MAX as usize + 1;
+
+// Hands out shared buffer ids on the control side, reusing freed slots
+#[derive(Debug)]
+pub(crate) struct BufferAllocator {
+	// Current generation of each slot, and whether it holds a buffer
+	slots: Vec<(u16, bool)>,
+	free_slots: Vec<u16>,
+}
+
+impl BufferAllocator {
+	pub(crate) fn new() -> Self {
+		BufferAllocator {
+			slots: Vec::new(),
+			free_slots: Vec::new(),
+		}
+	}
+
+	pub(crate) fn allocate(&mut self) -> SynthResult<BufferID> {
+		if let Some(idx) = self.free_slots.pop() {
+			let slot = &mut self.slots[idx as usize];
+			slot.1 = true;
+			return Ok(BufferID(BufferUsageType::Shared, idx, slot.0))
+		}
+
+		ensure!(self.slots.len() < MAX_BUFFERS, "Too many shared buffers, the limit is {}", MAX_BUFFERS);
+
+		self.slots.push((0, true));
+		Ok(BufferID(BufferUsageType::Shared, (self.slots.len() - 1) as u16, 0))
+	}
+
+	pub(crate) fn validate(&self, BufferID(usage, idx, generation): BufferID) -> SynthResult<()> {
+		ensure!(usage == BufferUsageType::Shared, "Buffer {} is local to a synth, not shared", idx);
+
+		match self.slots.get(idx as usize) {
+			Some(&(slot_generation, true)) if slot_generation == generation => Ok(()),
+			Some(_) => bail!("Shared buffer {} has been freed", idx),
+			None => bail!("Shared buffer {} doesn't exist", idx),
+		}
+	}
+
+	pub(crate) fn free(&mut self, id: BufferID) -> SynthResult<()> {
+		self.validate(id)?;
+
+		let BufferID(_, idx, _) = id;
+		let slot = &mut self.slots[idx as usize];
+		slot.1 = false;
+
+		// Reusing the slot past the last generation would make ids from its first use valid again
+		if slot.0 < u16::MAX {
+			slot.0 += 1;
+			self.free_slots.push(idx);
+		}
+
+		Ok(())
+	}
+}
+
+// A shared buffer as seen by the evaluation thread. Empty once freed
+#[derive(Clone, Debug)]
+pub(crate) struct SharedBuffer {
+	pub(crate) generation: u16,
+	pub(crate) buffer: Option<Buffer>,
+}
 
+// Samples are interleaved when channels > 1.
+// sample_rate is the rate the buffer was recorded at, so samplers can play it back at the same pitch
+// whatever rate the context runs at. Buffers without one are read a frame per sample
 #[derive(Clone, Debug)]
-pub struct Buffer { pub data: Vec<f32> }
+pub struct Buffer { pub data: Vec<f32>, pub channels: usize, pub sample_rate: Option<f32> }
 
 impl Buffer {
 	pub fn new(buffer_size: usize) -> Buffer {
-		Buffer{ data: vec![0.0; buffer_size] }
+		Buffer{ data: vec![0.0; buffer_size], channels: 1, sample_rate: None }
+	}
+
+	pub fn with_channels(buffer_size: usize, channels: usize) -> Buffer {
+		assert!(channels > 0);
+		Buffer{ data: vec![0.0; buffer_size], channels, sample_rate: None }
+	}
+
+	pub fn from_interleaved(data: Vec<f32>, channels: usize) -> Buffer {
+		assert!(channels > 0);
+		Buffer{ data, channels, sample_rate: None }
+	}
+
+	pub fn with_sample_rate(mut self, sample_rate: f32) -> Buffer {
+		assert!(sample_rate > 0.0);
+		self.sample_rate = Some(sample_rate);
+		self
 	}
 
 	pub fn resize(&mut self, buffer_size: usize) {
@@ -24,33 +116,40 @@ impl Buffer {
 		for v in self.data.iter_mut() { *v = 0.0; }
 	}
 
-	pub fn len(&self) -> usize { self.data.len() } 
+	pub fn len(&self) -> usize { self.data.len() }
+	pub fn is_empty(&self) -> bool { self.data.is_empty() }
+	pub fn frames(&self) -> usize { self.data.len() / self.channels }
 
 	pub fn copy_to(&self, dst: &mut [f32]) {
 		dst.copy_from_slice(&self.data);
 	}
 
+	/// # Safety
+	/// dst must be valid for writes of length_bytes bytes, and aligned for f32
 	pub unsafe fn copy_to_raw(&self, dst: *mut u8, length_bytes: usize) {
-		use std::mem::transmute;
 		use std::ptr;
 
-		let dst = transmute(dst);
+		let dst = dst as *mut f32;
 		ptr::copy(self.data.as_ptr(), dst, self.data.len().min(length_bytes / 4));
 	}
 
+	// Mono buffers are duplicated to both channels, and buffers with more than
+	// two channels only have their first two copied
+	/// # Safety
+	/// dst must be valid for writes of length bytes, and aligned for f32
 	pub unsafe fn copy_to_stereo(&self, dst: *mut u8, length: usize) {
-		use std::mem::transmute;
 		use std::mem::size_of;
 
 		type SampleType = [f32; 2];
 
-		let stereo = self.data.iter()
-			.map(|f| [*f, *f])
+		let channels = self.channels;
+		let stereo = self.data.chunks(channels)
+			.map(|f| if channels == 1 { [f[0], f[0]] } else { [f[0], f[1]] })
 			.take(length / size_of::<SampleType>());
 
-		let dst: *mut SampleType = transmute(dst);
+		let dst = dst as *mut SampleType;
 		for (i, s) in stereo.enumerate() {
-			*dst.offset(i as isize) = s;
+			*dst.add(i) = s;
 		}
 	}
 }
@@ -64,67 +163,133 @@ pub struct SamplerContext<'eval_ctx, 'synth> {
 }
 
 impl<'e,'s> SamplerContext<'e,'s> {
-	fn get_buffer(&self, BufferID(usage, id): BufferID) -> &Buffer {
+	// None for freed buffers and stale ids, which read as silence
+	pub(crate) fn get_buffer(&self, BufferID(usage, id, generation): BufferID) -> Option<&Buffer> {
 		use self::BufferUsageType::*;
 
 		let idx = id as usize;
 
-		match usage {
-			Local => &self.local_buffers[idx],
-			Shared => &self.eval_ctx.shared_buffers[idx],
-		}
+		let buffer = match usage {
+			Local => self.local_buffers.get(idx),
+			Shared => self.eval_ctx.shared_buffers.get(idx)
+				.filter(|shared| shared.generation == generation)
+				.and_then(|shared| shared.buffer.as_ref()),
+		};
+
+		buffer.filter(|buffer| buffer.frames() > 0)
 	}
 }
 
 
 
+// Steps through the frames of a buffer, reading a single channel
 #[derive(Clone, Debug)]
-pub struct Sequencer { pub buffer_id: BufferID, pub position: usize }
+pub struct Sequencer { pub buffer_id: BufferID, pub position: usize, pub channel: usize }
 
 impl Sequencer {
 	pub fn new(buffer_id: BufferID) -> Self {
+		Sequencer::with_channel(buffer_id, 0)
+	}
+
+	pub fn with_channel(buffer_id: BufferID, channel: usize) -> Self {
 		Sequencer {
-			buffer_id, position: 0
+			buffer_id, position: 0, channel
 		}
-	} 
+	}
 
 	pub fn reset(&mut self) {
 		self.position = 0;
 	}
 
 	pub fn advance(&mut self, ctx: SamplerContext) {
-		let buffer = ctx.get_buffer(self.buffer_id);
-		let num_samples = buffer.len();
-		self.position = (self.position + 1) % num_samples;
+		if let Some(buffer) = ctx.get_buffer(self.buffer_id) {
+			self.position = (self.position + 1) % buffer.frames();
+		}
 	}
 
 	pub fn sample(&mut self, ctx: SamplerContext) -> f32 {
-		let buffer = ctx.get_buffer(self.buffer_id);
-		let num_samples = buffer.len();
+		let buffer = match ctx.get_buffer(self.buffer_id) {
+			Some(buffer) => buffer,
+			None => return 0.0,
+		};
 
-		assert!(self.position < num_samples);
+		let num_frames = buffer.frames();
 
-		buffer.data[self.position]
+		// Position can be left out of range when state is carried over from a different buffer
+		if self.position >= num_frames {
+			self.position = 0;
+		}
+
+		let channel = self.channel.min(buffer.channels - 1);
+		buffer.data[self.position * buffer.channels + channel]
 	}
 }
 
 
 
 
+// Plays a buffer back at its own sample rate, interpolating between frames when that
+// differs from the rate of the context
 #[derive(Clone, Debug)]
-pub struct BufferSampler(pub Sequencer);
+pub struct BufferSampler {
+	pub seq: Sequencer,
+	// Position between seq.position and the frame after it
+	pub(crate) fraction: f32,
+}
 
 impl BufferSampler {
 	pub fn new(buffer_id: BufferID) -> Self {
-		BufferSampler(Sequencer::new(buffer_id))
+		BufferSampler::with_channel(buffer_id, 0)
 	}
 
-	pub fn reset(&mut self) { self.0.reset(); }
+	pub fn with_channel(buffer_id: BufferID, channel: usize) -> Self {
+		BufferSampler {
+			seq: Sequencer::with_channel(buffer_id, channel),
+			fraction: 0.0,
+		}
+	}
+
+	pub fn reset(&mut self) {
+		self.seq.reset();
+		self.fraction = 0.0;
+	}
 
 	pub fn sample(&mut self, ctx: SamplerContext) -> f32 {
-		let seq = &mut self.0;
-		let sample = seq.sample(ctx);
-		seq.advance(ctx);
-		sample
+		let buffer = match ctx.get_buffer(self.seq.buffer_id) {
+			Some(buffer) => buffer,
+			None => return 0.0,
+		};
+
+		let rate = match buffer.sample_rate {
+			Some(sample_rate) => sample_rate / ctx.eval_ctx.sample_rate,
+			None => 1.0,
+		};
+
+		let sample = self.seq.sample(ctx);
+
+		// At matching rates fraction stays at zero, and samples are read exactly
+		if rate == 1.0 && self.fraction == 0.0 {
+			self.seq.advance(ctx);
+			return sample
+		}
+
+		self.seq.advance(ctx);
+		let next = self.seq.sample(ctx);
+		let value = lerp(sample, next, self.fraction);
+
+		self.fraction += rate - 1.0;
+
+		// Stepping back undoes the advance above when playing slower than the context
+		while self.fraction < 0.0 {
+			self.fraction += 1.0;
+			self.seq.position = self.seq.position.checked_sub(1).unwrap_or(buffer.frames() - 1);
+		}
+
+		while self.fraction >= 1.0 {
+			self.fraction -= 1.0;
+			self.seq.advance(ctx);
+		}
+
+		value
 	}
 }
\ No newline at end of file
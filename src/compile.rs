@@ -0,0 +1,172 @@
+use crate::synth::{Synth, StoreID};
+use crate::node::{Node, NodeID, Input};
+use crate::SynthResult;
+
+use failure::{bail, ensure};
+
+// Where each node of a synth ended up after compiling or optimising. None for nodes that were removed
+#[derive(Clone, Debug)]
+pub struct NodeMap(pub(crate) Vec<Option<NodeID>>);
+
+impl NodeMap {
+	pub fn get(&self, NodeID(node): NodeID) -> Option<NodeID> {
+		self.0.get(node as usize).and_then(|&n| n)
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Edge {
+	// Reads the output of a node
+	Node,
+	// Keeps a store read or write on the same side of another write as when the nodes were added
+	Store,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Mark {
+	Unvisited, Visiting, Done,
+}
+
+// Orders nodes so that every node is evaluated after the nodes it reads, keeping the order nodes
+// were added in wherever possible. Node inputs that close a cycle are turned into one sample delays,
+// by reading a store written just after the node they read
+pub(crate) fn compile(synth: &mut Synth) -> SynthResult<NodeMap> {
+	let num_nodes = synth.instructions.len();
+	let num_stores = synth.value_store.len();
+
+	// Nodes that have to be evaluated before each node
+	let mut preds: Vec<Vec<(usize, Edge)>> = vec![Vec::new(); num_nodes];
+	let mut writers = vec![Vec::new(); num_stores];
+	let mut readers = vec![Vec::new(); num_stores];
+
+	for (idx, node) in synth.instructions.iter().enumerate() {
+		let mut missing = None;
+
+		node.visit_inputs(|input| match input {
+			Input::Node(NodeID(dep)) if dep as usize >= num_nodes => missing = Some(format!("node {}", dep)),
+			Input::Node(NodeID(dep)) => preds[idx].push((dep as usize, Edge::Node)),
+			Input::Store(StoreID(store)) if store as usize >= num_stores => missing = Some(format!("store {}", store)),
+			Input::Store(StoreID(store)) => readers[store as usize].push(idx),
+			_ => {}
+		});
+
+		if let Node::StoreWrite(StoreID(store), _) = node {
+			ensure!((*store as usize) < num_stores, "Node {} ({}) writes store {}, which doesn't exist", idx, node.name(), store);
+			writers[*store as usize].push(idx);
+		}
+
+		if let Some(missing) = missing {
+			bail!("Node {} ({}) reads {}, which doesn't exist", idx, node.name(), missing);
+		}
+	}
+
+	for (writers, readers) in writers.iter().zip(readers.iter()) {
+		for pair in writers.windows(2) {
+			preds[pair[1]].push((pair[0], Edge::Store));
+		}
+
+		for &writer in writers {
+			for &reader in readers.iter().filter(|&&r| r != writer) {
+				if reader > writer {
+					preds[reader].push((writer, Edge::Store));
+				} else {
+					preds[writer].push((reader, Edge::Store));
+				}
+			}
+		}
+	}
+
+	// Depth first, visiting roots and inputs in the order they were added, so that a graph
+	// that is already in order is left as it is
+	let mut marks = vec![Mark::Unvisited; num_nodes];
+	let mut order = Vec::with_capacity(num_nodes);
+	let mut delayed: Vec<(usize, usize)> = Vec::new();
+	let mut stack: Vec<(usize, usize)> = Vec::new();
+
+	for root in 0..num_nodes {
+		if marks[root] != Mark::Unvisited { continue }
+
+		marks[root] = Mark::Visiting;
+		stack.push((root, 0));
+
+		while let Some(&(node, next)) = stack.last() {
+			match preds[node].get(next) {
+				Some(&(pred, kind)) => {
+					stack.last_mut().unwrap().1 += 1;
+
+					match marks[pred] {
+						Mark::Unvisited => {
+							marks[pred] = Mark::Visiting;
+							stack.push((pred, 0));
+						}
+
+						// pred is waiting on node, so this input closes a cycle.
+						// Store order is only kept where it doesn't conflict with node inputs
+						Mark::Visiting => if kind == Edge::Node && !delayed.contains(&(node, pred)) {
+							delayed.push((node, pred));
+						}
+
+						Mark::Done => {}
+					}
+				}
+
+				None => {
+					marks[node] = Mark::Done;
+					order.push(node);
+					stack.pop();
+				}
+			}
+		}
+	}
+
+	let mut instructions: Vec<Option<Node>> = std::mem::take(&mut synth.instructions)
+		.into_iter()
+		.map(Some)
+		.collect();
+
+	// One store per delayed node, shared by everything reading it from the previous sample
+	let mut delay_stores: Vec<Option<StoreID>> = vec![None; num_nodes];
+
+	for &(reader, source) in delayed.iter() {
+		let StoreID(store) = *delay_stores[source].get_or_insert_with(|| synth.new_value_store());
+
+		instructions[reader].as_mut().unwrap().visit_inputs_mut(|input| match input {
+			Input::Node(NodeID(dep)) if *dep as usize == source => *input = Input::Store(StoreID(store)),
+			_ => {}
+		});
+	}
+
+	let mut new_index = vec![0; num_nodes];
+	let mut compiled = Vec::with_capacity(num_nodes + delayed.len());
+
+	for &idx in order.iter() {
+		new_index[idx] = compiled.len();
+		compiled.push(instructions[idx].take().unwrap());
+
+		if let Some(store) = delay_stores[idx] {
+			compiled.push(Node::StoreWrite(store, Input::Node(NodeID(idx as u32))));
+		}
+	}
+
+	for node in compiled.iter_mut() {
+		node.visit_inputs_mut(|input| if let Input::Node(NodeID(dep)) = input {
+			*dep = new_index[*dep as usize] as u32;
+		});
+	}
+
+	// Without explicit outputs the last node is the output, which may have moved
+	if synth.output_nodes.is_empty() && num_nodes > 0 {
+		synth.output_nodes.push(num_nodes - 1);
+	}
+
+	for output in synth.output_nodes.iter_mut().chain(synth.labels.iter_mut().map(|(_, n)| n)) {
+		if *output < num_nodes {
+			*output = new_index[*output];
+		}
+	}
+
+	synth.instructions = compiled;
+	synth.invalidate_block_plan();
+
+	Ok(NodeMap(new_index.into_iter().map(|n| Some(NodeID(n as u32))).collect()))
+}
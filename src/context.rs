@@ -1,24 +1,62 @@
-use std::thread::spawn;
+use std::thread::{spawn, JoinHandle};
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::mpsc::{SyncSender, Sender, Receiver, sync_channel, channel};
-use std::sync::{Mutex, Arc};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::path::Path;
+use std::collections::HashMap;
 
 use crate::SynthResult;
-use crate::synth::{Synth, SynthID};
-use crate::buffer::{Buffer, BufferID, BufferUsageType};
+use crate::synth::{self, Synth, SynthID, Release};
+use crate::buffer::{Buffer, BufferID, BufferAllocator, SharedBuffer};
 use crate::parameter::ParameterID;
+use crate::node::NodeID;
+use crate::wav::{WavSink, SinkHandle};
+use crate::loader::{load_audio_file, ChannelMode};
+use crate::wavetable::{Wavetable, WavetableID};
+use crate::worker::{WorkerPool, Worker};
+use crate::master::{MasterBus, MasterConfig};
+use crate::stats::{Stats, StatsHistory};
+
+// In seconds
+pub const DEFAULT_CROSSFADE_TIME: f32 = 0.02;
+
+// Events the evaluation thread holds on to at once, waiting for their time to come
+const MAX_PENDING_EVENTS: usize = 1024;
+
+// The evaluation thread owns all synth state. Everything the control thread wants to change
+// is sent as an event, and anything the evaluation thread is done with is sent back
+// as garbage so that it is never deallocated on the evaluation thread
+pub struct Context {
+	event_tx: Sender<TimedEvent>,
+	garbage_rx: Receiver<Garbage>,
 
-use crate::lerp;
+	clock: Arc<AtomicU64>,
+	stats: Arc<Mutex<StatsHistory>>,
 
-use failure::err_msg;
+	// Only None while dropping
+	queued_buffer_tx: Option<SyncSender<Buffer>>,
+	ready_buffer_rx: Option<Receiver<Buffer>>,
 
-pub struct Context {
-	shared_context: Arc<Mutex<SharedContext>>,
+	thread: Option<JoinHandle<()>>,
+	failure: Arc<Mutex<Option<String>>>,
 
-	event_tx: Sender<SynthEvent>,
+	// Ready buffer partially consumed by render, and how many samples of it have been read
+	render_buffer: Option<Buffer>,
+	render_position: usize,
+	render_underruns: AtomicU64,
 
-	queued_buffer_tx: SyncSender<Buffer>,
-	ready_buffer_rx: Receiver<Buffer>,
+	sample_rate: f32,
 	buffer_size: usize,
+	channels: usize,
+
+	shared_buffers: BufferAllocator,
+	master_config: MasterConfig,
+	sink: Option<WavSink>,
+
+	// Envelopes of each synth sent to the evaluation thread, so releases can be checked here.
+	// Entries are forgotten once the synth comes back as garbage
+	envelopes: Mutex<HashMap<SynthID, Vec<NodeID>>>,
 }
 
 impl Context {
@@ -27,126 +65,470 @@ impl Context {
 		let (ready_buffer_tx, ready_buffer_rx) = sync_channel::<Buffer>(16);
 
 		let (event_tx, event_rx) = channel();
+		let (garbage_tx, garbage_rx) = channel();
 
-		let shared_context = Arc::new(Mutex::new(SharedContext::new(event_rx)));
+		let sample_rate = 22050.0;
 
-		{
-			let shared_context = shared_context.clone();
+		let mut shared_context = SharedContext::new(event_rx, garbage_tx, sample_rate);
+		shared_context.buffer_size = buffer_size;
+		shared_context.channels = 1;
 
-			spawn::<_, SynthResult<_>>(move || {
-				for mut buffer in queued_buffer_rx.iter() {
-					shared_context.lock().map_err(
-						|_| err_msg("Failed to lock shared context in evaluation thread"))?
-						.fill_buffer(&mut buffer);
+		let clock = shared_context.clock.clone();
+		let stats = shared_context.published_stats.clone();
 
-					ready_buffer_tx.send(buffer)?;
+		let failure = Arc::new(Mutex::new(None));
+		let thread_failure = failure.clone();
+
+		let thread = spawn(move || {
+			let mut failed = false;
+
+			for mut buffer in queued_buffer_rx.iter() {
+				if !failed {
+					let result = catch_unwind(AssertUnwindSafe(|| shared_context.fill_buffer(&mut buffer)));
+
+					if let Err(payload) = result {
+						*thread_failure.lock().unwrap_or_else(|e| e.into_inner()) = Some(panic_message("Evaluation thread", &*payload));
+						failed = true;
+					}
 				}
-	
-				Ok(())
-			});
-		}
+
+				// Synth state can't be trusted after a panic, so from then on buffers are only filled with
+				// silence. This keeps the audio device fed, and events still make it back to be dropped
+				if failed {
+					shared_context.discard_events();
+					buffer.clear();
+				}
+
+				// The control side has gone away
+				if ready_buffer_tx.send(buffer).is_err() {
+					break
+				}
+			}
+		});
 
 		for _ in 0..buffer_count {
 			queued_buffer_tx.send(Buffer::new(buffer_size))?;
 		}
 
 		Ok(Context {
-			shared_context,
-
 			event_tx,
+			garbage_rx,
+
+			clock,
+			stats,
 
-			queued_buffer_tx,
-			ready_buffer_rx,
+			queued_buffer_tx: Some(queued_buffer_tx),
+			ready_buffer_rx: Some(ready_buffer_rx),
 
+			thread: Some(thread),
+			failure,
+
+			render_buffer: None,
+			render_position: 0,
+			render_underruns: AtomicU64::new(0),
+
+			sample_rate,
 			buffer_size,
+			channels: 1,
+
+			shared_buffers: BufferAllocator::new(),
+			master_config: MasterConfig::default(),
+			sink: None,
+
+			envelopes: Mutex::new(HashMap::new()),
 		})
 	}
 
+	// Why the evaluation thread stopped evaluating synths, if it has, in which case the context
+	// only produces silence from then on. A panic on a worker thread is also reported here,
+	// but only loses the synths that worker was evaluating
+	pub fn evaluation_error(&self) -> Option<String> {
+		self.collect_garbage();
+
+		self.failure.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.clone()
+	}
+
+	// Published by the evaluation thread after each buffer it fills
+	pub fn stats(&self) -> Stats {
+		let mut stats = self.stats.lock()
+			.unwrap_or_else(|e| e.into_inner())
+			.summarize();
+
+		stats.render_underruns = self.render_underruns.load(Ordering::Relaxed);
+		stats
+	}
+
 	pub fn dump_stats(&self) {
-		let ctx = self.shared_context.lock().unwrap();
+		let stats = self.stats();
+
+		println!("fill time: {:5.0}μs / {} synths = {:3.2}μs/synth   (limit: {:5.0}μs, rem:{:5.0}μs, load: {:3.0}%)    dc: {:1.6}, env: {}",
+			stats.average_fill_time,
+			stats.synth_count,
+			stats.average_fill_time / stats.synth_count.max(1) as f32,
+			stats.buffer_deadline,
+			stats.buffer_deadline - stats.average_fill_time,
+			stats.cpu_load * 100.0,
+			stats.dc_offset, stats.limiter_envelope);
+	}
+
+	// Drops anything the evaluation thread has finished with
+	pub fn collect_garbage(&self) {
+		for garbage in self.garbage_rx.try_iter() {
+			match garbage {
+				Garbage::Synth(synth) => {
+					self.envelopes.lock().unwrap().remove(&synth.id);
+				}
 
-		let max_micros_per_buffer = 256.0 / ctx.evaluation_ctx.sample_rate * 1000000.0;
+				Garbage::WorkerFailure(message) => {
+					self.failure.lock()
+						.unwrap_or_else(|e| e.into_inner())
+						.get_or_insert(message);
+				}
+
+				garbage => drop(garbage),
+			}
+		}
+	}
+
+	fn send_event(&self, event: SynthEvent) {
+		self.send_event_at(event, 0);
+	}
 
-		println!("fill time: {:5.0}μs / {} synths = {:3.2}μs/synth   (limit: {:5.0}μs, rem:{:5.0}μs)    dc: {:1.6}, env: {}",
-			ctx.average_fill_time,
-			ctx.synths.len(),
-			ctx.average_fill_time / ctx.synths.len() as f32,
-			max_micros_per_buffer,
-			max_micros_per_buffer - ctx.average_fill_time,
-			ctx.signal_dc, ctx.envelope);
+	fn send_event_at(&self, event: SynthEvent, time: u64) {
+		self.collect_garbage();
+		// Only fails if the evaluation thread has exited, which is reported by evaluation_error
+		let _ = self.event_tx.send(TimedEvent { time, event });
 	}
 
-	pub fn push_synth(&self, mut synth: Synth) -> SynthResult<SynthID> {
-		let mut ctx = self.shared_context.lock().unwrap();
+	// Fails if the synth's graph is invalid, see Synth::validate
+	pub fn push_synth(&self, synth: Synth) -> SynthResult<SynthID> {
+		synth.validate(&self.shared_buffers)?;
 
 		let id = synth.id;
-		ctx.synths.push(synth);
+		self.envelopes.lock().unwrap().insert(id, synth.envelope_nodes());
+		self.send_event(SynthEvent::NewSynth(synth));
 		Ok(id)
 	}
 
 	pub fn remove_synth(&self, synth_id: SynthID) {
-		let mut ctx = self.shared_context.lock().unwrap();
-		ctx.synths.retain(move |s| s.id != synth_id);
+		self.envelopes.lock().unwrap().remove(&synth_id);
+		self.send_event(SynthEvent::RemoveSynth(synth_id));
 	}
 
-	pub fn set_sample_rate(&self, sample_rate: f32) {
-		let mut ctx = self.shared_context.lock().unwrap();
-		ctx.evaluation_ctx.sample_rate = sample_rate;
-		ctx.evaluation_ctx.sample_dt = 1.0 / sample_rate;
+	// Lets a synth finish before it's removed, rather than cutting it off.
+	// It can still be controlled until then. Synths that have already gone are ignored
+	pub fn release_synth(&self, synth_id: SynthID, release: Release) -> SynthResult<()> {
+		if let Some(envelopes) = self.envelopes.lock().unwrap().get(&synth_id) {
+			synth::check_release(release, envelopes)?;
+		}
+
+		self.send_event(SynthEvent::ReleaseSynth(synth_id, release));
+		Ok(())
+	}
+
+	// Swaps in a new graph for a running synth, keeping oscillator, filter and envelope state
+	// where nodes match. The new synth takes over synth_id, so existing ParameterIDs keep working.
+	// A release in progress and one-shot timing carry over. Synths that have already gone aren't brought back
+	pub fn replace_synth(&self, synth_id: SynthID, synth: Synth) -> SynthResult<SynthID> {
+		self.replace_synth_with_crossfade(synth_id, synth, DEFAULT_CROSSFADE_TIME)
+	}
+
+	pub fn replace_synth_with_crossfade(&self, synth_id: SynthID, mut synth: Synth, crossfade_time: f32) -> SynthResult<SynthID> {
+		synth.adopt_id(synth_id);
+		synth.validate(&self.shared_buffers)?;
+
+		self.envelopes.lock().unwrap().insert(synth_id, synth.envelope_nodes());
+		self.send_event(SynthEvent::ReplaceSynth(synth, crossfade_time));
+		Ok(synth_id)
+	}
+
+	// Takes effect from the next buffer. Samplers play buffers tagged with a sample rate
+	// at their original pitch, and fades in progress keep their length in seconds
+	pub fn set_sample_rate(&mut self, sample_rate: f32) {
+		self.sample_rate = sample_rate;
+		self.send_event(SynthEvent::SampleRateChange(sample_rate));
 	}
 
 	pub fn get_sample_rate(&self) -> f32 {
-		let ctx = self.shared_context.lock().unwrap();
-		ctx.evaluation_ctx.sample_rate
-		// TODO: find a way to remove this
+		self.sample_rate
 	}
 
+	// In samples across all channels. Buffers already queued are resized as they are filled
 	pub fn set_buffer_size(&mut self, buffer_size: usize) {
 		self.buffer_size = buffer_size;
+		self.send_event(SynthEvent::BufferSizeChange(buffer_size));
 	}
 
 	pub fn get_buffer_size(&self) -> usize {
 		self.buffer_size
 	}
 
+	// Number of interleaved output channels. Like buffer size, buffers already queued are reshaped as they are filled
+	pub fn set_channels(&mut self, channels: usize) {
+		assert!(channels > 0);
+		self.channels = channels;
+		self.send_event(SynthEvent::ChannelCountChange(channels));
+	}
+
+	pub fn get_channels(&self) -> usize {
+		self.channels
+	}
+
+	// Synths are split across this many worker threads, each with their own evaluation context.
+	// Zero evaluates everything on the evaluation thread
+	pub fn set_worker_count(&self, worker_count: usize) {
+		self.send_event(SynthEvent::WorkerPoolChange(WorkerPool::new(worker_count)));
+	}
+
+	// Number of frames the evaluation thread has rendered so far.
+	// Note that this runs ahead of what is audible by however many buffers are queued
+	pub fn current_time(&self) -> u64 {
+		self.clock.load(Ordering::Acquire)
+	}
+
+	// Applied at the start of the next buffer
 	pub fn set_parameter(&self, param_id: ParameterID, value: f32) {
-		self.event_tx.send(SynthEvent::SetParam(param_id, value)).unwrap();
+		self.set_parameter_at(param_id, value, 0);
+	}
+
+	// Applied on the exact frame given, counted from the start of rendering like current_time.
+	// Times that have already passed are applied at the start of the next buffer
+	pub fn set_parameter_at(&self, param_id: ParameterID, value: f32, time: u64) {
+		self.send_event_at(SynthEvent::SetParam(param_id, value), time);
 	}
 
-	pub fn create_shared_buffer(&self, data: Vec<f32>) -> SynthResult<BufferID> {
-		let mut ctx = self.shared_context.lock().unwrap();
-		ctx.evaluation_ctx.shared_buffers.push(Buffer{ data });
-		Ok(BufferID(BufferUsageType::Shared, (ctx.evaluation_ctx.shared_buffers.len() - 1) as u16))
+	// Restarts an envelope's attack on the given frame, even if its gate is held high throughout
+	pub fn retrigger_envelope_at(&self, synth_id: SynthID, envelope: NodeID, time: u64) {
+		self.send_event_at(SynthEvent::RetriggerEnvelope(synth_id, envelope), time);
+	}
+
+	pub fn create_shared_buffer(&mut self, data: Vec<f32>) -> SynthResult<BufferID> {
+		self.add_shared_buffer(Buffer::from_interleaved(data, 1))
+	}
+
+	// Ids are allocated on the control side, so they can be handed out before the evaluation thread sees them
+	pub fn add_shared_buffer(&mut self, buffer: Buffer) -> SynthResult<BufferID> {
+		let id = self.shared_buffers.allocate()?;
+		self.send_event(SynthEvent::SetSharedBuffer(id, buffer));
+		Ok(id)
+	}
+
+	// Swaps in new contents in one go, between buffers. Samplers keep their position, wrapped to the new length
+	pub fn replace_shared_buffer(&mut self, id: BufferID, buffer: Buffer) -> SynthResult<()> {
+		self.shared_buffers.validate(id)?;
+		self.send_event(SynthEvent::SetSharedBuffer(id, buffer));
+		Ok(())
+	}
+
+	// Samplers still using the id read silence, and the slot is reused with a new generation
+	pub fn free_shared_buffer(&mut self, id: BufferID) -> SynthResult<()> {
+		self.shared_buffers.free(id)?;
+		self.send_event(SynthEvent::FreeSharedBuffer(id));
+		Ok(())
+	}
+
+	// Stored in a shared buffer, which is freed through the id's buffer_id
+	pub fn add_wavetable(&mut self, table: Wavetable) -> SynthResult<WavetableID> {
+		let (frame_size, frames) = (table.frame_size(), table.frames());
+		let buffer = self.add_shared_buffer(table.into_buffer())?;
+		WavetableID::new(buffer, frame_size, frames)
+	}
+
+	// Decodes a wav or aiff file, resampled to the current sample rate
+	pub fn load_shared_buffer<P: AsRef<Path>>(&mut self, path: P, mode: ChannelMode) -> SynthResult<BufferID> {
+		let audio = load_audio_file(path)?;
+		let buffer = audio.to_buffer(mode, self.get_sample_rate())?;
+		self.add_shared_buffer(buffer)
+	}
+
+	pub fn set_master_config(&mut self, config: MasterConfig) {
+		self.master_config = config;
+		self.send_event(SynthEvent::MasterConfigChange(config));
+	}
+
+	pub fn get_master_config(&self) -> MasterConfig {
+		self.master_config
+	}
+
+	// The master effect reads the mix of all other synths through bus input nodes,
+	// and its output replaces the mix before the rest of master processing
+	pub fn set_master_effect(&self, effect: Option<Synth>) -> SynthResult<()> {
+		if let Some(effect) = &effect {
+			effect.validate(&self.shared_buffers)?;
+		}
+
+		self.send_event(SynthEvent::MasterEffectChange(effect));
+		Ok(())
+	}
+
+	// Current limiter envelope. Anything above 1.0 is being attenuated
+	pub fn get_master_envelope(&self) -> f32 {
+		self.stats().limiter_envelope
+	}
+
+	// Largest dc offset being removed from any channel
+	pub fn get_master_dc_offset(&self) -> f32 {
+		self.stats().dc_offset
+	}
+
+	// Everything the evaluation thread produces from now on is also pushed to sink.
+	// Fails if the sink's channel count or sample rate differ from the context's
+	pub fn attach_sink(&mut self, sink: WavSink) -> SynthResult<Option<WavSink>> {
+		sink.check_format(self.channels, self.sample_rate)?;
+
+		self.send_event(SynthEvent::SinkChange(Some(sink.handle(false))));
+		Ok(self.sink.replace(sink))
+	}
+
+	// The evaluation thread may still push to the sink until it processes the detach.
+	// Anything it pushes after WavSink::finish is called is dropped
+	pub fn detach_sink(&mut self) -> Option<WavSink> {
+		self.send_event(SynthEvent::SinkChange(None));
+		self.sink.take()
+	}
+
+	// Fills output with interleaved samples from buffers the evaluation thread has already rendered,
+	// for calling directly from an audio callback. output can be any length, and buffers are consumed
+	// across calls as needed. Never blocks - if no rendered audio is ready the rest of output is
+	// silenced and an underrun is counted. Returns the number of samples that were rendered audio.
+	// Shouldn't be mixed with get_ready_buffer and queue_empty_buffer
+	pub fn render(&mut self, output: &mut [f32]) -> usize {
+		let mut written = 0;
+
+		while written < output.len() {
+			let buffer = match self.render_buffer.take() {
+				Some(buffer) => buffer,
+				None => match self.ready_buffer_rx.as_ref().unwrap().try_recv() {
+					Ok(buffer) => {
+						self.render_position = 0;
+						buffer
+					}
+
+					Err(_) => {
+						for v in output[written..].iter_mut() { *v = 0.0; }
+						self.render_underruns.fetch_add(1, Ordering::Relaxed);
+						return written
+					}
+				}
+			};
+
+			let available = &buffer.data[self.render_position..];
+			let count = available.len().min(output.len() - written);
+
+			output[written..written + count].copy_from_slice(&available[..count]);
+			written += count;
+			self.render_position += count;
+
+			if self.render_position < buffer.data.len() {
+				self.render_buffer = Some(buffer);
+			} else {
+				// Can only fail if the evaluation thread has gone, in which case the buffer is dropped
+				let _ = self.queue_empty_buffer(buffer);
+			}
+		}
+
+		written
 	}
 
 	pub fn get_ready_buffer(&self) -> SynthResult<Buffer> {
-		Ok(self.ready_buffer_rx.recv()?)
+		Ok(self.ready_buffer_rx.as_ref().unwrap().recv()?)
 	}
 
 	pub fn queue_empty_buffer(&self, mut buffer: Buffer) -> SynthResult<()> {
-		buffer.resize(self.buffer_size);
-		Ok(self.queued_buffer_tx.send(buffer)?)
+		buffer.channels = self.channels;
+		buffer.resize(self.buffer_size / self.channels * self.channels);
+		Ok(self.queued_buffer_tx.as_ref().unwrap().send(buffer)?)
+	}
+}
+
+impl Drop for Context {
+	fn drop(&mut self) {
+		// Closing the queue ends the evaluation thread once it has filled the buffer it's on.
+		// Closing the ready channel too means it can't block sending back a buffer nobody will read
+		self.queued_buffer_tx.take();
+		self.ready_buffer_rx.take();
+
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+
+		self.collect_garbage();
 	}
 }
 
+pub(crate) fn panic_message(thread: &str, payload: &(dyn std::any::Any + Send)) -> String {
+	if let Some(message) = payload.downcast_ref::<&str>() {
+		format!("{} panicked: {}", thread, message)
+	} else if let Some(message) = payload.downcast_ref::<String>() {
+		format!("{} panicked: {}", thread, message)
+	} else {
+		format!("{} panicked", thread)
+	}
+}
 
-enum SynthEvent {
+
+pub(crate) struct TimedEvent {
+	pub(crate) time: u64,
+	pub(crate) event: SynthEvent,
+}
+
+pub(crate) enum SynthEvent {
 	SetParam(ParameterID, f32),
-	// NewSynth
-	// NewSharedBuffer
-	// SampleRateChange
-	// BufferSizeChange
+	NewSynth(Synth),
+	RemoveSynth(SynthID),
+	ReleaseSynth(SynthID, Release),
+	RetriggerEnvelope(SynthID, NodeID),
+	ReplaceSynth(Synth, f32),
+	SetSharedBuffer(BufferID, Buffer),
+	FreeSharedBuffer(BufferID),
+	SampleRateChange(f32),
+	BufferSizeChange(usize),
+	ChannelCountChange(usize),
+	SinkChange(Option<SinkHandle>),
+	WorkerPoolChange(WorkerPool),
+	MasterConfigChange(MasterConfig),
+	MasterEffectChange(Option<Synth>),
+}
+
+// Anything removed on the evaluation thread is sent back to be dropped elsewhere
+#[allow(dead_code)]
+pub(crate) enum Garbage {
+	Synth(Synth),
+	// Its id lives on in the synth that replaced it
+	ReplacedSynth(Synth),
+	Event(SynthEvent),
+	WorkerPool(WorkerPool),
+	// Workers whose thread has stopped, after a synth panicked on it
+	Worker(Worker),
+	WorkerFailure(String),
+	Buffer(Buffer),
 }
 
 
+// f32 stored as bits, for publishing from the evaluation thread without locking
+pub(crate) struct AtomicF32(AtomicU32);
+
+impl AtomicF32 {
+	pub(crate) fn new(v: f32) -> Self { AtomicF32(AtomicU32::new(v.to_bits())) }
+	pub(crate) fn load(&self) -> f32 { f32::from_bits(self.0.load(Ordering::Relaxed)) }
+	pub(crate) fn store(&self, v: f32) { self.0.store(v.to_bits(), Ordering::Relaxed) }
+}
+
 pub struct EvaluationContext {
 	pub sample_rate: f32,
 	pub sample_dt: f32,
 
 	pub sample_arena: Vec<f32>,
-	pub shared_buffers: Vec<Buffer>,
+	pub(crate) shared_buffers: Arc<Vec<SharedBuffer>>,
+
+	// Per node and per parameter slices for block evaluation
+	pub(crate) block_arena: Vec<f32>,
+	pub(crate) param_arena: Vec<f32>,
 
-	// Wavetables
+	// The mixed frame currently being processed by the master effect
+	pub(crate) bus_frame: Vec<f32>,
 }
 
 impl EvaluationContext {
@@ -156,52 +538,172 @@ impl EvaluationContext {
 			sample_dt: 1.0 / sample_rate, 
 
 			sample_arena: Vec::new(),
-			shared_buffers: Vec::new(),
+			shared_buffers: Arc::new(Vec::new()),
+
+			block_arena: Vec::new(),
+			param_arena: Vec::new(),
+
+			bus_frame: Vec::new(),
 		}
 	}
 }
 
 
 
-struct SharedContext {
+pub(crate) struct SharedContext {
 	synths: Vec<Synth>,
+	// Replaced synths, fading out before they are discarded
+	fading_synths: Vec<Synth>,
 	// interpolators: Vec<Interpolator>,
-	event_rx: Receiver<SynthEvent>,
-
-	envelope: f32,
-	signal_dc: f32,
-
-	evaluation_ctx: EvaluationContext,
-
-	average_fill_time: f32,
+	event_rx: Receiver<TimedEvent>,
+	garbage_tx: Sender<Garbage>,
+	// Sorted by time, stable with respect to arrival order
+	pending_events: Vec<TimedEvent>,
+	pub(crate) clock: Arc<AtomicU64>,
+	pub(crate) stats: StatsHistory,
+	// Copied from stats whenever the control side isn't reading it
+	pub(crate) published_stats: Arc<Mutex<StatsHistory>>,
+
+	pub(crate) master: MasterBus,
+
+	pub(crate) evaluation_ctx: EvaluationContext,
+	pub(crate) sink: Option<SinkHandle>,
+	// In samples. Buffers are resized to this as they are filled, unless it is zero
+	pub(crate) buffer_size: usize,
+	// Likewise, buffers are reshaped to this many channels unless it is zero
+	pub(crate) channels: usize,
+	pub(crate) workers: WorkerPool,
 }
 
 impl SharedContext {
-	fn new(event_rx: Receiver<SynthEvent>) -> Self {
-		// Init wavetables
-
+	pub(crate) fn new(event_rx: Receiver<TimedEvent>, garbage_tx: Sender<Garbage>, sample_rate: f32) -> Self {
 		SharedContext {
-			synths: Vec::new(),
+			synths: Vec::with_capacity(256),
+			fading_synths: Vec::with_capacity(16),
 			event_rx,
+			garbage_tx,
+			pending_events: Vec::with_capacity(MAX_PENDING_EVENTS),
+			clock: Arc::new(AtomicU64::new(0)),
+			stats: StatsHistory::new(),
+			published_stats: Arc::new(Mutex::new(StatsHistory::new())),
+
+			master: MasterBus::new(),
+
+			evaluation_ctx: EvaluationContext::new(sample_rate),
+			sink: None,
+			buffer_size: 0,
+			channels: 0,
+			workers: WorkerPool::new(0),
+		}
+	}
 
-			envelope: 1000.0,
-			signal_dc: 0.0,
+	pub(crate) fn push_synth(&mut self, synth: Synth) -> SynthResult<SynthID> {
+		let id = synth.id;
+		self.synths.push(synth);
+		Ok(id)
+	}
 
-			evaluation_ctx: EvaluationContext::new(22050.0),
+	pub(crate) fn find_synth(&self, synth_id: SynthID) -> Option<&Synth> {
+		self.synths.iter().find(|s| s.id == synth_id)
+	}
 
-			average_fill_time: 0.0,
+	// Order is preserved so that mixing order, and therefore output, stays deterministic
+	pub(crate) fn remove_synth(&mut self, synth_id: SynthID) {
+		while let Some(idx) = self.synths.iter().position(|s| s.id == synth_id) {
+			let synth = self.synths.remove(idx);
+			self.discard(Garbage::Synth(synth));
 		}
 	}
 
-	fn fill_buffer(&mut self, buffer: &mut Buffer) {
-		use std::time;
+	pub(crate) fn release_synth(&mut self, synth_id: SynthID, release: Release) {
+		let sample_rate = self.evaluation_ctx.sample_rate;
 
-		let begin = time::Instant::now();
+		for synth in self.synths.iter_mut().filter(|s| s.id == synth_id) {
+			synth.start_release(release, sample_rate);
+		}
+	}
 
-		buffer.clear();
+	// The replacement adopts state from the synth with the same id, and the two are crossfaded.
+	// A synth that is fading out on release is swapped straight away and keeps fading
+	pub(crate) fn replace_synth(&mut self, mut synth: Synth, crossfade_time: f32) {
+		let sample_rate = self.evaluation_ctx.sample_rate;
+		let frames = crossfade_time * sample_rate;
+
+		match self.synths.iter().position(|s| s.id == synth.id) {
+			Some(idx) => {
+				synth.transfer_state_from(&self.synths[idx]);
+				let fading_out = synth.transfer_release_from(&self.synths[idx], crossfade_time, sample_rate);
+
+				if !fading_out {
+					synth.set_fade_level(0.0);
+					synth.start_fade(1.0, frames);
+				}
+
+				let mut old_synth = std::mem::replace(&mut self.synths[idx], synth);
+
+				if fading_out {
+					old_synth.set_fade_level(0.0);
+				} else {
+					old_synth.start_fade(0.0, frames);
+				}
 
-		for ev in self.event_rx.try_recv() {
-			if let SynthEvent::SetParam(param_id, value) = ev {
+				if old_synth.is_faded_out() {
+					self.discard(Garbage::ReplacedSynth(old_synth));
+				} else {
+					self.fading_synths.push(old_synth);
+				}
+			}
+
+			// The synth has been removed or has finished, and isn't brought back
+			None => self.discard(Garbage::Synth(synth)),
+		}
+	}
+
+	// Used once evaluation has failed, so that events are still dropped on the control side
+	pub(crate) fn discard_events(&mut self) {
+		for TimedEvent{event, ..} in self.pending_events.drain(..) {
+			let _ = self.garbage_tx.send(Garbage::Event(event));
+		}
+
+		for TimedEvent{event, ..} in self.event_rx.try_iter() {
+			let _ = self.garbage_tx.send(Garbage::Event(event));
+		}
+	}
+
+	fn discard(&self, garbage: Garbage) {
+		// If the control side has gone away there's nowhere better to drop it
+		let _ = self.garbage_tx.send(garbage);
+	}
+
+	// Adds or replaces a shared buffer. Ids are assumed to have been validated by the control side
+	pub(crate) fn set_shared_buffer(&mut self, BufferID(_, idx, generation): BufferID, buffer: Buffer) {
+		// Workers only hold references to shared buffers while evaluating, so this never copies
+		let shared_buffers = Arc::make_mut(&mut self.evaluation_ctx.shared_buffers);
+		let idx = idx as usize;
+
+		if idx >= shared_buffers.len() {
+			shared_buffers.resize(idx + 1, SharedBuffer { generation: 0, buffer: None });
+		}
+
+		let shared = &mut shared_buffers[idx];
+		shared.generation = generation;
+
+		if let Some(old_buffer) = shared.buffer.replace(buffer) {
+			self.discard(Garbage::Buffer(old_buffer));
+		}
+	}
+
+	pub(crate) fn free_shared_buffer(&mut self, BufferID(_, idx, _): BufferID) {
+		let shared_buffers = Arc::make_mut(&mut self.evaluation_ctx.shared_buffers);
+
+		if let Some(old_buffer) = shared_buffers.get_mut(idx as usize).and_then(|s| s.buffer.take()) {
+			self.discard(Garbage::Buffer(old_buffer));
+		}
+	}
+
+	pub(crate) fn apply_event(&mut self, event: SynthEvent) {
+		match event {
+			SynthEvent::SetParam(param_id, value) => {
 				let param = self.synths.iter_mut()
 					.find(|s| s.id == param_id.owner)
 					.map(move |s| s.get_parameter(param_id));
@@ -210,42 +712,192 @@ impl SharedContext {
 					param.set_value(value);
 				}
 			}
+
+			SynthEvent::NewSynth(synth) => {
+				let _ = self.push_synth(synth);
+			}
+
+			SynthEvent::RemoveSynth(synth_id) => self.remove_synth(synth_id),
+
+			SynthEvent::ReleaseSynth(synth_id, release) => self.release_synth(synth_id, release),
+
+			SynthEvent::RetriggerEnvelope(synth_id, node) => {
+				if let Some(synth) = self.synths.iter_mut().find(|s| s.id == synth_id) {
+					synth.retrigger_envelope(node);
+				}
+			}
+
+			SynthEvent::ReplaceSynth(synth, crossfade_time) => self.replace_synth(synth, crossfade_time),
+
+			SynthEvent::SetSharedBuffer(id, buffer) => self.set_shared_buffer(id, buffer),
+			SynthEvent::FreeSharedBuffer(id) => self.free_shared_buffer(id),
+
+			// Nodes read the rate every sample, so only state measured in frames needs adapting.
+			// Buffers tagged with a sample rate are compensated for as they are sampled
+			SynthEvent::SampleRateChange(sample_rate) => {
+				let old_sample_rate = self.evaluation_ctx.sample_rate;
+
+				for synth in self.synths.iter_mut().chain(self.fading_synths.iter_mut()) {
+					synth.rescale_fade(old_sample_rate, sample_rate);
+				}
+
+				self.evaluation_ctx.sample_rate = sample_rate;
+				self.evaluation_ctx.sample_dt = 1.0 / sample_rate;
+			}
+
+			SynthEvent::BufferSizeChange(buffer_size) => {
+				self.buffer_size = buffer_size;
+			}
+
+			SynthEvent::ChannelCountChange(channels) => {
+				self.channels = channels;
+			}
+
+			SynthEvent::WorkerPoolChange(workers) => {
+				let old_workers = std::mem::replace(&mut self.workers, workers);
+				self.discard(Garbage::WorkerPool(old_workers));
+			}
+
+			SynthEvent::MasterConfigChange(config) => {
+				self.master.config = config;
+			}
+
+			SynthEvent::MasterEffectChange(effect) => {
+				if let Some(old_effect) = std::mem::replace(&mut self.master.effect, effect) {
+					self.discard(Garbage::Synth(old_effect));
+				}
+			}
+
+			SynthEvent::SinkChange(sink) => {
+				if let Some(old_sink) = std::mem::replace(&mut self.sink, sink) {
+					self.discard(Garbage::Event(SynthEvent::SinkChange(Some(old_sink))));
+				}
+			}
 		}
+	}
 
+	fn apply_due_events(&mut self, pending_events: &mut Vec<TimedEvent>, now: u64) {
+		let num_due = pending_events.iter()
+			.take_while(|e| e.time <= now)
+			.count();
 
-		// TODO: multiple evaluation contexts, push synth evals to diff threads
-		// recombine on completion
-		for synth in self.synths.iter_mut() {
-			synth.evaluate_into_buffer(buffer, &mut self.evaluation_ctx);
+		for TimedEvent{event, ..} in pending_events.drain(..num_due) {
+			self.apply_event(event);
 		}
+	}
 
-		const ATTACK_TIME: f32  = 5.0 / 1000.0;
-		const RELEASE_TIME: f32 = 200.0 / 1000.0;
+	pub(crate) fn fill_buffer(&mut self, buffer: &mut Buffer) {
+		use std::time;
 
-		let sample_rate = self.evaluation_ctx.sample_rate;
+		let begin = time::Instant::now();
+
+		// Taken so events can be applied while iterating
+		let mut pending_events = std::mem::take(&mut self.pending_events);
 
-		let attack  = 1.0 - (-1.0 / (ATTACK_TIME * sample_rate)).exp();
-		let release = 1.0 - (-1.0 / (RELEASE_TIME * sample_rate)).exp();
+		// Never grows past its capacity, so this doesn't allocate. Events that don't fit stay
+		// in the channel until a later buffer has room, and land late if their time has passed by then
+		while pending_events.len() < pending_events.capacity() {
+			let event = match self.event_rx.try_recv() {
+				Ok(event) => event,
+				Err(_) => break,
+			};
+
+			// After pending events due at the same time, so they apply in the order they were sent
+			let idx = pending_events.partition_point(|e| e.time <= event.time);
+			pending_events.insert(idx, event);
+		}
 
-		for v in buffer.data.iter_mut() {
-			let mut sample = *v;
-			self.signal_dc = lerp(self.signal_dc, sample, 0.5/sample_rate);
-			sample -= self.signal_dc;
+		let clock = self.clock.load(Ordering::Relaxed);
+
+		// Applied before the buffer is sized, so that a buffer size change applies to this buffer
+		self.apply_due_events(&mut pending_events, clock);
+
+		// Buffers queued before a buffer size or channel change still arrive in the old shape
+		if self.channels > 0 {
+			buffer.channels = self.channels;
+		}
 
-			let abs_signal = sample.abs();
-			if abs_signal > self.envelope {
-				self.envelope = lerp(self.envelope, abs_signal, attack);
+		if self.buffer_size > 0 {
+			let channels = buffer.channels;
+			buffer.resize(self.buffer_size / channels * channels);
+		}
+
+		buffer.clear();
+
+		let channels = buffer.channels;
+		let num_frames = buffer.frames();
+
+		// Evaluation is split at event boundaries so events land on the requested frame
+		let mut frame = 0;
+		while frame < num_frames {
+			self.apply_due_events(&mut pending_events, clock + frame as u64);
+
+			let segment_end = pending_events.first()
+				.map(|e| (e.time - clock).min(num_frames as u64) as usize)
+				.unwrap_or(num_frames);
+
+			let segment = &mut buffer.data[frame*channels .. segment_end*channels];
+
+			if self.workers.worker_count() > 0 {
+				self.workers.evaluate(&mut self.synths, segment, channels, &mut self.evaluation_ctx, &self.garbage_tx);
 			} else {
-				self.envelope = lerp(self.envelope, abs_signal, release);
+				for synth in self.synths.iter_mut() {
+					synth.evaluate_into_slice_timed(segment, channels, &mut self.evaluation_ctx);
+				}
+			}
+
+			for synth in self.fading_synths.iter_mut() {
+				synth.evaluate_into_slice_timed(segment, channels, &mut self.evaluation_ctx);
 			}
 
-			self.envelope = self.envelope.max(1.0);
-			*v = (sample*0.6/self.envelope).max(-1.0).min(1.0);
+			frame = segment_end;
+		}
+
+		while let Some(idx) = self.fading_synths.iter().position(Synth::is_faded_out) {
+			let synth = self.fading_synths.remove(idx);
+			self.discard(Garbage::ReplacedSynth(synth));
+		}
+
+		while let Some(idx) = self.synths.iter().position(Synth::is_finished) {
+			let synth = self.synths.remove(idx);
+			self.discard(Garbage::Synth(synth));
+		}
+
+		self.pending_events = pending_events;
+		self.clock.store(clock + num_frames as u64, Ordering::Release);
+
+		self.master.process(buffer, &mut self.evaluation_ctx);
+
+		if let Some(sink) = &self.sink {
+			sink.push(&buffer.data);
+		}
+
+		let fill_time = begin.elapsed().as_secs_f32() * 1000000.0;
+		self.record_stats(fill_time, num_frames);
+	}
+
+	fn record_stats(&mut self, fill_time: f32, num_frames: usize) {
+		let buffer_deadline = num_frames as f32 / self.evaluation_ctx.sample_rate * 1000000.0;
+		self.stats.record_fill(fill_time, buffer_deadline);
+
+		self.stats.synth_times.clear();
+		for synth in self.synths.iter_mut() {
+			self.stats.synth_times.push((synth.id, synth.take_eval_time()));
 		}
 
-		let end = time::Instant::now();
-		let diff = (end-begin).subsec_nanos() as f32 / 1000.0;
+		for synth in self.fading_synths.iter_mut().chain(self.master.effect.iter_mut()) {
+			synth.take_eval_time();
+		}
+
+		self.stats.gain_reduction = self.master.gain_reduction();
+		self.stats.limiter_envelope = self.master.envelope;
+		self.stats.dc_offset = self.master.max_dc();
+		self.stats.output_peak = self.master.output_peak;
+		self.stats.output_rms = self.master.output_rms;
 
-		self.average_fill_time = lerp(self.average_fill_time, diff, 0.1);
+		// Never blocks. If the control side is reading, this buffer's stats are skipped
+		if let Ok(mut published) = self.published_stats.try_lock() {
+			self.stats.copy_to(&mut published);
+		}
 	}
 }
@@ -1,17 +1,55 @@
 use crate::node::{Input, InputContext};
 use crate::gate::{Gate, GateState};
+use crate::context::AtomicF32;
 
-#[derive(Copy, Clone, Debug)]
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 enum State {
 	Silence, Attack, Decay, Sustain, Release,
 }
 
+// Published by an envelope every sample, so the control side can tell when a voice has finished
+pub(crate) struct EnvelopeMonitor {
+	level: AtomicF32,
+	active: AtomicBool,
+}
+
+impl EnvelopeMonitor {
+	pub(crate) fn new() -> Self {
+		EnvelopeMonitor {
+			level: AtomicF32::new(0.0),
+			active: AtomicBool::new(false),
+		}
+	}
+
+	pub(crate) fn level(&self) -> f32 { self.level.load() }
+	pub(crate) fn is_active(&self) -> bool { self.active.load(Ordering::Relaxed) }
+
+	fn publish(&self, level: f32, state: State) {
+		self.level.store(level);
+		self.active.store(state != State::Silence, Ordering::Relaxed);
+	}
+}
+
+impl std::fmt::Debug for EnvelopeMonitor {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "EnvelopeMonitor({}, {})", self.level(), self.is_active())
+	}
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, Debug)]
 pub struct ADSR {
 	state: State,
 	position: f32,
 
 	gate: Gate,
+	monitor: Option<Arc<EnvelopeMonitor>>,
+
+	// As passed to new, so the envelope can be saved
+	times: [f32; 4],
 
 	atk_inc: f32,
 	dec_inc: f32,
@@ -21,13 +59,16 @@ pub struct ADSR {
 
 impl ADSR {
 	pub fn new<G: Into<Input>>(atk: f32, dec: f32, sus: f32, rel: f32, gate: G) -> ADSR {
-		let sus_lvl = sus.max(0.0).min(1.0);
+		let sus_lvl = sus.clamp(0.0, 1.0);
 
 		ADSR {
 			state: State::Silence,
 			position: 0.0,
 
 			gate: Gate::new(gate.into()),
+			monitor: None,
+
+			times: [atk, dec, sus, rel],
 
 			// NOTE: this model allows doesn't allow decay to be cancelled on gate falling edge
 			// this may or may not be desirable but needs thought
@@ -38,6 +79,24 @@ impl ADSR {
 		}
 	}
 
+	// Attack, decay, sustain level and release
+	pub(crate) fn times(&self) -> [f32; 4] { self.times }
+
+	pub(crate) fn gate(&self) -> &Gate { &self.gate }
+	pub(crate) fn gate_mut(&mut self) -> &mut Gate { &mut self.gate }
+	pub(crate) fn retrigger(&mut self) { self.gate.retrigger() }
+
+	pub(crate) fn set_monitor(&mut self, monitor: Option<Arc<EnvelopeMonitor>>) { self.monitor = monitor }
+	pub(crate) fn is_monitored(&self) -> bool { self.monitor.is_some() }
+	pub(crate) fn is_silent(&self) -> bool { self.state == State::Silence }
+
+	// Timing is kept, so the envelope continues from the same point with the new rates
+	pub(crate) fn transfer_state(&mut self, old: &Self) {
+		self.state = old.state;
+		self.position = old.position;
+		self.gate.transfer_state(&old.gate);
+	}
+
 	fn update(&mut self, gate: GateState, dt: f32) {
 		use self::State::*;
 
@@ -100,6 +159,11 @@ impl ADSR {
 		let sample = self.position;
 		let gate = self.gate.update(input_ctx);
 		self.update(gate, input_ctx.eval_ctx.sample_dt);
+
+		if let Some(monitor) = &self.monitor {
+			monitor.publish(sample, self.state);
+		}
+
 		sample
 	}
 }
@@ -111,6 +175,10 @@ pub struct AR {
 	position: f32,
 
 	gate: Gate,
+	monitor: Option<Arc<EnvelopeMonitor>>,
+
+	// As passed to new, so the envelope can be saved
+	times: [f32; 2],
 
 	// in u/s
 	atk_inc: f32,
@@ -124,12 +192,33 @@ impl AR {
 			position: 0.0,
 
 			gate: Gate::new(gate.into()),
+			monitor: None,
+
+			times: [atk, rel],
 
 			atk_inc: 1.0 / atk.max(0.00001),
 			rel_inc: 1.0 / rel.max(0.00001),
 		}
 	}
 
+	// Attack and release
+	pub(crate) fn times(&self) -> [f32; 2] { self.times }
+
+	pub(crate) fn gate(&self) -> &Gate { &self.gate }
+	pub(crate) fn gate_mut(&mut self) -> &mut Gate { &mut self.gate }
+	pub(crate) fn retrigger(&mut self) { self.gate.retrigger() }
+
+	pub(crate) fn set_monitor(&mut self, monitor: Option<Arc<EnvelopeMonitor>>) { self.monitor = monitor }
+	pub(crate) fn is_monitored(&self) -> bool { self.monitor.is_some() }
+	pub(crate) fn is_silent(&self) -> bool { self.state == State::Silence }
+
+	// Timing is kept, so the envelope continues from the same point with the new rates
+	pub(crate) fn transfer_state(&mut self, old: &Self) {
+		self.state = old.state;
+		self.position = old.position;
+		self.gate.transfer_state(&old.gate);
+	}
+
 	fn update(&mut self, gate: GateState, inc: f32) {
 		use self::State::*;
 
@@ -173,6 +262,11 @@ impl AR {
 		let sample = self.position;
 		let gate = self.gate.update(input_ctx);
 		self.update(gate, input_ctx.eval_ctx.sample_dt);
+
+		if let Some(monitor) = &self.monitor {
+			monitor.publish(sample, self.state);
+		}
+
 		sample
 	}
 }
\ No newline at end of file
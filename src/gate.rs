@@ -12,11 +12,23 @@ const GATE_THRESHOLD: f32 = 0.05;
 impl Gate {
 	pub fn new(input: Input) -> Self { Gate (input, GateState::Low, 0.0) }
 
+	pub(crate) fn input(&self) -> Input { self.0 }
+	pub(crate) fn input_mut(&mut self) -> &mut Input { &mut self.0 }
+
+	// The next update sees a rising edge if the input is high, even if it already was
+	pub(crate) fn retrigger(&mut self) {
+		self.1 = GateState::Low;
+	}
+
+	pub(crate) fn transfer_state(&mut self, old: &Gate) {
+		self.1 = old.1;
+		self.2 = old.2;
+	}
+
 	pub fn update(&mut self, ctx: InputContext) -> GateState {
 		use self::GateState::*;
 
 		let input_sample = self.0.evaluate(ctx);
-		let diff = input_sample - self.2;
 		self.2 = input_sample;
 
 		self.1 = match self.1 {
@@ -48,29 +60,15 @@ impl Gate {
 
 impl GateState {
 	pub fn is_rising_edge(self) -> bool {
-		match self {
-			GateState::RisingEdge => true,
-			_ => false
-		}
+		matches!(self, GateState::RisingEdge)
 	}
 	pub fn is_falling_edge(self) -> bool {
-		match self {
-			GateState::FallingEdge => true,
-			_ => false
-		}
+		matches!(self, GateState::FallingEdge)
 	}
 	pub fn is_highish(self) -> bool {
-		match self {
-			GateState::RisingEdge => true,
-			GateState::High => true,
-			_ => false
-		}
+		matches!(self, GateState::RisingEdge | GateState::High)
 	}
 	pub fn is_lowish(self) -> bool {
-		match self {
-			GateState::FallingEdge => true,
-			GateState::Low => true,
-			_ => false
-		}
+		matches!(self, GateState::FallingEdge | GateState::Low)
 	}
 }
\ No newline at end of file
@@ -1,24 +1,48 @@
-#![feature(nll)]
-#![feature(integer_atomics)]
-
 // #[macro_use]
 pub extern crate failure;
 
 pub type SynthResult<T> = Result<T, failure::Error>;
 
 pub mod context;
+pub mod offline;
 pub mod synth;
 pub mod node;
 pub mod buffer;
+pub mod wav;
+pub mod loader;
+mod aiff;
+mod resample;
+mod worker;
+mod block;
+mod compile;
+mod optimise;
+mod patch;
+mod module;
+mod wavetable;
+mod master;
 mod parameter;
 mod envelope;
 mod gate;
+mod voice;
+mod stats;
 
 pub use context::Context;
-pub use synth::{Synth, SynthID};
+pub use offline::OfflineContext;
+pub use synth::{Synth, SynthID, Release};
+pub use block::{EvaluationMode, BLOCK_SIZE};
+pub use compile::NodeMap;
+pub use optimise::OptimiseReport;
+pub use patch::{PatchFormat, PATCH_VERSION};
+pub use module::{Module, ModuleInput, ModuleInstance};
+pub use wavetable::{Wavetable, WavetableID};
+pub use master::{MasterConfig, Dynamics};
 pub use node::{NodeID, NodeContainer};
-pub use parameter::ParameterID;
-pub use buffer::Buffer;
+pub use parameter::{ParameterID, SampleMode};
+pub use voice::{VoicePool, VoiceConfig, VoiceContext, StealPolicy};
+pub use stats::{Stats, FILL_TIME_HISTORY};
+pub use buffer::{Buffer, BufferID, MAX_BUFFERS};
+pub use wav::{WavWriter, WavSink, SampleFormat};
+pub use loader::{load_audio_file, AudioData, ChannelMode};
 
 fn lerp(from: f32, to: f32, amt: f32) -> f32 {
 	from + (to-from) * amt
@@ -30,7 +54,7 @@ TODO
 ====
 
 Allow creation of triggerable, and fillable audiobuffers
-	Load from file or prerender a synth to a buffer for later playback
+	Prerender a synth to a buffer for later playback
 
 Buffer based effects
 	Delay lines - basically looping audio buffers with some extra behaviour 
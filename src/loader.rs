@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use crate::SynthResult;
+use crate::resample::resample;
+use crate::buffer::Buffer;
+
+use failure::{bail, ensure};
+
+// Decoded audio file contents, samples interleaved
+#[derive(Clone, Debug)]
+pub struct AudioData {
+	pub sample_rate: f32,
+	pub channels: usize,
+	pub samples: Vec<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChannelMode {
+	// Average all channels into one
+	Downmix,
+	// Keep only the given channel
+	Select(usize),
+	// Keep all channels interleaved
+	Interleaved,
+}
+
+impl AudioData {
+	pub fn frames(&self) -> usize { self.samples.len() / self.channels }
+
+	// Applies channel mode and resamples to sample_rate
+	pub fn to_buffer(&self, mode: ChannelMode, sample_rate: f32) -> SynthResult<Buffer> {
+		let (samples, channels) = match mode {
+			ChannelMode::Downmix => {
+				let scale = 1.0 / self.channels as f32;
+				let mono = self.samples.chunks(self.channels)
+					.map(|frame| frame.iter().sum::<f32>() * scale)
+					.collect();
+
+				(mono, 1)
+			}
+
+			ChannelMode::Select(channel) => {
+				ensure!(channel < self.channels,
+					"Tried to select channel {} of audio with {} channels", channel, self.channels);
+
+				let mono = self.samples.chunks(self.channels)
+					.map(|frame| frame[channel])
+					.collect();
+
+				(mono, 1)
+			}
+
+			ChannelMode::Interleaved => (self.samples.clone(), self.channels),
+		};
+
+		let data = resample(&samples, channels, self.sample_rate, sample_rate)?;
+		Ok(Buffer::from_interleaved(data, channels).with_sample_rate(sample_rate))
+	}
+}
+
+// Decodes a wav or aiff file, detected by its header
+pub fn load_audio_file<P: AsRef<Path>>(path: P) -> SynthResult<AudioData> {
+	let path = path.as_ref();
+	let bytes = std::fs::read(path)?;
+
+	ensure!(bytes.len() >= 12, "'{}' is too short to be an audio file", path.display());
+
+	match (&bytes[0..4], &bytes[8..12]) {
+		(b"RIFF", b"WAVE") => crate::wav::decode_wav(&bytes),
+		(b"FORM", b"AIFF") | (b"FORM", b"AIFC") => crate::aiff::decode_aiff(&bytes),
+		_ => bail!("'{}' is not a wav or aiff file", path.display()),
+	}
+}
+
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Endianness { Little, Big }
+
+// Decodes integer pcm to f32 in [-1, 1). 8 bit data is unsigned, everything else is signed
+pub(crate) fn decode_pcm(data: &[u8], bits: u16, endianness: Endianness, unsigned_8bit: bool) -> SynthResult<Vec<f32>> {
+	let bytes_per_sample = match bits {
+		8 => 1, 16 => 2, 24 => 3, 32 => 4,
+		_ => bail!("Unsupported pcm bit depth: {}", bits),
+	};
+
+	let scale = 1.0 / (1u64 << (bits - 1)) as f64;
+
+	let samples = data.chunks_exact(bytes_per_sample)
+		.map(|bytes| {
+			let mut word = [0u8; 4];
+
+			// Place sample bytes in the most significant end, so the sign is preserved on shift
+			match endianness {
+				Endianness::Little => word[4-bytes_per_sample..].copy_from_slice(bytes),
+				Endianness::Big => {
+					for (dst, src) in word[4-bytes_per_sample..].iter_mut().zip(bytes.iter().rev()) {
+						*dst = *src;
+					}
+				}
+			}
+
+			if bits == 8 && unsigned_8bit {
+				word[3] ^= 0x80;
+			}
+
+			let value = i32::from_le_bytes(word) >> (32 - bits);
+			(value as f64 * scale) as f32
+		})
+		.collect();
+
+	Ok(samples)
+}
+
+pub(crate) fn decode_float(data: &[u8], bits: u16, endianness: Endianness) -> SynthResult<Vec<f32>> {
+	let samples = match (bits, endianness) {
+		(32, Endianness::Little) => data.chunks_exact(4)
+			.map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+			.collect(),
+
+		(32, Endianness::Big) => data.chunks_exact(4)
+			.map(|b| f32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+			.collect(),
+
+		(64, Endianness::Little) => data.chunks_exact(8)
+			.map(|b| f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32)
+			.collect(),
+
+		(64, Endianness::Big) => data.chunks_exact(8)
+			.map(|b| f64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]) as f32)
+			.collect(),
+
+		_ => bail!("Unsupported float bit depth: {}", bits),
+	};
+
+	Ok(samples)
+}
@@ -0,0 +1,175 @@
+use crate::synth::Synth;
+use crate::buffer::Buffer;
+use crate::context::EvaluationContext;
+
+use crate::lerp;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dynamics {
+	// Envelope follower linked across channels. Signal is only attenuated once the envelope goes above 1.0.
+	// Times are in seconds
+	Limiter { attack: f32, release: f32 },
+	// tanh saturation, drive scales the signal going into the curve
+	SoftClip { drive: f32 },
+	None,
+}
+
+// Processing applied to the mixed output of all synths, in the order
+// master effect, dc blocker, dynamics, gain, clip
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MasterConfig {
+	// Rate at which the dc estimate follows the signal, per second. None disables dc removal
+	pub dc_blocker: Option<f32>,
+	pub dynamics: Dynamics,
+	pub gain: f32,
+	// Hard clip to [-1, 1]
+	pub clip: bool,
+}
+
+impl Default for MasterConfig {
+	fn default() -> Self {
+		MasterConfig {
+			dc_blocker: Some(0.5),
+			dynamics: Dynamics::Limiter {
+				attack: 5.0 / 1000.0,
+				release: 200.0 / 1000.0,
+			},
+			gain: 0.6,
+			clip: true,
+		}
+	}
+}
+
+impl MasterConfig {
+	// Leaves the mix untouched, so the output is exactly what the synths produce
+	pub fn bypass() -> Self {
+		MasterConfig {
+			dc_blocker: None,
+			dynamics: Dynamics::None,
+			gain: 1.0,
+			clip: false,
+		}
+	}
+}
+
+
+pub(crate) struct MasterBus {
+	pub(crate) config: MasterConfig,
+
+	// Reads the mixed output through Node::BusInput and replaces it with its own output
+	pub(crate) effect: Option<Synth>,
+	effect_frame: Vec<f32>,
+
+	pub(crate) envelope: f32,
+	pub(crate) signal_dc: Vec<f32>,
+
+	// Measured over the last processed buffer
+	pub(crate) peak_envelope: f32,
+	pub(crate) output_peak: f32,
+	pub(crate) output_rms: f32,
+}
+
+impl MasterBus {
+	pub(crate) fn new() -> Self {
+		MasterBus {
+			config: MasterConfig::default(),
+
+			effect: None,
+			effect_frame: Vec::new(),
+
+			envelope: 1000.0,
+			signal_dc: Vec::new(),
+
+			peak_envelope: 1.0,
+			output_peak: 0.0,
+			output_rms: 0.0,
+		}
+	}
+
+	pub(crate) fn process(&mut self, buffer: &mut Buffer, eval_ctx: &mut EvaluationContext) {
+		let channels = buffer.channels;
+		let sample_rate = eval_ctx.sample_rate;
+
+		if let Some(effect) = &mut self.effect {
+			// Timed as a whole, since the effect is evaluated a frame at a time
+			let begin = std::time::Instant::now();
+			self.effect_frame.resize(channels, 0.0);
+
+			for frame in buffer.data.chunks_mut(channels) {
+				eval_ctx.bus_frame.clear();
+				eval_ctx.bus_frame.extend_from_slice(frame);
+
+				for v in self.effect_frame.iter_mut() { *v = 0.0; }
+				effect.evaluate_into_slice(&mut self.effect_frame, channels, eval_ctx);
+				frame.copy_from_slice(&self.effect_frame);
+			}
+
+			effect.add_eval_time(begin.elapsed());
+
+			// So that bus inputs in regular synths read silence
+			eval_ctx.bus_frame.clear();
+		}
+
+		self.signal_dc.resize(channels, 0.0);
+
+		let (attack, release) = match self.config.dynamics {
+			Dynamics::Limiter{attack, release} => (
+				1.0 - (-1.0 / (attack * sample_rate)).exp(),
+				1.0 - (-1.0 / (release * sample_rate)).exp(),
+			),
+
+			_ => (0.0, 0.0),
+		};
+
+		let MasterConfig{dc_blocker, dynamics, gain, clip} = self.config;
+
+		self.peak_envelope = 1.0;
+
+		for frame in buffer.data.chunks_mut(channels) {
+			if let Some(dc_rate) = dc_blocker {
+				for (v, dc) in frame.iter_mut().zip(self.signal_dc.iter_mut()) {
+					*dc = lerp(*dc, *v, dc_rate/sample_rate);
+					*v -= *dc;
+				}
+			}
+
+			match dynamics {
+				// The limiter envelope is linked across channels so the stereo image doesn't shift
+				Dynamics::Limiter{..} => {
+					let abs_signal = frame.iter().fold(0.0f32, |a, v| a.max(v.abs()));
+
+					if abs_signal > self.envelope {
+						self.envelope = lerp(self.envelope, abs_signal, attack);
+					} else {
+						self.envelope = lerp(self.envelope, abs_signal, release);
+					}
+
+					self.envelope = self.envelope.max(1.0);
+					self.peak_envelope = self.peak_envelope.max(self.envelope);
+
+					for v in frame.iter_mut() { *v = *v*gain/self.envelope; }
+				}
+
+				Dynamics::SoftClip{drive} => for v in frame.iter_mut() { *v = (*v*drive).tanh()*gain; }
+				Dynamics::None => for v in frame.iter_mut() { *v *= gain; }
+			}
+
+			if clip {
+				for v in frame.iter_mut() { *v = v.clamp(-1.0, 1.0); }
+			}
+		}
+
+		let sum_squares = buffer.data.iter().fold(0.0f32, |a, v| a + v*v);
+		self.output_peak = buffer.data.iter().fold(0.0f32, |a, v| a.max(v.abs()));
+		self.output_rms = (sum_squares / buffer.data.len().max(1) as f32).sqrt();
+	}
+
+	// In dB
+	pub(crate) fn gain_reduction(&self) -> f32 {
+		20.0 * self.peak_envelope.log10()
+	}
+
+	pub(crate) fn max_dc(&self) -> f32 {
+		self.signal_dc.iter().fold(0.0f32, |a, dc| a.max(dc.abs()))
+	}
+}
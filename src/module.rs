@@ -0,0 +1,239 @@
+use crate::synth::{Synth, SynthID, StoreID, next_synth_id};
+use crate::node::{Node, NodeID, NodeContainer, Input};
+use crate::buffer::{Buffer, BufferID, BufferUsageType, MAX_BUFFERS};
+use crate::parameter::{ParameterID, Parameter};
+use crate::SynthResult;
+
+use failure::{bail, ensure};
+
+// Stands in for whatever is connected to a module input when it is instantiated
+#[derive(Copy, Clone, Debug)]
+pub struct ModuleInput(ParameterID);
+
+impl From<ModuleInput> for Input {
+	fn from(input: ModuleInput) -> Input { Input::Parameter(input.0) }
+}
+
+#[derive(Clone, Debug)]
+enum Port {
+	// Read as a literal when left unconnected
+	Input{ name: String, default: f32 },
+	// Each instance gets its own copy in the synth
+	Parameter{ name: String, parameter: Parameter },
+}
+
+impl Port {
+	fn name(&self) -> &str {
+		match self {
+			Port::Input{name, ..} | Port::Parameter{name, ..} => name,
+		}
+	}
+}
+
+// A sub-graph that can be instantiated any number of times in a synth.
+// Inputs and parameters are handed out as ids owned by the module, and are replaced by
+// connections and synth parameters, along with node, store and buffer ids, when instantiated
+#[derive(Clone, Debug)]
+pub struct Module {
+	id: SynthID,
+
+	instructions: Vec<Node>,
+	num_stores: usize,
+	local_buffers: Vec<Buffer>,
+
+	ports: Vec<Port>,
+	outputs: Vec<(String, NodeID)>,
+}
+
+impl Module {
+	pub fn new() -> Self {
+		Module {
+			id: next_synth_id(),
+
+			instructions: Vec::new(),
+			num_stores: 0,
+			local_buffers: Vec::new(),
+
+			ports: Vec::new(),
+			outputs: Vec::new(),
+		}
+	}
+
+	// Inputs and parameters are connected and looked up by name, so names have to be unique
+	fn new_port(&mut self, port: Port) -> SynthResult<ParameterID> {
+		ensure!(self.ports.iter().all(|p| p.name() != port.name()), "Module already has an input or parameter named '{}'", port.name());
+
+		self.ports.push(port);
+		Ok(ParameterID { owner: self.id, id: self.ports.len() as u32 - 1 })
+	}
+
+	pub fn new_input(&mut self, name: &str, default: f32) -> SynthResult<ModuleInput> {
+		self.new_port(Port::Input{ name: name.to_owned(), default }).map(ModuleInput)
+	}
+
+	pub fn new_parameter(&mut self, name: &str) -> SynthResult<ParameterID> {
+		self.new_port(Port::Parameter{ name: name.to_owned(), parameter: Parameter::new() })
+	}
+
+	// Sets the initial value and sample mode of the parameter in each instance
+	pub fn get_parameter(&mut self, ParameterID{owner, id}: ParameterID) -> &mut Parameter {
+		assert!(owner == self.id);
+
+		match &mut self.ports[id as usize] {
+			Port::Parameter{parameter, ..} => parameter,
+			Port::Input{name, ..} => panic!("'{}' is a module input, not a parameter", name),
+		}
+	}
+
+	pub fn new_value_store(&mut self) -> StoreID {
+		self.num_stores += 1;
+		StoreID(self.num_stores as u32 - 1)
+	}
+
+	pub fn new_buffer(&mut self, data: Vec<f32>) -> SynthResult<BufferID> {
+		self.add_buffer(Buffer::from_interleaved(data, 1))
+	}
+
+	// Copied into the synth for every instance
+	pub fn add_buffer(&mut self, buffer: Buffer) -> SynthResult<BufferID> {
+		ensure!(self.local_buffers.len() < MAX_BUFFERS, "Too many buffers in module, the limit is {}", MAX_BUFFERS);
+
+		self.local_buffers.push(buffer);
+		Ok(BufferID(BufferUsageType::Local, (self.local_buffers.len() - 1) as u16, 0))
+	}
+
+	pub fn set_output(&mut self, name: &str, node: NodeID) {
+		self.outputs.retain(|(n, _)| n != name);
+		self.outputs.push((name.to_owned(), node));
+	}
+}
+
+impl Default for Module {
+	fn default() -> Self { Module::new() }
+}
+
+impl NodeContainer for Module {
+	fn add_node(&mut self, inst: Node) -> NodeID {
+		self.instructions.push(inst);
+		NodeID(self.instructions.len() as u32 - 1)
+	}
+}
+
+// Where the outputs and parameters of a module ended up in a synth
+#[derive(Clone, Debug)]
+pub struct ModuleInstance {
+	outputs: Vec<(String, NodeID)>,
+	parameters: Vec<(String, ParameterID)>,
+}
+
+impl ModuleInstance {
+	pub fn output(&self, name: &str) -> Option<NodeID> {
+		self.outputs.iter()
+			.find(|(n, _)| n == name)
+			.map(|&(_, node)| node)
+	}
+
+	// In the order they were set on the module
+	pub fn outputs(&self) -> Vec<NodeID> {
+		self.outputs.iter().map(|&(_, node)| node).collect()
+	}
+
+	pub fn parameter(&self, name: &str) -> Option<ParameterID> {
+		self.parameters.iter()
+			.find(|(n, _)| n == name)
+			.map(|&(_, param)| param)
+	}
+}
+
+// Nodes are appended to the synth in the order they were added to the module.
+// Nothing is added if any input is unknown or a reference can't be remapped
+pub(crate) fn instantiate(synth: &mut Synth, module: &Module, connections: &[(&str, Input)]) -> SynthResult<ModuleInstance> {
+	let mut ports: Vec<Option<Input>> = vec![None; module.ports.len()];
+
+	for &(name, input) in connections.iter() {
+		match module.ports.iter().position(|p| p.name() == name) {
+			Some(idx) if ports[idx].is_some() => bail!("Module input '{}' is connected more than once", name),
+			Some(idx) => match &module.ports[idx] {
+				Port::Input{..} => ports[idx] = Some(input),
+				Port::Parameter{..} => bail!("'{}' is a module parameter, and can't be connected", name),
+			}
+			None => bail!("Module has no input named '{}'", name),
+		}
+	}
+
+	ensure!(synth.local_buffers.len() + module.local_buffers.len() <= MAX_BUFFERS,
+		"Too many buffers in synth, the limit is {}", MAX_BUFFERS);
+
+	let node_offset = synth.instructions.len() as u32;
+	let store_offset = synth.value_store.len() as u32;
+	let buffer_offset = synth.local_buffers.len() as u16;
+
+	let mut parameters = Vec::new();
+	let mut new_parameters = Vec::new();
+
+	for (idx, port) in module.ports.iter().enumerate() {
+		match port {
+			Port::Input{default, ..} => if ports[idx].is_none() {
+				ports[idx] = Some(Input::Literal(*default));
+			}
+
+			Port::Parameter{name, parameter} => {
+				let id = ParameterID { owner: synth.id, id: (synth.parameters.len() + new_parameters.len()) as u32 };
+				ports[idx] = Some(Input::Parameter(id));
+				parameters.push((name.clone(), id));
+				new_parameters.push(parameter.clone());
+			}
+		}
+	}
+
+	let map_buffer = |id: &mut BufferID| if let BufferID(BufferUsageType::Local, idx, _) = id {
+		*idx += buffer_offset;
+	};
+
+	let mut nodes = Vec::with_capacity(module.instructions.len());
+
+	for (idx, node) in module.instructions.iter().enumerate() {
+		let mut node = node.clone();
+		let name = node.name();
+
+		node.visit_inputs_mut(|input| match input {
+			Input::Node(NodeID(dep)) => *dep += node_offset,
+			Input::Store(StoreID(store)) => *store += store_offset,
+			Input::Parameter(ParameterID{owner, id}) if *owner == module.id => *input = ports[*id as usize].unwrap(),
+			_ => {}
+		});
+
+		match &mut node {
+			Node::StoreWrite(StoreID(store), _) => *store += store_offset,
+			Node::Sampler{sampler, ..} => map_buffer(&mut sampler.seq.buffer_id),
+			Node::Sequencer{seq, ..} => map_buffer(&mut seq.buffer_id),
+			Node::Wavetable{table, ..} => map_buffer(&mut table.buffer),
+
+			Node::ParameterSampler(sampler) if sampler.parameter().owner == module.id => {
+				match ports[sampler.parameter().id as usize] {
+					Some(Input::Parameter(param)) => *sampler.parameter_mut() = param,
+					_ => bail!("Node {} ({}) samples module input '{}', but only parameters can be sampled",
+						idx, name, module.ports[sampler.parameter().id as usize].name()),
+				}
+			}
+
+			_ => {}
+		}
+
+		nodes.push(node);
+	}
+
+	synth.value_store.extend((0..module.num_stores).map(|_| 0.0));
+	synth.local_buffers.extend(module.local_buffers.iter().cloned());
+	synth.parameters.extend(new_parameters);
+
+	for node in nodes {
+		synth.add_node(node);
+	}
+
+	let outputs = module.outputs.iter()
+		.map(|(name, NodeID(node))| (name.clone(), NodeID(node + node_offset)))
+		.collect();
+
+	Ok(ModuleInstance { outputs, parameters })
+}
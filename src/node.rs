@@ -1,11 +1,14 @@
 use crate::synth::{Synth, StoreID};
 use crate::buffer::{BufferID, BufferSampler, Sequencer};
 use crate::context::EvaluationContext;
-use crate::parameter::{ParameterID, Parameter, ParameterSampler, SampleMode as ParamSampleMode};
+use crate::parameter::{ParameterID, Parameter, ParameterSampler, SampleMode};
 use crate::gate::Gate;
+use crate::wavetable::WavetableID;
 
 use crate::envelope as env;
 
+use crate::lerp;
+
 use std::f32::consts::PI;
 
 // NOTE: evaluation of Input::Node assumes that dependent nodes are evaluated before terminal nodes
@@ -37,20 +40,20 @@ impl Input {
 	}
 }
 
-impl Into<Input> for f32 {
-	fn into(self) -> Input { Input::Literal(self) }
+impl From<f32> for Input {
+	fn from(v: f32) -> Input { Input::Literal(v) }
 }
 
-impl Into<Input> for NodeID {
-	fn into(self) -> Input { Input::Node(self) }
+impl From<NodeID> for Input {
+	fn from(v: NodeID) -> Input { Input::Node(v) }
 }
 
-impl Into<Input> for StoreID {
-	fn into(self) -> Input { Input::Store(self) }
+impl From<StoreID> for Input {
+	fn from(v: StoreID) -> Input { Input::Store(v) }
 }
 
-impl Into<Input> for ParameterID {
-	fn into(self) -> Input { Input::Parameter(self) }
+impl From<ParameterID> for Input {
+	fn from(v: ParameterID) -> Input { Input::Parameter(v) }
 }
 
 
@@ -84,16 +87,129 @@ impl Phase {
 
 	pub fn advance(&mut self, ctx: InputContext) -> f32 {
 		let freq = self.freq.evaluate(ctx);
+		self.advance_with(freq, ctx.eval_ctx.sample_rate)
+	}
 
-		self.phase += self.period * freq as f32 / ctx.eval_ctx.sample_rate as f32;
+	pub(crate) fn advance_with(&mut self, freq: f32, sample_rate: f32) -> f32 {
+		self.phase += self.period * freq / sample_rate;
 		self.phase %= self.period;
-		self.phase as f32
+		self.phase
+	}
+
+	// Also returns the phase step, for band limited shapes
+	pub(crate) fn advance_stepped(&mut self, ctx: InputContext) -> (f32, f32) {
+		let freq = self.freq.evaluate(ctx);
+		let sample_rate = ctx.eval_ctx.sample_rate;
+		(self.advance_with(freq, sample_rate), phase_step(freq, sample_rate))
+	}
+}
+
+
+// Shared between per-sample and block evaluation, so that both produce identical results
+
+pub(crate) fn saw_shape(ph: f32) -> f32 { ph * 2.0 - 1.0 }
+pub(crate) fn square_shape(ph: f32) -> f32 { 1.0 - (ph + 0.5).floor() * 2.0 }
+
+pub(crate) fn triangle_shape(ph: f32) -> f32 {
+	if ph <= 0.5 {
+		(ph - 0.25)*4.0
+	} else {
+		(0.75 - ph)*4.0
 	}
 }
 
+pub(crate) fn lowpass_step(prev_result: &mut f32, sample: f32, cutoff: f32, dt: f32) -> f32 {
+	if cutoff > 0.0 {
+		let a = dt / (dt + 1.0 / (2.0 * PI * cutoff));
+		*prev_result = lerp(*prev_result, sample, a);
+	} else {
+		*prev_result = 0.0;
+	}
+
+	*prev_result
+}
 
+pub(crate) fn highpass_step(prev_sample_diff: &mut f32, sample: f32, cutoff: f32, dt: f32) -> f32 {
+	let rc = 1.0 / (2.0 * PI * cutoff);
+	let a = rc / (rc + dt);
 
-#[derive(Debug, Copy, Clone)]
+	let result = a * (*prev_sample_diff + sample);
+	*prev_sample_diff = result - sample;
+
+	result
+}
+
+pub(crate) fn remap(sample: f32, in_lb: f32, in_ub: f32, out_lb: f32, out_ub: f32) -> f32 {
+	let normalised = (sample - in_lb) / (in_ub - in_lb);
+	normalised * (out_ub - out_lb) + out_lb
+}
+
+// Band limited shapes take a phase in [0, 1) along with how far it moves per sample, and smooth
+// out discontinuities with polynomial approximations of a band limited step (PolyBLEP)
+// or of its integral, for discontinuities in slope (PolyBLAMP)
+
+pub(crate) fn phase_step(freq: f32, sample_rate: f32) -> f32 {
+	// Past half a period per sample the corrections would overlap
+	(freq / sample_rate).abs().min(0.5)
+}
+
+// For a step of height 2 at phase 0
+fn poly_blep(ph: f32, step: f32) -> f32 {
+	if ph < step {
+		let x = ph / step;
+		2.0*x - x*x - 1.0
+	} else if ph > 1.0 - step {
+		let x = (ph - 1.0) / step;
+		x*x + 2.0*x + 1.0
+	} else {
+		0.0
+	}
+}
+
+// For a change in slope of 1 per sample at phase 0
+fn poly_blamp(ph: f32, step: f32) -> f32 {
+	if ph < step {
+		let x = 1.0 - ph / step;
+		x*x*x / 6.0
+	} else if ph > 1.0 - step {
+		let x = (ph - 1.0) / step + 1.0;
+		x*x*x / 6.0
+	} else {
+		0.0
+	}
+}
+
+pub(crate) fn bl_saw_shape(ph: f32, step: f32) -> f32 {
+	if step <= 0.0 { return saw_shape(ph) }
+	saw_shape(ph) - poly_blep(ph, step)
+}
+
+pub(crate) fn bl_square_shape(ph: f32, step: f32) -> f32 {
+	if step <= 0.0 { return square_shape(ph) }
+	square_shape(ph) + poly_blep(ph, step) - poly_blep((ph + 0.5) % 1.0, step)
+}
+
+// Slope changes by 8 per period at each corner
+pub(crate) fn bl_triangle_shape(ph: f32, step: f32) -> f32 {
+	if step <= 0.0 { return triangle_shape(ph) }
+	let corner = 8.0 * step;
+	triangle_shape(ph) + corner * (poly_blamp(ph, step) - poly_blamp((ph + 0.5) % 1.0, step))
+}
+
+// Equal power
+pub(crate) fn pan_gain(pan: f32, channel: u8) -> f32 {
+	let pan = pan.clamp(-1.0, 1.0);
+	let angle = (pan + 1.0) * PI / 4.0;
+
+	match channel {
+		0 => angle.cos(),
+		_ => angle.sin(),
+	}
+}
+
+
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct NodeID (pub(crate) u32);
 
 #[derive(Debug, Clone)]
@@ -103,12 +219,23 @@ pub enum Node {
 	Square(Phase),
 	Saw(Phase),
 
+	// Alias far less than the naive shapes at high frequencies, at some extra cost
+	BandLimitedTriangle(Phase),
+	BandLimitedSquare(Phase),
+	BandLimitedSaw(Phase),
+
+	// Reads a band limited level of a wavetable, crossfading between the frames either side of position
+	Wavetable{ phase: Phase, position: Input, table: WavetableID },
+
 	LowPass{ input: Input, freq: Input, prev_result: f32 },
 	HighPass{ input: Input, freq: Input, prev_sample_diff: f32 },
 
 	Clamp{ input: Input, lb: Input, ub: Input },
 	Remap{ input: Input, in_lb: f32, in_ub: f32, out_lb: f32, out_ub: f32 },
 
+	// Equal power pan, pan in [-1, 1]. channel 0 is left, 1 is right
+	Pan{ input: Input, pan: Input, channel: u8 },
+
 	Mix{ a: Input, b: Input, mix: Input },
 	Add(Input, Input),
 	Subtract(Input, Input),
@@ -123,6 +250,163 @@ pub enum Node {
 
 	EnvAR(env::AR),
 	EnvADSR(env::ADSR),
+
+	// A channel of the mixed output, only non-zero in a master effect
+	BusInput(u8),
+}
+
+// Binary patches refer to node types by their position in this list, so new types only ever go on the end
+pub(crate) const NODE_NAMES: &[&str] = &[
+	"sine", "triangle", "square", "saw",
+	"lowpass", "highpass",
+	"clamp", "remap", "pan",
+	"mix", "add", "subtract", "multiply", "divide", "power",
+	"store_write", "sampler", "sequencer", "param_sampler",
+	"env_ar", "env_adsr",
+	"bus_input",
+	"bl_triangle", "bl_square", "bl_saw",
+	"wavetable",
+];
+
+impl Node {
+	pub(crate) fn name(&self) -> &'static str {
+		NODE_NAMES[self.type_index()]
+	}
+
+	// Position of this node's type in NODE_NAMES
+	pub(crate) fn type_index(&self) -> usize {
+		match self {
+			Node::Sine(_) => 0,
+			Node::Triangle(_) => 1,
+			Node::Square(_) => 2,
+			Node::Saw(_) => 3,
+
+			Node::LowPass{..} => 4,
+			Node::HighPass{..} => 5,
+
+			Node::Clamp{..} => 6,
+			Node::Remap{..} => 7,
+			Node::Pan{..} => 8,
+
+			Node::Mix{..} => 9,
+			Node::Add(..) => 10,
+			Node::Subtract(..) => 11,
+			Node::Multiply(..) => 12,
+			Node::Divide(..) => 13,
+			Node::Power(..) => 14,
+
+			Node::StoreWrite(..) => 15,
+			Node::Sampler{..} => 16,
+			Node::Sequencer{..} => 17,
+			Node::ParameterSampler(_) => 18,
+
+			Node::EnvAR(_) => 19,
+			Node::EnvADSR(_) => 20,
+
+			Node::BusInput(_) => 21,
+
+			Node::BandLimitedTriangle(_) => 22,
+			Node::BandLimitedSquare(_) => 23,
+			Node::BandLimitedSaw(_) => 24,
+
+			Node::Wavetable{..} => 25,
+		}
+	}
+
+	// Inputs are visited in declaration order
+	pub(crate) fn visit_inputs<F: FnMut(Input)>(&self, mut f: F) {
+		match self {
+			Node::Sine(phase) | Node::Triangle(phase) | Node::Square(phase) | Node::Saw(phase)
+				| Node::BandLimitedTriangle(phase) | Node::BandLimitedSquare(phase) | Node::BandLimitedSaw(phase) => f(phase.freq),
+
+			Node::Wavetable{phase, position, ..} => { f(phase.freq); f(*position); }
+
+			Node::LowPass{input, freq, ..} | Node::HighPass{input, freq, ..} => { f(*input); f(*freq); }
+			Node::Clamp{input, lb, ub} => { f(*input); f(*lb); f(*ub); }
+			Node::Remap{input, ..} => f(*input),
+			Node::Pan{input, pan, ..} => { f(*input); f(*pan); }
+
+			Node::Mix{a, b, mix} => { f(*a); f(*b); f(*mix); }
+			Node::Add(a, b) | Node::Subtract(a, b) | Node::Multiply(a, b)
+				| Node::Divide(a, b) | Node::Power(a, b) => { f(*a); f(*b); }
+
+			Node::StoreWrite(_, input) => f(*input),
+			Node::Sampler{reset, ..} => f(reset.input()),
+			Node::Sequencer{advance, reset, ..} => { f(advance.input()); f(reset.input()); }
+			Node::ParameterSampler(_) => {}
+
+			Node::EnvAR(env) => f(env.gate().input()),
+			Node::EnvADSR(env) => f(env.gate().input()),
+
+			Node::BusInput(_) => {}
+		}
+	}
+
+	pub(crate) fn visit_inputs_mut<F: FnMut(&mut Input)>(&mut self, mut f: F) {
+		match self {
+			Node::Sine(phase) | Node::Triangle(phase) | Node::Square(phase) | Node::Saw(phase)
+				| Node::BandLimitedTriangle(phase) | Node::BandLimitedSquare(phase) | Node::BandLimitedSaw(phase) => f(&mut phase.freq),
+
+			Node::Wavetable{phase, position, ..} => { f(&mut phase.freq); f(position); }
+
+			Node::LowPass{input, freq, ..} | Node::HighPass{input, freq, ..} => { f(input); f(freq); }
+			Node::Clamp{input, lb, ub} => { f(input); f(lb); f(ub); }
+			Node::Remap{input, ..} => f(input),
+			Node::Pan{input, pan, ..} => { f(input); f(pan); }
+
+			Node::Mix{a, b, mix} => { f(a); f(b); f(mix); }
+			Node::Add(a, b) | Node::Subtract(a, b) | Node::Multiply(a, b)
+				| Node::Divide(a, b) | Node::Power(a, b) => { f(a); f(b); }
+
+			Node::StoreWrite(_, input) => f(input),
+			Node::Sampler{reset, ..} => f(reset.input_mut()),
+			Node::Sequencer{advance, reset, ..} => { f(advance.input_mut()); f(reset.input_mut()); }
+			Node::ParameterSampler(_) => {}
+
+			Node::EnvAR(env) => f(env.gate_mut().input_mut()),
+			Node::EnvADSR(env) => f(env.gate_mut().input_mut()),
+
+			Node::BusInput(_) => {}
+		}
+	}
+
+	// Carries over running state from a node of the same type, so that replacing a synth
+	// doesn't reset phases, filter memory or envelope positions. Different types are left alone
+	pub(crate) fn transfer_state(&mut self, old: &Node) {
+		match (self, old) {
+			(Node::Sine(phase), Node::Sine(old)) | (Node::Triangle(phase), Node::Triangle(old))
+				| (Node::Square(phase), Node::Square(old)) | (Node::Saw(phase), Node::Saw(old))
+				| (Node::BandLimitedTriangle(phase), Node::BandLimitedTriangle(old))
+				| (Node::BandLimitedSquare(phase), Node::BandLimitedSquare(old))
+				| (Node::BandLimitedSaw(phase), Node::BandLimitedSaw(old))
+				| (Node::Wavetable{phase, ..}, Node::Wavetable{phase: old, ..}) =>
+			{
+				phase.phase = old.phase % phase.period;
+			}
+
+			(Node::LowPass{prev_result, ..}, Node::LowPass{prev_result: old, ..}) => *prev_result = *old,
+			(Node::HighPass{prev_sample_diff, ..}, Node::HighPass{prev_sample_diff: old, ..}) => *prev_sample_diff = *old,
+
+			(Node::Sampler{sampler, reset}, Node::Sampler{sampler: old_sampler, reset: old_reset}) => {
+				sampler.seq.position = old_sampler.seq.position;
+				sampler.fraction = old_sampler.fraction;
+				reset.transfer_state(old_reset);
+			}
+
+			(Node::Sequencer{seq, advance, reset}, Node::Sequencer{seq: old_seq, advance: old_advance, reset: old_reset}) => {
+				seq.position = old_seq.position;
+				advance.transfer_state(old_advance);
+				reset.transfer_state(old_reset);
+			}
+
+			(Node::ParameterSampler(sampler), Node::ParameterSampler(old)) => sampler.transfer_state(old),
+
+			(Node::EnvAR(env), Node::EnvAR(old)) => env.transfer_state(old),
+			(Node::EnvADSR(env), Node::EnvADSR(old)) => env.transfer_state(old),
+
+			_ => {}
+		}
+	}
 }
 
 pub trait NodeContainer {
@@ -145,6 +429,23 @@ pub trait NodeContainer {
 		self.add_node(Node::Square( Phase::new(freq.into()) ))
 	}
 
+	fn new_bl_triangle<I: Into<Input>>(&mut self, freq: I) -> NodeID {
+		self.add_node(Node::BandLimitedTriangle( Phase::new(freq.into()) ))
+	}
+
+	fn new_bl_saw<I: Into<Input>>(&mut self, freq: I) -> NodeID {
+		self.add_node(Node::BandLimitedSaw( Phase::new(freq.into()) ))
+	}
+
+	fn new_bl_square<I: Into<Input>>(&mut self, freq: I) -> NodeID {
+		self.add_node(Node::BandLimitedSquare( Phase::new(freq.into()) ))
+	}
+
+	// Position sweeps from the first frame of the table at 0 to the last at 1
+	fn new_wavetable<I: Into<Input>, P: Into<Input>>(&mut self, table: WavetableID, freq: I, position: P) -> NodeID {
+		self.add_node(Node::Wavetable{ phase: Phase::new(freq.into()), position: position.into(), table })
+	}
+
 
 	fn new_lowpass<I: Into<Input>, I2: Into<Input>>(&mut self, input: I, freq: I2) -> NodeID {
 		self.add_node(Node::LowPass{ input: input.into(), freq: freq.into(), prev_result: 0.0 })
@@ -175,6 +476,40 @@ pub trait NodeContainer {
 		self.add_node(Node::Mix{a: a.into(), b: b.into(), mix: m.into()})
 	}
 
+	fn new_pan<I: Into<Input>, P: Into<Input>>(&mut self, input: I, pan: P) -> [NodeID; 2] {
+		let input = input.into();
+		let pan = pan.into();
+
+		[
+			self.add_node(Node::Pan{ input, pan, channel: 0 }),
+			self.add_node(Node::Pan{ input, pan, channel: 1 }),
+		]
+	}
+
+	// Averages any number of channels down to one
+	fn new_downmix(&mut self, channels: &[NodeID]) -> NodeID {
+		assert!(!channels.is_empty());
+
+		let sum = channels[1..].iter()
+			.fold(channels[0], |acc, &ch| self.new_add(acc, ch));
+
+		self.new_multiply(sum, 1.0 / channels.len() as f32)
+	}
+
+	// Mid/side width control. 0 collapses to mono, 1 leaves the signal unchanged, > 1 widens
+	fn new_stereo_width<W: Into<Input>>(&mut self, [left, right]: [NodeID; 2], width: W) -> [NodeID; 2] {
+		let sum = self.new_add(left, right);
+		let mid = self.new_multiply(sum, 0.5);
+		let diff = self.new_sub(left, right);
+		let side = self.new_multiply(diff, 0.5);
+		let side = self.new_multiply(side, width);
+
+		[
+			self.new_add(mid, side),
+			self.new_sub(mid, side),
+		]
+	}
+
 	fn new_add<I: Into<Input>, I2: Into<Input>>(&mut self, a: I, b: I2) -> NodeID { self.add_node(Node::Add(a.into(), b.into())) }
 	fn new_sub<I: Into<Input>, I2: Into<Input>>(&mut self, a: I, b: I2) -> NodeID { self.add_node(Node::Subtract(a.into(), b.into())) }
 	fn new_multiply<I: Into<Input>, I2: Into<Input>>(&mut self, a: I, b: I2) -> NodeID { self.add_node(Node::Multiply(a.into(), b.into())) }
@@ -190,7 +525,19 @@ pub trait NodeContainer {
 			reset: Gate::new(reset.into())
 		})
 	}
-	fn new_param_sampler(&mut self, param_id: ParameterID, samp_mode: ParamSampleMode) -> NodeID {
+	fn new_sampler_channel<R: Into<Input>>(&mut self, buffer_id: BufferID, channel: usize, reset: R) -> NodeID {
+		self.add_node(Node::Sampler{
+			sampler: BufferSampler::with_channel(buffer_id, channel),
+			reset: Gate::new(reset.into())
+		})
+	}
+	fn new_multichannel_sampler<R: Into<Input>>(&mut self, buffer_id: BufferID, channels: usize, reset: R) -> Vec<NodeID> {
+		let reset = reset.into();
+		(0..channels)
+			.map(|channel| self.new_sampler_channel(buffer_id, channel, reset))
+			.collect()
+	}
+	fn new_param_sampler(&mut self, param_id: ParameterID, samp_mode: SampleMode) -> NodeID {
 		self.add_node(Node::ParameterSampler(ParameterSampler::new(param_id, samp_mode)))
 	}
 	fn new_sequencer<A: Into<Input>, R: Into<Input>>(&mut self, buffer_id: BufferID, advance: A, reset: R) -> NodeID {
@@ -207,10 +554,15 @@ pub trait NodeContainer {
 	fn new_env_adsr<G: Into<Input>> (&mut self, attack: f32, decay: f32, sustain: f32, release: f32, gate: G) -> NodeID {
 		self.add_node(Node::EnvADSR(env::ADSR::new(attack, decay, sustain, release, gate)))
 	}
+
+	fn new_bus_input(&mut self, channel: u8) -> NodeID {
+		self.add_node(Node::BusInput(channel))
+	}
 }
 
 impl NodeContainer for Synth {
 	fn add_node(&mut self, inst: Node) -> NodeID {
+		self.invalidate_block_plan();
 		self.instructions.push(inst);
 		NodeID(self.instructions.len() as u32 - 1)
 	}
@@ -0,0 +1,259 @@
+use std::sync::mpsc::{Sender, Receiver, channel};
+use std::sync::atomic::Ordering;
+use std::path::Path;
+
+use crate::SynthResult;
+use crate::context::{SharedContext, SynthEvent, TimedEvent, Garbage, DEFAULT_CROSSFADE_TIME};
+use crate::synth::{self, Synth, SynthID, Release};
+use crate::buffer::{Buffer, BufferID, BufferAllocator};
+use crate::parameter::ParameterID;
+use crate::node::NodeID;
+use crate::wav::WavSink;
+use crate::loader::{load_audio_file, ChannelMode};
+use crate::wavetable::{Wavetable, WavetableID};
+use crate::worker::WorkerPool;
+use crate::master::MasterConfig;
+use crate::stats::Stats;
+
+// Renders synchronously on the calling thread, with no audio device or evaluation thread.
+// Evaluation goes through the same SharedContext::fill_buffer as a realtime Context,
+// so output is identical across runs given the same synths, events and buffer size
+pub struct OfflineContext {
+	shared_context: SharedContext,
+
+	event_tx: Sender<TimedEvent>,
+	garbage_rx: Receiver<Garbage>,
+
+	buffer_size: usize,
+	channels: usize,
+
+	shared_buffers: BufferAllocator,
+
+	sink: Option<WavSink>,
+}
+
+impl OfflineContext {
+	pub fn new(sample_rate: f32, buffer_size: usize) -> Self {
+		let (event_tx, event_rx) = channel();
+		let (garbage_tx, garbage_rx) = channel();
+
+		// Left without a buffer size, so render_frames can fill partial buffers
+		let shared_context = SharedContext::new(event_rx, garbage_tx, sample_rate);
+
+		OfflineContext {
+			shared_context,
+
+			event_tx,
+			garbage_rx,
+
+			buffer_size: buffer_size.max(1),
+			channels: 1,
+
+			shared_buffers: BufferAllocator::new(),
+
+			sink: None,
+		}
+	}
+
+	pub fn push_synth(&mut self, synth: Synth) -> SynthResult<SynthID> {
+		synth.validate(&self.shared_buffers)?;
+		self.shared_context.push_synth(synth)
+	}
+
+	pub fn remove_synth(&mut self, synth_id: SynthID) {
+		self.shared_context.remove_synth(synth_id);
+		self.collect_garbage();
+	}
+
+	// Synths that have already gone are ignored
+	pub fn release_synth(&mut self, synth_id: SynthID, release: Release) -> SynthResult<()> {
+		if let Some(synth) = self.shared_context.find_synth(synth_id) {
+			synth::check_release(release, &synth.envelope_nodes())?;
+		}
+
+		self.shared_context.release_synth(synth_id, release);
+		Ok(())
+	}
+
+	pub fn replace_synth(&mut self, synth_id: SynthID, synth: Synth) -> SynthResult<SynthID> {
+		self.replace_synth_with_crossfade(synth_id, synth, DEFAULT_CROSSFADE_TIME)
+	}
+
+	// Replaced synths are dropped once their fade finishes, during a later render
+	pub fn replace_synth_with_crossfade(&mut self, synth_id: SynthID, mut synth: Synth, crossfade_time: f32) -> SynthResult<SynthID> {
+		synth.adopt_id(synth_id);
+		synth.validate(&self.shared_buffers)?;
+
+		self.shared_context.replace_synth(synth, crossfade_time);
+		self.collect_garbage();
+		Ok(synth_id)
+	}
+
+	fn collect_garbage(&self) {
+		for garbage in self.garbage_rx.try_iter() {
+			drop(garbage);
+		}
+	}
+
+	pub fn set_sample_rate(&mut self, sample_rate: f32) {
+		self.shared_context.apply_event(SynthEvent::SampleRateChange(sample_rate));
+	}
+
+	pub fn get_sample_rate(&self) -> f32 {
+		self.shared_context.evaluation_ctx.sample_rate
+	}
+
+	pub fn get_buffer_size(&self) -> usize {
+		self.buffer_size
+	}
+
+	pub fn set_channels(&mut self, channels: usize) {
+		assert!(channels > 0);
+		self.channels = channels;
+	}
+
+	pub fn get_channels(&self) -> usize {
+		self.channels
+	}
+
+	// Output is deterministic for any given worker count, but may differ slightly between counts
+	// since partial results are summed in a different order
+	pub fn set_worker_count(&mut self, worker_count: usize) {
+		self.shared_context.workers = WorkerPool::new(worker_count);
+	}
+
+	// Number of frames rendered so far
+	pub fn current_time(&self) -> u64 {
+		self.shared_context.clock.load(Ordering::Relaxed)
+	}
+
+	// Applied at the start of the next render
+	pub fn set_parameter(&self, param_id: ParameterID, value: f32) {
+		self.set_parameter_at(param_id, value, 0);
+	}
+
+	// Applied on the exact frame given, counted from the start of rendering like current_time
+	pub fn set_parameter_at(&self, param_id: ParameterID, value: f32, time: u64) {
+		let event = TimedEvent { time, event: SynthEvent::SetParam(param_id, value) };
+		self.event_tx.send(event).unwrap();
+	}
+
+	// Restarts an envelope's attack on the given frame, even if its gate is held high throughout
+	pub fn retrigger_envelope_at(&self, synth_id: SynthID, envelope: NodeID, time: u64) {
+		let event = TimedEvent { time, event: SynthEvent::RetriggerEnvelope(synth_id, envelope) };
+		self.event_tx.send(event).unwrap();
+	}
+
+	pub fn create_shared_buffer(&mut self, data: Vec<f32>) -> SynthResult<BufferID> {
+		self.add_shared_buffer(Buffer::from_interleaved(data, 1))
+	}
+
+	pub fn add_shared_buffer(&mut self, buffer: Buffer) -> SynthResult<BufferID> {
+		let id = self.shared_buffers.allocate()?;
+		self.shared_context.set_shared_buffer(id, buffer);
+		Ok(id)
+	}
+
+	pub fn replace_shared_buffer(&mut self, id: BufferID, buffer: Buffer) -> SynthResult<()> {
+		self.shared_buffers.validate(id)?;
+		self.shared_context.set_shared_buffer(id, buffer);
+		self.collect_garbage();
+		Ok(())
+	}
+
+	pub fn free_shared_buffer(&mut self, id: BufferID) -> SynthResult<()> {
+		self.shared_buffers.free(id)?;
+		self.shared_context.free_shared_buffer(id);
+		self.collect_garbage();
+		Ok(())
+	}
+
+	pub fn add_wavetable(&mut self, table: Wavetable) -> SynthResult<WavetableID> {
+		let (frame_size, frames) = (table.frame_size(), table.frames());
+		let buffer = self.add_shared_buffer(table.into_buffer())?;
+		WavetableID::new(buffer, frame_size, frames)
+	}
+
+	pub fn load_shared_buffer<P: AsRef<Path>>(&mut self, path: P, mode: ChannelMode) -> SynthResult<BufferID> {
+		let audio = load_audio_file(path)?;
+		let buffer = audio.to_buffer(mode, self.get_sample_rate())?;
+		self.add_shared_buffer(buffer)
+	}
+
+	// Fill times are wall clock, so unlike the output they vary between runs
+	pub fn stats(&self) -> Stats {
+		self.shared_context.stats.summarize()
+	}
+
+	pub fn set_master_config(&mut self, config: MasterConfig) {
+		self.shared_context.master.config = config;
+	}
+
+	pub fn get_master_config(&self) -> MasterConfig {
+		self.shared_context.master.config
+	}
+
+	pub fn set_master_effect(&mut self, effect: Option<Synth>) -> SynthResult<()> {
+		if let Some(effect) = &effect {
+			effect.validate(&self.shared_buffers)?;
+		}
+
+		self.shared_context.master.effect = effect;
+		Ok(())
+	}
+
+	pub fn get_master_envelope(&self) -> f32 {
+		self.shared_context.master.envelope
+	}
+
+	pub fn get_master_dc_offset(&self) -> f32 {
+		self.shared_context.master.max_dc()
+	}
+
+	pub fn attach_sink(&mut self, sink: WavSink) -> SynthResult<Option<WavSink>> {
+		sink.check_format(self.channels, self.get_sample_rate())?;
+
+		self.shared_context.sink = Some(sink.handle(true));
+		Ok(self.sink.replace(sink))
+	}
+
+	pub fn detach_sink(&mut self) -> Option<WavSink> {
+		self.shared_context.sink = None;
+		self.sink.take()
+	}
+
+	// Fills one buffer exactly as the evaluation thread of a Context would
+	pub fn render_buffer(&mut self, buffer: &mut Buffer) {
+		self.shared_context.fill_buffer(buffer);
+		self.collect_garbage();
+	}
+
+	// Renders num_frames in buffer_size chunks, so that events and the master limiter
+	// behave the same as they would in realtime
+	pub fn render_frames(&mut self, num_frames: usize) -> Buffer {
+		let channels = self.channels;
+		let frames_per_chunk = (self.buffer_size / channels).max(1);
+
+		let mut output = Buffer::with_channels(0, channels);
+		output.data.reserve(num_frames * channels);
+
+		let mut chunk = Buffer::with_channels(frames_per_chunk * channels, channels);
+
+		while output.frames() < num_frames {
+			let remaining = num_frames - output.frames();
+			chunk.resize(remaining.min(frames_per_chunk) * channels);
+
+			self.shared_context.fill_buffer(&mut chunk);
+			output.data.extend_from_slice(&chunk.data);
+		}
+
+		self.collect_garbage();
+
+		output
+	}
+
+	pub fn render_seconds(&mut self, seconds: f32) -> Buffer {
+		let num_frames = (seconds.max(0.0) * self.get_sample_rate()).round() as usize;
+		self.render_frames(num_frames)
+	}
+}
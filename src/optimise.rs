@@ -0,0 +1,216 @@
+use crate::synth::Synth;
+use crate::node::{Node, NodeID, Input, remap, pan_gain};
+use crate::compile::NodeMap;
+use crate::SynthResult;
+
+use crate::lerp;
+
+use failure::bail;
+
+// What an optimisation pass did to a synth
+#[derive(Clone, Debug)]
+pub struct OptimiseReport {
+	pub nodes_before: usize,
+	pub nodes_after: usize,
+
+	// Nodes that always produce the same value, or pass one of their inputs through unchanged
+	pub folded: usize,
+	// Remaps of remaps that were merged into one
+	pub remaps_collapsed: usize,
+
+	// Node ids from before optimising can be mapped to the optimised synth with this.
+	// Removed nodes map to None
+	pub node_map: NodeMap,
+}
+
+impl OptimiseReport {
+	pub fn eliminated(&self) -> usize {
+		self.nodes_before - self.nodes_after
+	}
+}
+
+// Folds constant expressions and identities like multiplying by one into the nodes that use them,
+// merges chains of remaps, then removes nodes that no longer contribute to an output or store write.
+// Output is unchanged, apart from rounding in merged remaps and the sign of zero when adding zero.
+// Labelled nodes and envelopes watched by a VoicePool are always kept
+pub(crate) fn optimise(synth: &mut Synth) -> SynthResult<OptimiseReport> {
+	let num_nodes = synth.instructions.len();
+
+	for (idx, node) in synth.instructions.iter().enumerate() {
+		let mut forward = None;
+
+		node.visit_inputs(|input| match input {
+			Input::Node(NodeID(dep)) if dep as usize >= idx => forward = Some(dep),
+			_ => {}
+		});
+
+		if let Some(dep) = forward {
+			bail!("Node {} ({}) reads node {}, which is evaluated after it. The synth has to be compiled before it is optimised", idx, node.name(), dep);
+		}
+	}
+
+	if let Some(output) = synth.output_nodes.iter().find(|&&o| o >= num_nodes) {
+		bail!("Output node {} doesn't exist", output);
+	}
+
+	if let Some((label, node)) = synth.labels.iter().find(|&(_, n)| *n >= num_nodes) {
+		bail!("Label '{}' refers to node {}, which doesn't exist", label, node);
+	}
+
+	// Without explicit outputs the last node is the output, which may be removed or moved
+	if synth.output_nodes.is_empty() && num_nodes > 0 {
+		synth.output_nodes.push(num_nodes - 1);
+	}
+
+	// What reads of each node can be replaced with
+	let mut replacements: Vec<Option<Input>> = vec![None; num_nodes];
+	let mut folded = 0;
+	let mut remaps_collapsed = 0;
+
+	for idx in 0..num_nodes {
+		let (earlier, rest) = synth.instructions.split_at_mut(idx);
+		let node = &mut rest[0];
+
+		node.visit_inputs_mut(|input| if let Input::Node(NodeID(dep)) = *input {
+			if let Some(replacement) = replacements[dep as usize] {
+				*input = replacement;
+			}
+		});
+
+		if collapse_remap(node, earlier) {
+			remaps_collapsed += 1;
+		}
+
+		if let Some(replacement) = simplify(node) {
+			replacements[idx] = Some(replacement);
+			folded += 1;
+		}
+	}
+
+	// Outputs have to be nodes, so folded outputs are kept rather than replaced by a constant
+	for output in synth.output_nodes.iter_mut() {
+		if let Some(Input::Node(NodeID(node))) = replacements[*output] {
+			*output = node as usize;
+		}
+	}
+
+	let mut live = vec![false; num_nodes];
+
+	for &output in synth.output_nodes.iter() {
+		live[output] = true;
+	}
+
+	for &(_, node) in synth.labels.iter() {
+		live[node] = true;
+	}
+
+	for (idx, node) in synth.instructions.iter().enumerate() {
+		live[idx] |= match node {
+			Node::StoreWrite(..) => true,
+			Node::EnvAR(env) => env.is_monitored(),
+			Node::EnvADSR(env) => env.is_monitored(),
+			_ => false,
+		};
+	}
+
+	// Nodes only read earlier nodes, so one pass from the end finds everything that's used
+	for idx in (0..num_nodes).rev() {
+		if !live[idx] { continue }
+
+		synth.instructions[idx].visit_inputs(|input| if let Input::Node(NodeID(dep)) = input {
+			live[dep as usize] = true;
+		});
+	}
+
+	let mut new_index = vec![None; num_nodes];
+	let instructions = std::mem::take(&mut synth.instructions);
+
+	for (idx, node) in instructions.into_iter().enumerate() {
+		if live[idx] {
+			new_index[idx] = Some(synth.instructions.len());
+			synth.instructions.push(node);
+		}
+	}
+
+	for node in synth.instructions.iter_mut() {
+		node.visit_inputs_mut(|input| if let Input::Node(NodeID(dep)) = input {
+			*dep = new_index[*dep as usize].unwrap() as u32;
+		});
+	}
+
+	for output in synth.output_nodes.iter_mut().chain(synth.labels.iter_mut().map(|(_, n)| n)) {
+		*output = new_index[*output].unwrap();
+	}
+
+	synth.invalidate_block_plan();
+
+	Ok(OptimiseReport {
+		nodes_before: num_nodes,
+		nodes_after: synth.instructions.len(),
+
+		folded,
+		remaps_collapsed,
+
+		node_map: NodeMap(new_index.into_iter().map(|n| n.map(|n| NodeID(n as u32))).collect()),
+	})
+}
+
+// Remaps are linear, so a remap of a remap is a single remap over the inner input range.
+// The inner remap is left for anything else reading it
+fn collapse_remap(node: &mut Node, earlier: &[Node]) -> bool {
+	if let Node::Remap{input: Input::Node(NodeID(inner)), in_lb, in_ub, out_lb, out_ub} = *node {
+		if let Node::Remap{input: inner_input, in_lb: inner_in_lb, in_ub: inner_in_ub, out_lb: inner_out_lb, out_ub: inner_out_ub} = earlier[inner as usize] {
+			// Empty input ranges divide by zero, so merging them would change the result
+			if in_lb == in_ub || inner_in_lb == inner_in_ub {
+				return false
+			}
+
+			*node = Node::Remap {
+				input: inner_input,
+				in_lb: inner_in_lb,
+				in_ub: inner_in_ub,
+				out_lb: remap(inner_out_lb, in_lb, in_ub, out_lb, out_ub),
+				out_ub: remap(inner_out_ub, in_lb, in_ub, out_lb, out_ub),
+			};
+
+			return true
+		}
+	}
+
+	false
+}
+
+// What reads of a node can be replaced with, if it doesn't need to be evaluated.
+// Constants are computed exactly as evaluation would
+fn simplify(node: &Node) -> Option<Input> {
+	use self::Input::Literal as Lit;
+
+	let value = match *node {
+		Node::Add(Lit(a), Lit(b)) => a + b,
+		Node::Subtract(Lit(a), Lit(b)) => a - b,
+		Node::Multiply(Lit(a), Lit(b)) => a * b,
+		Node::Divide(Lit(a), Lit(b)) => a / b,
+		Node::Power(Lit(a), Lit(b)) => a.powf(b),
+
+		Node::Clamp{input: Lit(sample), lb: Lit(lb), ub: Lit(ub)} => sample.max(lb).min(ub),
+		Node::Remap{input: Lit(sample), in_lb, in_ub, out_lb, out_ub} => remap(sample, in_lb, in_ub, out_lb, out_ub),
+		Node::Pan{input: Lit(sample), pan: Lit(pan), channel} => sample * pan_gain(pan, channel),
+		Node::Mix{a: Lit(a), b: Lit(b), mix: Lit(mix)} => lerp(a, b, mix),
+
+		Node::Multiply(x, Lit(one)) | Node::Multiply(Lit(one), x)
+			| Node::Divide(x, Lit(one)) | Node::Power(x, Lit(one)) if one == 1.0 && can_forward(x) => return Some(x),
+
+		Node::Add(x, Lit(zero)) | Node::Add(Lit(zero), x)
+			| Node::Subtract(x, Lit(zero)) if zero == 0.0 && can_forward(x) => return Some(x),
+
+		_ => return None,
+	};
+
+	Some(Lit(value))
+}
+
+// A store may be written between a node and the nodes that read it, so reads of stores
+// can't be moved into those nodes. Everything else reads the same wherever it's read
+fn can_forward(input: Input) -> bool {
+	!matches!(input, Input::Store(_))
+}
@@ -1,6 +1,8 @@
 use crate::context::EvaluationContext;
 use crate::synth::SynthID;
 
+use crate::lerp;
+
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
 pub struct ParameterID {
 	pub(crate) owner: SynthID,
@@ -9,48 +11,166 @@ pub struct ParameterID {
 
 #[derive(Clone, Debug)]
 pub struct Parameter {
-	value: f32
+	target: f32,
+	smoother: Smoother,
 }
 
 impl Parameter {
 	pub(crate) fn new() -> Self {
-		Parameter { value: 0.0 }
+		Parameter {
+			target: 0.0,
+			smoother: Smoother::new(SampleMode::Step),
+		}
 	}
 
 	pub(crate) fn update(&mut self, eval_ctx: &EvaluationContext) {
-		// interpolation
+		self.smoother.update(self.target, eval_ctx.sample_dt);
 	}
 
 	pub(crate) fn evaluate(&self) -> f32 {
-		self.value
+		self.smoother.current
+	}
+
+	pub(crate) fn target(&self) -> f32 { self.target }
+	pub(crate) fn sample_mode(&self) -> SampleMode { self.smoother.mode }
+
+	// Keeps the sample mode
+	pub(crate) fn transfer_state(&mut self, old: &Parameter) {
+		self.target = old.target;
+		self.smoother.transfer_state(&old.smoother);
 	}
 
-	pub fn set_value(&mut self, val: f32) { self.value = val; }
+	// Parameters move towards new values according to their sample mode
+	pub fn set_value(&mut self, val: f32) { self.target = val; }
+	pub fn set_sample_mode(&mut self, mode: SampleMode) { self.smoother.mode = mode; }
+
+	// Overrides the value seen by evaluate, used to replay parameter values in block evaluation
+	pub(crate) fn set_current(&mut self, val: f32) {
+		self.smoother.current = val;
+		self.smoother.settled = true;
+	}
 }
 
 
-#[derive(Clone, Debug)]
+// Times are in seconds. A time of zero behaves like Step
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SampleMode {
-	Linear
+	// Jump straight to new values
+	Step,
+	// Ramp to new values at a constant rate, arriving after the given time
+	Linear(f32),
+	// One pole filter with the given time constant
+	Exponential(f32),
+	// Ramp along a smoothstep curve, arriving after the given time
+	Cubic(f32),
 }
 
+#[derive(Clone, Debug)]
+pub(crate) struct Smoother {
+	mode: SampleMode,
+
+	current: f32,
+	start: f32,
+	target: f32,
+	// In [0, 1] along the current ramp
+	progress: f32,
+
+	// Jumps to the first target it sees, so initial values aren't smoothed
+	settled: bool,
+}
+
+impl Smoother {
+	pub(crate) fn new(mode: SampleMode) -> Self {
+		Smoother {
+			mode,
+
+			current: 0.0,
+			start: 0.0,
+			target: 0.0,
+			progress: 1.0,
+
+			settled: false,
+		}
+	}
+
+	pub(crate) fn transfer_state(&mut self, old: &Smoother) {
+		self.current = old.current;
+		self.start = old.start;
+		self.target = old.target;
+		self.progress = old.progress;
+		self.settled = old.settled;
+	}
 
+	pub(crate) fn update(&mut self, target: f32, dt: f32) -> f32 {
+		if !self.settled {
+			self.settled = true;
+			self.current = target;
+			self.start = target;
+			self.target = target;
+		}
+
+		// Retargeting mid ramp starts a new ramp from wherever it got to
+		if target != self.target {
+			self.start = self.current;
+			self.target = target;
+			self.progress = 0.0;
+		}
+
+		self.current = match self.mode {
+			SampleMode::Step => target,
+
+			SampleMode::Linear(time) | SampleMode::Cubic(time) if time <= 0.0 => target,
+			SampleMode::Exponential(time) if time <= 0.0 => target,
+
+			SampleMode::Linear(_) | SampleMode::Cubic(_) if self.progress >= 1.0 => target,
+
+			SampleMode::Linear(time) => {
+				self.progress = (self.progress + dt / time).min(1.0);
+				lerp(self.start, self.target, self.progress)
+			}
+
+			SampleMode::Cubic(time) => {
+				self.progress = (self.progress + dt / time).min(1.0);
+				let t = self.progress;
+				lerp(self.start, self.target, t * t * (3.0 - 2.0 * t))
+			}
+
+			SampleMode::Exponential(time) => {
+				let a = 1.0 - (-dt / time).exp();
+				lerp(self.current, self.target, a)
+			}
+		};
+
+		self.current
+	}
+}
+
+
+// Reads the target of a parameter, smoothed independently of the parameter's own sample mode
 #[derive(Clone, Debug)]
 pub struct ParameterSampler {
 	parameter: ParameterID,
-	sample_mode: SampleMode,
+	smoother: Smoother,
 }
 
 impl ParameterSampler {
 	pub(crate) fn new(parameter: ParameterID, sample_mode: SampleMode) -> Self {
 		ParameterSampler {
-			parameter, sample_mode
+			parameter,
+			smoother: Smoother::new(sample_mode),
 		}
 	}
 
-	pub(crate) fn sample(&mut self, parameters: &[Parameter]) -> f32 {
-		let new_sample = parameters[self.parameter.id as usize].evaluate();
-		new_sample
-		// TODO
+	pub(crate) fn parameter(&self) -> ParameterID { self.parameter }
+	pub(crate) fn sample_mode(&self) -> SampleMode { self.smoother.mode }
+	pub(crate) fn parameter_mut(&mut self) -> &mut ParameterID { &mut self.parameter }
+
+	pub(crate) fn transfer_state(&mut self, old: &ParameterSampler) {
+		self.smoother.transfer_state(&old.smoother);
+	}
+
+	pub(crate) fn sample(&mut self, parameters: &[Parameter], dt: f32) -> f32 {
+		let target = parameters[self.parameter.id as usize].target();
+		self.smoother.update(target, dt)
 	}
-}
\ No newline at end of file
+}
@@ -0,0 +1,852 @@
+use crate::synth::{Synth, SynthID, StoreID};
+use crate::node::{Node, NodeID, NodeContainer, Input, NODE_NAMES};
+use crate::buffer::{Buffer, BufferID, BufferUsageType, Sequencer};
+use crate::parameter::{ParameterID, SampleMode};
+use crate::gate::Gate;
+use crate::wavetable::WavetableID;
+use crate::SynthResult;
+
+use failure::{bail, ensure, format_err};
+
+// Bumped whenever a change to the format would make existing patches read differently
+pub const PATCH_VERSION: u32 = 1;
+
+const TEXT_HEADER: &str = "voi-synth-patch";
+const BINARY_MAGIC: &[u8; 4] = b"VOIP";
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PatchFormat {
+	// Line based and human readable, with one record per line
+	Text,
+	// The same records, packed little endian
+	Binary,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Record {
+	Gain, Store, Parameter, Buffer, Node, Output, Label,
+}
+
+// Binary patches refer to records, node types and sample modes by their position in these lists,
+// with node types listed alongside Node as NODE_NAMES,
+// so new entries only ever go on the end
+const RECORDS: &[Record] = &[
+	Record::Gain, Record::Store, Record::Parameter, Record::Buffer,
+	Record::Node, Record::Output, Record::Label,
+];
+
+const SAMPLE_MODES: &[&str] = &["step", "linear", "exponential", "cubic"];
+
+impl Record {
+	fn name(self) -> &'static str {
+		match self {
+			Record::Gain => "gain",
+			Record::Store => "store",
+			Record::Parameter => "param",
+			Record::Buffer => "buffer",
+			Record::Node => "node",
+			Record::Output => "output",
+			Record::Label => "label",
+		}
+	}
+}
+
+trait PatchWriter {
+	fn record(&mut self, record: Record);
+	fn choice(&mut self, options: &[&str], idx: usize);
+	fn int(&mut self, value: u32);
+	fn float(&mut self, value: f32);
+	fn string(&mut self, value: &str);
+	fn input(&mut self, input: Input);
+	fn buffer(&mut self, id: BufferID);
+
+	fn sample_mode(&mut self, mode: SampleMode) {
+		let (idx, time) = match mode {
+			SampleMode::Step => (0, 0.0),
+			SampleMode::Linear(time) => (1, time),
+			SampleMode::Exponential(time) => (2, time),
+			SampleMode::Cubic(time) => (3, time),
+		};
+
+		self.choice(SAMPLE_MODES, idx);
+		self.float(time);
+	}
+}
+
+trait PatchReader {
+	// None at the end of the patch
+	fn record(&mut self) -> SynthResult<Option<Record>>;
+	fn choice(&mut self, what: &str, options: &[&str]) -> SynthResult<usize>;
+	fn int(&mut self) -> SynthResult<u32>;
+	fn float(&mut self) -> SynthResult<f32>;
+	fn string(&mut self) -> SynthResult<String>;
+	fn input(&mut self, owner: SynthID) -> SynthResult<Input>;
+	fn buffer(&mut self) -> SynthResult<BufferID>;
+
+	fn sample_mode(&mut self) -> SynthResult<SampleMode> {
+		let idx = self.choice("sample mode", SAMPLE_MODES)?;
+		let time = self.float()?;
+
+		Ok(match idx {
+			0 => SampleMode::Step,
+			1 => SampleMode::Linear(time),
+			2 => SampleMode::Exponential(time),
+			_ => SampleMode::Cubic(time),
+		})
+	}
+
+	fn small_int(&mut self, what: &str) -> SynthResult<u8> {
+		let value = self.int()?;
+		ensure!(value <= u8::MAX as u32, "{} {} is out of range", what, value);
+		Ok(value as u8)
+	}
+}
+
+
+
+pub(crate) fn to_text(synth: &Synth) -> SynthResult<String> {
+	let mut writer = TextWriter(format!("{} {}\n", TEXT_HEADER, PATCH_VERSION));
+	write_synth(synth, &mut writer)?;
+	writer.0.push('\n');
+	Ok(writer.0)
+}
+
+pub(crate) fn from_text(text: &str) -> SynthResult<Synth> {
+	let mut reader = TextReader::new(text)?;
+	let synth = read_synth(&mut reader)
+		.map_err(|e| format_err!("Line {}: {}", reader.line, e))?;
+
+	synth.validate_patch()?;
+	Ok(synth)
+}
+
+pub(crate) fn to_bytes(synth: &Synth) -> SynthResult<Vec<u8>> {
+	let mut writer = BinaryWriter(BINARY_MAGIC.to_vec());
+	writer.int(PATCH_VERSION);
+	write_synth(synth, &mut writer)?;
+	writer.0.push(0);
+	Ok(writer.0)
+}
+
+pub(crate) fn from_bytes(bytes: &[u8]) -> SynthResult<Synth> {
+	let mut reader = BinaryReader::new(bytes)?;
+	let synth = read_synth(&mut reader)
+		.map_err(|e| format_err!("Byte {}: {}", reader.offset, e))?;
+
+	ensure!(reader.offset == bytes.len(), "Unexpected data after the end of the patch, at byte {}", reader.offset);
+
+	synth.validate_patch()?;
+	Ok(synth)
+}
+
+pub(crate) fn is_binary(bytes: &[u8]) -> bool {
+	bytes.starts_with(BINARY_MAGIC)
+}
+
+fn check_version(version: u32) -> SynthResult<()> {
+	ensure!(version == PATCH_VERSION, "Patch is format version {}, but only version {} can be read", version, PATCH_VERSION);
+	Ok(())
+}
+
+
+
+// Running state, like oscillator phases and envelope positions, isn't saved
+fn write_synth<W: PatchWriter>(synth: &Synth, writer: &mut W) -> SynthResult<()> {
+	// Parameters are saved by index, which would silently change their owner
+	for (idx, node) in synth.instructions.iter().enumerate() {
+		let mut foreign = None;
+
+		node.visit_inputs(|input| match input {
+			Input::Parameter(param) if param.owner != synth.id => foreign = Some(param),
+			_ => {}
+		});
+
+		if let Node::ParameterSampler(sampler) = node {
+			if sampler.parameter().owner != synth.id {
+				foreign = Some(sampler.parameter());
+			}
+		}
+
+		if let Some(ParameterID{owner, id}) = foreign {
+			bail!("Node {} ({}) uses parameter {} of {:?}, which can't be saved with {:?}", idx, node.name(), id, owner, synth.id);
+		}
+	}
+
+	writer.record(Record::Gain);
+	writer.float(synth.gain);
+
+	for (idx, &value) in synth.value_store.iter().enumerate() {
+		writer.record(Record::Store);
+		writer.int(idx as u32);
+		writer.float(value);
+	}
+
+	for (idx, parameter) in synth.parameters.iter().enumerate() {
+		writer.record(Record::Parameter);
+		writer.int(idx as u32);
+		writer.float(parameter.target());
+		writer.sample_mode(parameter.sample_mode());
+	}
+
+	// A sample rate of zero means the buffer doesn't have one
+	for (idx, buffer) in synth.local_buffers.iter().enumerate() {
+		writer.record(Record::Buffer);
+		writer.int(idx as u32);
+		writer.int(buffer.channels as u32);
+		writer.float(buffer.sample_rate.unwrap_or(0.0));
+		writer.int(buffer.data.len() as u32);
+
+		for &sample in buffer.data.iter() {
+			writer.float(sample);
+		}
+	}
+
+	for (idx, node) in synth.instructions.iter().enumerate() {
+		writer.record(Record::Node);
+		writer.int(idx as u32);
+		writer.choice(NODE_NAMES, node.type_index());
+		write_node(node, writer);
+	}
+
+	for &output in synth.output_nodes.iter() {
+		writer.record(Record::Output);
+		writer.int(output as u32);
+	}
+
+	for (label, node) in synth.labels.iter() {
+		writer.record(Record::Label);
+		writer.int(*node as u32);
+		writer.string(label);
+	}
+
+	Ok(())
+}
+
+// Settings that aren't inputs come first, followed by inputs in declaration order
+fn write_node<W: PatchWriter>(node: &Node, writer: &mut W) {
+	match node {
+		Node::Remap{in_lb, in_ub, out_lb, out_ub, ..} => {
+			for &value in [*in_lb, *in_ub, *out_lb, *out_ub].iter() {
+				writer.float(value);
+			}
+		}
+
+		Node::Pan{channel, ..} => writer.int(*channel as u32),
+		Node::StoreWrite(StoreID(store), _) => writer.int(*store),
+
+		Node::Sampler{sampler, ..} => {
+			writer.buffer(sampler.seq.buffer_id);
+			writer.int(sampler.seq.channel as u32);
+		}
+
+		Node::Sequencer{seq, ..} => {
+			writer.buffer(seq.buffer_id);
+			writer.int(seq.channel as u32);
+		}
+
+		Node::Wavetable{table, ..} => {
+			writer.buffer(table.buffer);
+			writer.int(table.frame_size);
+			writer.int(table.frames);
+		}
+
+		Node::ParameterSampler(sampler) => {
+			writer.int(sampler.parameter().id);
+			writer.sample_mode(sampler.sample_mode());
+		}
+
+		Node::EnvAR(env) => for &time in env.times().iter() { writer.float(time) },
+		Node::EnvADSR(env) => for &time in env.times().iter() { writer.float(time) },
+
+		Node::BusInput(channel) => writer.int(*channel as u32),
+
+		_ => {}
+	}
+
+	node.visit_inputs(|input| writer.input(input));
+}
+
+fn read_synth<R: PatchReader>(reader: &mut R) -> SynthResult<Synth> {
+	let mut synth = Synth::new();
+
+	let in_order = |what: &str, idx: u32, expected: usize| {
+		ensure!(idx as usize == expected, "{} {} is out of order, expected {} {}", what, idx, what, expected);
+		Ok(())
+	};
+
+	while let Some(record) = reader.record()? {
+		match record {
+			Record::Gain => {
+				let gain = reader.float()?;
+				synth.set_gain(gain);
+			}
+
+			Record::Store => {
+				in_order("Store", reader.int()?, synth.value_store.len())?;
+				let value = reader.float()?;
+				synth.value_store.push(value);
+			}
+
+			Record::Parameter => {
+				in_order("Parameter", reader.int()?, synth.parameters.len())?;
+				let value = reader.float()?;
+				let mode = reader.sample_mode()?;
+
+				let param = synth.new_parameter();
+				let parameter = synth.get_parameter(param);
+				parameter.set_value(value);
+				parameter.set_sample_mode(mode);
+			}
+
+			Record::Buffer => {
+				in_order("Buffer", reader.int()?, synth.local_buffers.len())?;
+
+				let channels = reader.int()? as usize;
+				let sample_rate = reader.float()?;
+				let len = reader.int()? as usize;
+
+				ensure!(channels > 0, "Buffers must have at least one channel");
+				ensure!(sample_rate >= 0.0, "Buffer has a negative sample rate");
+
+				let mut data = Vec::with_capacity(len.min(1 << 20));
+				for _ in 0..len {
+					data.push(reader.float()?);
+				}
+
+				let mut buffer = Buffer::from_interleaved(data, channels);
+				if sample_rate > 0.0 {
+					buffer = buffer.with_sample_rate(sample_rate);
+				}
+
+				synth.add_buffer(buffer)?;
+			}
+
+			Record::Node => {
+				in_order("Node", reader.int()?, synth.instructions.len())?;
+				let name = NODE_NAMES[reader.choice("node type", NODE_NAMES)?];
+				read_node(&mut synth, name, reader)?;
+			}
+
+			Record::Output => {
+				let output = reader.int()?;
+				synth.output_nodes.push(output as usize);
+			}
+
+			Record::Label => {
+				let node = reader.int()? as usize;
+				let label = reader.string()?;
+				synth.labels.push((label, node));
+			}
+		}
+	}
+
+	Ok(synth)
+}
+
+// Nodes are added with placeholder inputs, which are filled in once the settings are known
+fn read_node<R: PatchReader>(synth: &mut Synth, name: &str, reader: &mut R) -> SynthResult<()> {
+	let zero = Input::Literal(0.0);
+
+	match name {
+		"sine" => { synth.new_sine(zero); }
+		"triangle" => { synth.new_triangle(zero); }
+		"square" => { synth.new_square(zero); }
+		"saw" => { synth.new_saw(zero); }
+
+		"bl_triangle" => { synth.new_bl_triangle(zero); }
+		"bl_square" => { synth.new_bl_square(zero); }
+		"bl_saw" => { synth.new_bl_saw(zero); }
+
+		"wavetable" => {
+			let buffer = reader.buffer()?;
+			let frame_size = reader.int()? as usize;
+			let frames = reader.int()? as usize;
+			synth.new_wavetable(WavetableID::new(buffer, frame_size, frames)?, zero, zero);
+		}
+
+		"lowpass" => { synth.new_lowpass(zero, zero); }
+		"highpass" => { synth.new_highpass(zero, zero); }
+
+		"clamp" => { synth.new_clamp(zero, zero, zero); }
+
+		"remap" => {
+			let in_lb = reader.float()?;
+			let in_ub = reader.float()?;
+			let out_lb = reader.float()?;
+			let out_ub = reader.float()?;
+			synth.new_remap(zero, in_lb, in_ub, out_lb, out_ub);
+		}
+
+		"pan" => {
+			let channel = reader.small_int("Pan channel")?;
+			synth.add_node(Node::Pan{ input: zero, pan: zero, channel });
+		}
+
+		"mix" => { synth.new_mix(zero, zero, zero); }
+		"add" => { synth.new_add(zero, zero); }
+		"subtract" => { synth.new_sub(zero, zero); }
+		"multiply" => { synth.new_multiply(zero, zero); }
+		"divide" => { synth.new_divide(zero, zero); }
+		"power" => { synth.new_power(zero, zero); }
+
+		"store_write" => {
+			let store = reader.int()?;
+			synth.new_store_write(StoreID(store), zero);
+		}
+
+		"sampler" => {
+			let buffer = reader.buffer()?;
+			let channel = reader.int()? as usize;
+			synth.new_sampler_channel(buffer, channel, zero);
+		}
+
+		"sequencer" => {
+			let buffer = reader.buffer()?;
+			let channel = reader.int()? as usize;
+
+			synth.add_node(Node::Sequencer{
+				seq: Sequencer::with_channel(buffer, channel),
+				advance: Gate::new(zero),
+				reset: Gate::new(zero),
+			});
+		}
+
+		"param_sampler" => {
+			let id = reader.int()?;
+			let mode = reader.sample_mode()?;
+			synth.new_param_sampler(ParameterID{ owner: synth.id, id }, mode);
+		}
+
+		"env_ar" => {
+			let attack = reader.float()?;
+			let release = reader.float()?;
+			synth.new_env_ar(attack, release, zero);
+		}
+
+		"env_adsr" => {
+			let attack = reader.float()?;
+			let decay = reader.float()?;
+			let sustain = reader.float()?;
+			let release = reader.float()?;
+			synth.new_env_adsr(attack, decay, sustain, release, zero);
+		}
+
+		"bus_input" => {
+			let channel = reader.small_int("Bus channel")?;
+			synth.new_bus_input(channel);
+		}
+
+		_ => unreachable!(),
+	}
+
+	let node = synth.instructions.last_mut().unwrap();
+
+	let mut num_inputs = 0;
+	node.visit_inputs(|_| num_inputs += 1);
+
+	let mut inputs = Vec::with_capacity(num_inputs);
+	for _ in 0..num_inputs {
+		inputs.push(reader.input(synth.id)?);
+	}
+
+	let mut inputs = inputs.into_iter();
+	node.visit_inputs_mut(|input| *input = inputs.next().unwrap());
+
+	Ok(())
+}
+
+
+
+struct TextWriter(String);
+
+impl TextWriter {
+	fn token(&mut self, token: &str) {
+		self.0.push(' ');
+		self.0.push_str(token);
+	}
+}
+
+impl PatchWriter for TextWriter {
+	fn record(&mut self, record: Record) {
+		if !self.0.ends_with('\n') {
+			self.0.push('\n');
+		}
+
+		self.0.push_str(record.name());
+	}
+
+	fn choice(&mut self, options: &[&str], idx: usize) { self.token(options[idx]) }
+	fn int(&mut self, value: u32) { self.token(&value.to_string()) }
+
+	// Debug formatting is the shortest representation that parses back to the same value
+	fn float(&mut self, value: f32) { self.token(&format!("{:?}", value)) }
+
+	fn string(&mut self, value: &str) {
+		let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+		self.token(&format!("\"{}\"", escaped));
+	}
+
+	fn input(&mut self, input: Input) {
+		let token = match input {
+			Input::Literal(value) => format!("{:?}", value),
+			Input::Node(NodeID(node)) => format!("node:{}", node),
+			Input::Store(StoreID(store)) => format!("store:{}", store),
+			Input::Parameter(ParameterID{id, ..}) => format!("param:{}", id),
+		};
+
+		self.token(&token);
+	}
+
+	fn buffer(&mut self, BufferID(usage, idx, generation): BufferID) {
+		let token = match usage {
+			BufferUsageType::Local => format!("local:{}", idx),
+			BufferUsageType::Shared => format!("shared:{}:{}", idx, generation),
+		};
+
+		self.token(&token);
+	}
+
+	fn sample_mode(&mut self, mode: SampleMode) {
+		let token = match mode {
+			SampleMode::Step => "step".to_owned(),
+			SampleMode::Linear(time) => format!("linear:{:?}", time),
+			SampleMode::Exponential(time) => format!("exponential:{:?}", time),
+			SampleMode::Cubic(time) => format!("cubic:{:?}", time),
+		};
+
+		self.token(&token);
+	}
+}
+
+struct TextReader<'a> {
+	lines: std::str::Lines<'a>,
+	// Of the line being read, counting from 1
+	line: usize,
+	tokens: std::vec::IntoIter<Token>,
+}
+
+// Quoted tokens are only ever strings, so a label can't be mistaken for anything else
+struct Token {
+	text: String,
+	quoted: bool,
+}
+
+impl<'a> TextReader<'a> {
+	fn new(text: &'a str) -> SynthResult<Self> {
+		let mut reader = TextReader {
+			lines: text.lines(),
+			line: 0,
+			tokens: Vec::new().into_iter(),
+		};
+
+		let header = reader.next_line()
+			.map_err(|e| format_err!("Line {}: {}", reader.line, e))?;
+
+		match header.as_deref() {
+			Some([name, version]) if !name.quoted && name.text == TEXT_HEADER => {
+				let version = version.text.parse()
+					.map_err(|_| format_err!("Patch has an invalid version '{}'", version.text))?;
+
+				check_version(version)?;
+			}
+
+			_ => bail!("Not a synth patch, expected '{} {}' on the first line", TEXT_HEADER, PATCH_VERSION),
+		}
+
+		Ok(reader)
+	}
+
+	// Skips blank lines and comments
+	fn next_line(&mut self) -> SynthResult<Option<Vec<Token>>> {
+		for line in &mut self.lines {
+			self.line += 1;
+
+			let tokens = tokenize(line)?;
+			if !tokens.is_empty() {
+				return Ok(Some(tokens))
+			}
+		}
+
+		Ok(None)
+	}
+
+	fn next_token(&mut self, what: &str) -> SynthResult<Token> {
+		self.tokens.next()
+			.ok_or_else(|| format_err!("Expected {}, found the end of the line", what))
+	}
+
+	fn token(&mut self, what: &str) -> SynthResult<String> {
+		let token = self.next_token(what)?;
+		ensure!(!token.quoted, "Expected {}, found \"{}\"", what, token.text);
+		Ok(token.text)
+	}
+
+	fn number<T: std::str::FromStr>(&mut self, what: &str) -> SynthResult<T> {
+		let token = self.token(what)?;
+		parse_number(&token, what)
+	}
+}
+
+fn parse_number<T: std::str::FromStr>(token: &str, what: &str) -> SynthResult<T> {
+	token.parse().map_err(|_| format_err!("Expected {}, found '{}'", what, token))
+}
+
+impl<'a> PatchReader for TextReader<'a> {
+	fn record(&mut self) -> SynthResult<Option<Record>> {
+		if let Some(token) = self.tokens.next() {
+			bail!("Unexpected '{}' at the end of the line", token.text);
+		}
+
+		let mut tokens = match self.next_line()? {
+			Some(tokens) => tokens.into_iter(),
+			None => return Ok(None),
+		};
+
+		let keyword = tokens.next().unwrap();
+		self.tokens = tokens;
+
+		ensure!(!keyword.quoted, "Expected a record, found \"{}\"", keyword.text);
+		let keyword = keyword.text;
+
+		match RECORDS.iter().find(|record| record.name() == keyword) {
+			Some(&record) => Ok(Some(record)),
+			None => bail!("Unknown record '{}'", keyword),
+		}
+	}
+
+	fn choice(&mut self, what: &str, options: &[&str]) -> SynthResult<usize> {
+		let token = self.token(what)?;
+		options.iter().position(|&o| o == token)
+			.ok_or_else(|| format_err!("Unknown {} '{}'", what, token))
+	}
+
+	fn int(&mut self) -> SynthResult<u32> { self.number("an integer") }
+	fn float(&mut self) -> SynthResult<f32> { self.number("a number") }
+	fn string(&mut self) -> SynthResult<String> {
+		let token = self.next_token("a string")?;
+		ensure!(token.quoted, "Expected a quoted string, found '{}'", token.text);
+		Ok(token.text)
+	}
+
+	fn input(&mut self, owner: SynthID) -> SynthResult<Input> {
+		let token = self.token("an input")?;
+		let mut parts = token.splitn(2, ':');
+
+		let input = match (parts.next().unwrap(), parts.next()) {
+			("node", Some(node)) => Input::Node(NodeID(parse_number(node, "a node index")?)),
+			("store", Some(store)) => Input::Store(StoreID(parse_number(store, "a store index")?)),
+			("param", Some(id)) => Input::Parameter(ParameterID{ owner, id: parse_number(id, "a parameter index")? }),
+			(value, None) => Input::Literal(parse_number(value, "a number or an input")?),
+			_ => bail!("Expected an input, found '{}'", token),
+		};
+
+		Ok(input)
+	}
+
+	fn buffer(&mut self) -> SynthResult<BufferID> {
+		let token = self.token("a buffer")?;
+		let parts: Vec<_> = token.split(':').collect();
+
+		let buffer = match parts.as_slice() {
+			["local", idx] => BufferID(BufferUsageType::Local, parse_number(idx, "a buffer index")?, 0),
+			["shared", idx, generation] => BufferID(BufferUsageType::Shared,
+				parse_number(idx, "a buffer index")?, parse_number(generation, "a buffer generation")?),
+			_ => bail!("Expected a buffer, found '{}'", token),
+		};
+
+		Ok(buffer)
+	}
+
+	fn sample_mode(&mut self) -> SynthResult<SampleMode> {
+		let token = self.token("a sample mode")?;
+		let mut parts = token.splitn(2, ':');
+
+		let mode = match (parts.next().unwrap(), parts.next()) {
+			("step", None) => SampleMode::Step,
+			("linear", Some(time)) => SampleMode::Linear(parse_number(time, "a time")?),
+			("exponential", Some(time)) => SampleMode::Exponential(parse_number(time, "a time")?),
+			("cubic", Some(time)) => SampleMode::Cubic(parse_number(time, "a time")?),
+			_ => bail!("Expected a sample mode, found '{}'", token),
+		};
+
+		Ok(mode)
+	}
+}
+
+// Splits on whitespace, up to a # outside of quotes. Quoted tokens may contain spaces
+fn tokenize(line: &str) -> SynthResult<Vec<Token>> {
+	let mut tokens = Vec::new();
+	let mut chars = line.chars().peekable();
+
+	while let Some(&c) = chars.peek() {
+		if c.is_whitespace() {
+			chars.next();
+			continue
+		}
+
+		if c == '#' { break }
+
+		let mut token = String::new();
+		let quoted = c == '"';
+
+		if quoted {
+			chars.next();
+
+			loop {
+				match chars.next() {
+					Some('"') => break,
+					Some('\\') => match chars.next() {
+						Some('n') => token.push('\n'),
+						Some(c) => token.push(c),
+						None => bail!("Unterminated string"),
+					}
+					Some(c) => token.push(c),
+					None => bail!("Unterminated string"),
+				}
+			}
+		} else {
+			while let Some(&c) = chars.peek() {
+				if c.is_whitespace() { break }
+				token.push(c);
+				chars.next();
+			}
+		}
+
+		tokens.push(Token{ text: token, quoted });
+	}
+
+	Ok(tokens)
+}
+
+
+
+struct BinaryWriter(Vec<u8>);
+
+impl PatchWriter for BinaryWriter {
+	// Zero marks the end of the patch
+	fn record(&mut self, record: Record) {
+		let idx = RECORDS.iter().position(|&r| r == record).unwrap();
+		self.0.push(idx as u8 + 1);
+	}
+
+	fn choice(&mut self, _: &[&str], idx: usize) { self.0.push(idx as u8) }
+	fn int(&mut self, value: u32) { self.0.extend_from_slice(&value.to_le_bytes()) }
+	fn float(&mut self, value: f32) { self.0.extend_from_slice(&value.to_bits().to_le_bytes()) }
+
+	fn string(&mut self, value: &str) {
+		self.int(value.len() as u32);
+		self.0.extend_from_slice(value.as_bytes());
+	}
+
+	fn input(&mut self, input: Input) {
+		match input {
+			Input::Literal(value) => { self.0.push(0); self.float(value); }
+			Input::Node(NodeID(node)) => { self.0.push(1); self.int(node); }
+			Input::Store(StoreID(store)) => { self.0.push(2); self.int(store); }
+			Input::Parameter(ParameterID{id, ..}) => { self.0.push(3); self.int(id); }
+		}
+	}
+
+	fn buffer(&mut self, BufferID(usage, idx, generation): BufferID) {
+		self.0.push(match usage {
+			BufferUsageType::Local => 0,
+			BufferUsageType::Shared => 1,
+		});
+
+		self.0.extend_from_slice(&idx.to_le_bytes());
+		self.0.extend_from_slice(&generation.to_le_bytes());
+	}
+}
+
+struct BinaryReader<'a> {
+	bytes: &'a [u8],
+	offset: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+	fn new(bytes: &'a [u8]) -> SynthResult<Self> {
+		ensure!(is_binary(bytes), "Not a binary synth patch");
+
+		let mut reader = BinaryReader { bytes, offset: BINARY_MAGIC.len() };
+		let version = reader.int()?;
+		check_version(version)?;
+
+		Ok(reader)
+	}
+
+	fn take(&mut self, len: usize) -> SynthResult<&'a [u8]> {
+		ensure!(self.bytes.len() - self.offset >= len, "Patch is truncated");
+
+		let bytes = &self.bytes[self.offset..self.offset + len];
+		self.offset += len;
+		Ok(bytes)
+	}
+
+	fn byte(&mut self) -> SynthResult<u8> {
+		Ok(self.take(1)?[0])
+	}
+
+	fn u16(&mut self) -> SynthResult<u16> {
+		let bytes = self.take(2)?;
+		Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+	}
+}
+
+impl<'a> PatchReader for BinaryReader<'a> {
+	fn record(&mut self) -> SynthResult<Option<Record>> {
+		match self.byte()? {
+			0 => Ok(None),
+			tag => match RECORDS.get(tag as usize - 1) {
+				Some(&record) => Ok(Some(record)),
+				None => bail!("Unknown record type {}", tag),
+			}
+		}
+	}
+
+	fn choice(&mut self, what: &str, options: &[&str]) -> SynthResult<usize> {
+		let idx = self.byte()? as usize;
+		ensure!(idx < options.len(), "Unknown {} {}", what, idx);
+		Ok(idx)
+	}
+
+	fn int(&mut self) -> SynthResult<u32> {
+		let bytes = self.take(4)?;
+		Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+	}
+
+	fn float(&mut self) -> SynthResult<f32> {
+		Ok(f32::from_bits(self.int()?))
+	}
+
+	fn string(&mut self) -> SynthResult<String> {
+		let len = self.int()? as usize;
+		let bytes = self.take(len)?;
+
+		String::from_utf8(bytes.to_vec())
+			.map_err(|_| format_err!("String is not valid utf-8"))
+	}
+
+	fn input(&mut self, owner: SynthID) -> SynthResult<Input> {
+		let input = match self.byte()? {
+			0 => Input::Literal(self.float()?),
+			1 => Input::Node(NodeID(self.int()?)),
+			2 => Input::Store(StoreID(self.int()?)),
+			3 => Input::Parameter(ParameterID{ owner, id: self.int()? }),
+			tag => bail!("Unknown input type {}", tag),
+		};
+
+		Ok(input)
+	}
+
+	fn buffer(&mut self) -> SynthResult<BufferID> {
+		let usage = match self.byte()? {
+			0 => BufferUsageType::Local,
+			1 => BufferUsageType::Shared,
+			tag => bail!("Unknown buffer type {}", tag),
+		};
+
+		let idx = self.u16()?;
+		let generation = self.u16()?;
+		Ok(BufferID(usage, idx, generation))
+	}
+}
@@ -0,0 +1,75 @@
+use std::f64::consts::PI;
+
+use crate::SynthResult;
+
+use failure::ensure;
+
+// Lobes either side of the windowed sinc kernel
+const KERNEL_RADIUS: f64 = 8.0;
+
+fn sinc(x: f64) -> f64 {
+	if x.abs() < 1e-9 {
+		1.0
+	} else {
+		(PI * x).sin() / (PI * x)
+	}
+}
+
+fn lanczos(x: f64) -> f64 {
+	if x.abs() >= KERNEL_RADIUS {
+		0.0
+	} else {
+		sinc(x) * sinc(x / KERNEL_RADIUS)
+	}
+}
+
+// Band-limited resampling of interleaved samples using a lanczos kernel.
+// When downsampling the kernel is widened so that content above the new nyquist is filtered out.
+// Samples after the last whole frame are dropped
+pub(crate) fn resample(samples: &[f32], channels: usize, from_rate: f32, to_rate: f32) -> SynthResult<Vec<f32>> {
+	ensure!(channels > 0, "Can't resample audio with no channels");
+	ensure!(from_rate > 0.0 && to_rate > 0.0, "Can't resample from {}Hz to {}Hz", from_rate, to_rate);
+
+	let in_frames = samples.len() / channels;
+	let samples = &samples[..in_frames * channels];
+
+	if from_rate == to_rate || in_frames == 0 {
+		return Ok(samples.to_vec());
+	}
+	let ratio = to_rate as f64 / from_rate as f64;
+	let out_frames = (in_frames as f64 * ratio).ceil() as usize;
+
+	let scale = ratio.min(1.0);
+	let radius = KERNEL_RADIUS / scale;
+
+	let mut output = Vec::with_capacity(out_frames * channels);
+	let mut accumulators = vec![0.0f64; channels];
+
+	for frame in 0..out_frames {
+		let center = frame as f64 / ratio;
+
+		let first = (center - radius).floor().max(0.0) as usize;
+		let last = ((center + radius).ceil() as usize).min(in_frames - 1);
+
+		for acc in accumulators.iter_mut() { *acc = 0.0; }
+		let mut weight_sum = 0.0;
+
+		for i in first..=last {
+			let weight = lanczos((center - i as f64) * scale);
+			if weight == 0.0 { continue }
+
+			weight_sum += weight;
+
+			let in_frame = &samples[i*channels .. (i+1)*channels];
+			for (acc, &s) in accumulators.iter_mut().zip(in_frame) {
+				*acc += s as f64 * weight;
+			}
+		}
+
+		// Normalising by the kernel sum avoids gain ripple and droop at the edges of the input
+		let norm = if weight_sum.abs() > 1e-9 { 1.0 / weight_sum } else { 0.0 };
+		output.extend(accumulators.iter().map(|acc| (acc * norm) as f32));
+	}
+
+	Ok(output)
+}
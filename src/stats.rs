@@ -0,0 +1,153 @@
+use crate::synth::SynthID;
+
+// Number of recent buffers fill time statistics are taken over
+pub const FILL_TIME_HISTORY: usize = 256;
+
+// Snapshot of evaluation performance and output levels.
+// Times are in microseconds, levels are linear unless stated otherwise
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+	// Over the last FILL_TIME_HISTORY buffers
+	pub average_fill_time: f32,
+	pub peak_fill_time: f32,
+	pub median_fill_time: f32,
+	pub p95_fill_time: f32,
+	pub p99_fill_time: f32,
+
+	// Time available to fill one buffer, given its size and the sample rate
+	pub buffer_deadline: f32,
+	// Fill time as a fraction of buffer_deadline. Above 1.0 the evaluation thread can't keep up
+	pub cpu_load: f32,
+	pub peak_cpu_load: f32,
+
+	pub buffers_filled: u64,
+	// Buffers that took longer to fill than their deadline. These only become audible
+	// underruns once the buffers queued ahead have run out
+	pub deadline_misses: u64,
+	// Calls to Context::render that ran out of rendered audio
+	pub render_underruns: u64,
+
+	pub synth_count: usize,
+	// Time spent evaluating each synth during the last buffer, in mixing order
+	pub synth_times: Vec<(SynthID, f32)>,
+
+	// Most attenuation applied by the master limiter during the last buffer, in dB
+	pub gain_reduction: f32,
+	pub limiter_envelope: f32,
+	pub dc_offset: f32,
+
+	// Of the final output during the last buffer
+	pub output_peak: f32,
+	pub output_rms: f32,
+}
+
+// Raw measurements, recorded by the evaluation thread and summarised on request
+#[derive(Clone)]
+pub(crate) struct StatsHistory {
+	fill_times: [f32; FILL_TIME_HISTORY],
+	buffers_filled: u64,
+	deadline_misses: u64,
+	buffer_deadline: f32,
+
+	pub(crate) synth_times: Vec<(SynthID, f32)>,
+
+	pub(crate) gain_reduction: f32,
+	pub(crate) limiter_envelope: f32,
+	pub(crate) dc_offset: f32,
+	pub(crate) output_peak: f32,
+	pub(crate) output_rms: f32,
+}
+
+impl StatsHistory {
+	pub(crate) fn new() -> Self {
+		StatsHistory {
+			fill_times: [0.0; FILL_TIME_HISTORY],
+			buffers_filled: 0,
+			deadline_misses: 0,
+			buffer_deadline: 0.0,
+
+			synth_times: Vec::with_capacity(256),
+
+			gain_reduction: 0.0,
+			limiter_envelope: 0.0,
+			dc_offset: 0.0,
+			output_peak: 0.0,
+			output_rms: 0.0,
+		}
+	}
+
+	pub(crate) fn record_fill(&mut self, fill_time: f32, buffer_deadline: f32) {
+		let slot = (self.buffers_filled % FILL_TIME_HISTORY as u64) as usize;
+		self.fill_times[slot] = fill_time;
+		self.buffers_filled += 1;
+		self.buffer_deadline = buffer_deadline;
+
+		if fill_time > buffer_deadline {
+			self.deadline_misses += 1;
+		}
+	}
+
+	// Doesn't allocate once synth_times has grown to fit, so is safe on the evaluation thread
+	pub(crate) fn copy_to(&self, other: &mut StatsHistory) {
+		other.fill_times = self.fill_times;
+		other.buffers_filled = self.buffers_filled;
+		other.deadline_misses = self.deadline_misses;
+		other.buffer_deadline = self.buffer_deadline;
+
+		other.synth_times.clear();
+		other.synth_times.extend_from_slice(&self.synth_times);
+
+		other.gain_reduction = self.gain_reduction;
+		other.limiter_envelope = self.limiter_envelope;
+		other.dc_offset = self.dc_offset;
+		other.output_peak = self.output_peak;
+		other.output_rms = self.output_rms;
+	}
+
+	pub(crate) fn summarize(&self) -> Stats {
+		let count = (self.buffers_filled as usize).min(FILL_TIME_HISTORY);
+
+		let mut fill_times = self.fill_times[..count].to_vec();
+		fill_times.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+		let percentile = |p: f32| match count {
+			0 => 0.0,
+			_ => fill_times[((count - 1) as f32 * p).round() as usize],
+		};
+
+		let average_fill_time = match count {
+			0 => 0.0,
+			_ => fill_times.iter().sum::<f32>() / count as f32,
+		};
+
+		let peak_fill_time = percentile(1.0);
+
+		let load = |time: f32| if self.buffer_deadline > 0.0 { time / self.buffer_deadline } else { 0.0 };
+
+		Stats {
+			average_fill_time,
+			peak_fill_time,
+			median_fill_time: percentile(0.5),
+			p95_fill_time: percentile(0.95),
+			p99_fill_time: percentile(0.99),
+
+			buffer_deadline: self.buffer_deadline,
+			cpu_load: load(average_fill_time),
+			peak_cpu_load: load(peak_fill_time),
+
+			buffers_filled: self.buffers_filled,
+			deadline_misses: self.deadline_misses,
+			render_underruns: 0,
+
+			synth_count: self.synth_times.len(),
+			synth_times: self.synth_times.clone(),
+
+			gain_reduction: self.gain_reduction,
+			limiter_envelope: self.limiter_envelope,
+			dc_offset: self.dc_offset,
+
+			output_peak: self.output_peak,
+			output_rms: self.output_rms,
+		}
+	}
+}
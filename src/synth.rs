@@ -1,33 +1,93 @@
-use crate::buffer::{Buffer, BufferID, BufferUsageType, SamplerContext};
+use crate::buffer::{Buffer, BufferID, BufferUsageType, BufferAllocator, SamplerContext, MAX_BUFFERS};
 use crate::context::EvaluationContext;
-use crate::node::{Node, NodeID, InputContext};
+use crate::node::{Node, NodeID, Input, InputContext};
+use crate::node::{saw_shape, square_shape, triangle_shape, bl_saw_shape, bl_square_shape, bl_triangle_shape, lowpass_step, highpass_step, remap, pan_gain};
 use crate::parameter::{ParameterID, Parameter};
+use crate::loader::{load_audio_file, ChannelMode};
+use crate::block::{self, BlockPlan, Step, EvaluationMode, BLOCK_SIZE};
+use crate::compile::{self, NodeMap};
+use crate::optimise::{self, OptimiseReport};
+use crate::patch::{self, PatchFormat};
+use crate::module::{self, Module, ModuleInstance};
+use crate::envelope::EnvelopeMonitor;
+use crate::wavetable::{Wavetable, WavetableID};
+use crate::SynthResult;
 
 use crate::lerp;
 
-use std::f32::consts::PI;
+use failure::{bail, ensure, format_err};
+
 use std::sync::atomic;
+use std::sync::Arc;
+use std::time;
+use std::path::Path;
+use std::ops::Range;
 
 
 static SYNTH_COUNTER: atomic::AtomicU32 = atomic::AtomicU32::new(0);
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+// Modules take ids from the same counter, so that their ParameterIDs never match a synth's
+pub(crate) fn next_synth_id() -> SynthID {
+	SynthID(SYNTH_COUNTER.fetch_add(1, atomic::Ordering::Relaxed))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct SynthID (pub(crate) u32);
 
 #[derive(Copy, Clone, Debug)]
 pub struct StoreID (pub(crate) u32);
 
+// How a released synth finishes before it is removed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Release {
+	// Fades gain out over the given time in seconds
+	Fade(f32),
+	// Plays until the given EnvAR or EnvADSR node falls silent. Other nodes are rejected by release_synth
+	Envelope(NodeID),
+}
+
+// Fails for envelope releases that name a node that isn't one of the synth's envelopes
+pub(crate) fn check_release(release: Release, envelopes: &[NodeID]) -> SynthResult<()> {
+	if let Release::Envelope(node) = release {
+		ensure!(envelopes.contains(&node), "Can't release on node {}, which isn't an envelope", node.0);
+	}
+
+	Ok(())
+}
+
 #[derive(Clone, Debug)]
 pub struct Synth {
 	pub id: SynthID,
 
-	gain: f32,
-	output_node: Option<usize>,
+	pub(crate) gain: f32,
+	pub(crate) output_nodes: Vec<usize>,
 
 	pub(crate) instructions: Vec<Node>,
 	pub(crate) value_store: Vec<f32>,
 	pub(crate) local_buffers: Vec<Buffer>,
 	pub(crate) parameters: Vec<Parameter>,
+
+	evaluation_mode: EvaluationMode,
+	// Built on first block evaluation, and invalidated whenever the graph changes
+	block_plan: Option<BlockPlan>,
+
+	// Used to match nodes when replacing a synth
+	pub(crate) labels: Vec<(String, usize)>,
+
+	// Multiplies gain, moving by fade_step each frame until it reaches 0 or 1
+	fade_level: f32,
+	fade_step: f32,
+
+	// Microseconds spent evaluating since last taken, for stats
+	eval_time: f32,
+
+	release: Option<Release>,
+	// Output level below which a one-shot synth is silent, and how long in seconds it has to stay silent to be removed
+	one_shot: Option<(f32, f32)>,
+	silent_time: f32,
+
+	// Only set by tests, to check how contexts recover from synths that panic
+	panics: bool,
 }
 
 macro_rules! input_context {
@@ -49,27 +109,54 @@ macro_rules! sampler_context {
 	}}
 }
 
+impl Default for Synth {
+	fn default() -> Self { Synth::new() }
+}
+
 impl Synth {
 	pub fn new() -> Self {
 		Synth {
-			id: SynthID(SYNTH_COUNTER.fetch_add(1, atomic::Ordering::Relaxed)),
+			id: next_synth_id(),
 
 			gain: 1.0,
-			output_node: None,
+			output_nodes: Vec::new(),
 
 			instructions: Vec::new(),
 			value_store: Vec::new(),
 			local_buffers: Vec::new(),
 			parameters: Vec::new(),
+
+			evaluation_mode: EvaluationMode::PerSample,
+			block_plan: None,
+
+			labels: Vec::new(),
+
+			fade_level: 1.0,
+			fade_step: 0.0,
+
+			eval_time: 0.0,
+
+			release: None,
+			one_shot: None,
+			silent_time: 0.0,
+
+			panics: false,
 		}
 	}
 
+	pub fn set_evaluation_mode(&mut self, mode: EvaluationMode) { self.evaluation_mode = mode }
+	pub fn evaluation_mode(&self) -> EvaluationMode { self.evaluation_mode }
+
+	pub(crate) fn invalidate_block_plan(&mut self) { self.block_plan = None }
+
 	pub fn new_value_store(&mut self) -> StoreID {
+		self.invalidate_block_plan();
 		self.value_store.push(0.0);
 		StoreID(self.value_store.len() as u32 - 1)
 	}
 
 	pub fn new_parameter(&mut self) -> ParameterID {
+		self.invalidate_block_plan();
 		self.parameters.push(Parameter::new());
 		ParameterID {
 			owner: self.id,
@@ -82,23 +169,606 @@ impl Synth {
 		&mut self.parameters[id as usize]
 	}
 
-	pub fn new_buffer(&mut self, data: Vec<f32>) -> BufferID {
-		self.local_buffers.push(Buffer{ data });
-		BufferID(BufferUsageType::Local, self.local_buffers.len() as u16 - 1)
+	pub fn new_buffer(&mut self, data: Vec<f32>) -> SynthResult<BufferID> {
+		self.add_buffer(Buffer::from_interleaved(data, 1))
+	}
+
+	pub fn add_buffer(&mut self, buffer: Buffer) -> SynthResult<BufferID> {
+		ensure!(self.local_buffers.len() < MAX_BUFFERS, "Too many buffers in synth, the limit is {}", MAX_BUFFERS);
+
+		self.local_buffers.push(buffer);
+		Ok(BufferID(BufferUsageType::Local, (self.local_buffers.len() - 1) as u16, 0))
+	}
+
+	pub fn add_wavetable(&mut self, table: Wavetable) -> SynthResult<WavetableID> {
+		let (frame_size, frames) = (table.frame_size(), table.frames());
+		let buffer = self.add_buffer(table.into_buffer())?;
+		WavetableID::new(buffer, frame_size, frames)
+	}
+
+	// Decodes a wav or aiff file, resampled to sample_rate
+	pub fn load_buffer<P: AsRef<Path>>(&mut self, path: P, mode: ChannelMode, sample_rate: f32) -> SynthResult<BufferID> {
+		let audio = load_audio_file(path)?;
+		let buffer = audio.to_buffer(mode, sample_rate)?;
+		self.add_buffer(buffer)
 	}
 
 	pub fn set_gain(&mut self, gain: f32) { self.gain = gain }
-	pub fn set_output(&mut self, NodeID(output): NodeID) { self.output_node = Some(output as usize); }
+	pub fn set_output(&mut self, NodeID(output): NodeID) { self.output_nodes = vec![output as usize]; }
+
+	// One output node per channel
+	pub fn set_outputs(&mut self, outputs: &[NodeID]) {
+		self.output_nodes = outputs.iter().map(|&NodeID(o)| o as usize).collect();
+	}
+
+	pub fn channels(&self) -> usize { self.output_nodes.len().max(1) }
+
+	// Labelled nodes are matched by label when replacing a synth, rather than by position
+	pub fn set_label(&mut self, NodeID(node): NodeID, label: &str) {
+		self.labels.retain(|(l, n)| l != label && *n != node as usize);
+		self.labels.push((label.to_owned(), node as usize));
+	}
+
+	// Replaces an input of a node, counting inputs in the order they are passed when creating it.
+	// Inputs may read nodes added later, in which case the synth has to be compiled before it's used
+	pub fn set_input<I: Into<Input>>(&mut self, NodeID(node): NodeID, input_idx: usize, input: I) -> SynthResult<()> {
+		let input = input.into();
+		let mut count = 0;
+
+		match self.instructions.get_mut(node as usize) {
+			Some(instruction) => instruction.visit_inputs_mut(|slot| {
+				if count == input_idx { *slot = input; }
+				count += 1;
+			}),
+
+			None => bail!("Node {} doesn't exist", node),
+		}
+
+		ensure!(input_idx < count, "Node {} only has {} inputs", node, count);
+
+		self.invalidate_block_plan();
+		Ok(())
+	}
+
+	// Appends a copy of a module, connecting its inputs by name. Unconnected inputs read their defaults
+	pub fn add_module(&mut self, module: &Module, inputs: &[(&str, Input)]) -> SynthResult<ModuleInstance> {
+		module::instantiate(self, module, inputs)
+	}
+
+	// Sorts nodes so that they can be added in any order. Cycles are broken with one sample delays.
+	// Node ids from before compiling can be mapped to the compiled synth with the returned map
+	pub fn compile(&mut self) -> SynthResult<NodeMap> {
+		compile::compile(self)
+	}
+
+	// Patches hold the graph, stores, parameters with their values, local buffers, gain, outputs and labels,
+	// but not running state. Shared buffers are saved by id, so are only valid in the context that created them
+	pub fn to_patch_text(&self) -> SynthResult<String> {
+		patch::to_text(self)
+	}
+
+	pub fn to_patch_bytes(&self) -> SynthResult<Vec<u8>> {
+		patch::to_bytes(self)
+	}
+
+	// Loaded synths get a new id, and own the parameters in the patch
+	pub fn from_patch_text(text: &str) -> SynthResult<Synth> {
+		patch::from_text(text)
+	}
+
+	pub fn from_patch_bytes(bytes: &[u8]) -> SynthResult<Synth> {
+		patch::from_bytes(bytes)
+	}
+
+	pub fn save_patch<P: AsRef<Path>>(&self, path: P, format: PatchFormat) -> SynthResult<()> {
+		let bytes = match format {
+			PatchFormat::Text => self.to_patch_text()?.into_bytes(),
+			PatchFormat::Binary => self.to_patch_bytes()?,
+		};
+
+		std::fs::write(path, bytes)?;
+		Ok(())
+	}
+
+	// Reads either format
+	pub fn load_patch<P: AsRef<Path>>(path: P) -> SynthResult<Synth> {
+		let bytes = std::fs::read(path)?;
+
+		if patch::is_binary(&bytes) {
+			Synth::from_patch_bytes(&bytes)
+		} else {
+			let text = String::from_utf8(bytes)
+				.map_err(|_| format_err!("Patch is neither binary nor valid utf-8 text"))?;
+
+			Synth::from_patch_text(&text)
+		}
+	}
+
+	// Best run once the graph is complete, since node ids change. Synths that replace each other
+	// should be optimised alike, so that unlabelled nodes still line up
+	pub fn optimise(&mut self) -> SynthResult<OptimiseReport> {
+		optimise::optimise(self)
+	}
+
+	pub fn get_labelled_node(&self, label: &str) -> Option<NodeID> {
+		self.labels.iter()
+			.find(|(l, _)| l == label)
+			.map(|&(_, n)| NodeID(n as u32))
+	}
+
+	fn label_of(&self, node: usize) -> Option<&str> {
+		self.labels.iter()
+			.find(|&&(_, n)| n == node)
+			.map(|(l, _)| l.as_str())
+	}
+
+	// Copy of this synth with a new id. Parameters are owned by the copy, and are found
+	// at the same position in it, so ParameterIDs can be mapped with parameter_of_duplicate
+	pub fn duplicate(&self) -> Synth {
+		let mut synth = self.clone();
+		synth.adopt_id(next_synth_id());
+		synth
+	}
+
+	// The equivalent of a parameter of the template this synth was duplicated from
+	pub fn parameter_of_duplicate(&self, ParameterID{id, ..}: ParameterID) -> ParameterID {
+		ParameterID { owner: self.id, id }
+	}
+
+	pub(crate) fn monitor_envelope(&mut self, NodeID(node): NodeID) -> SynthResult<Arc<EnvelopeMonitor>> {
+		let monitor = Arc::new(EnvelopeMonitor::new());
+
+		match self.instructions.get_mut(node as usize) {
+			Some(Node::EnvAR(env)) => env.set_monitor(Some(monitor.clone())),
+			Some(Node::EnvADSR(env)) => env.set_monitor(Some(monitor.clone())),
+			_ => bail!("Node {} is not an envelope", node),
+		}
+
+		Ok(monitor)
+	}
+
+	// Nodes a synth can be released on with Release::Envelope
+	pub(crate) fn envelope_nodes(&self) -> Vec<NodeID> {
+		self.instructions.iter().enumerate()
+			.filter(|(_, node)| matches!(node, Node::EnvAR(_) | Node::EnvADSR(_)))
+			.map(|(idx, _)| NodeID(idx as u32))
+			.collect()
+	}
+
+	// Restarts an envelope's attack from wherever it is, without the gate having to fall first.
+	// Anything else is ignored, since the id has already been checked on the control side
+	pub(crate) fn retrigger_envelope(&mut self, NodeID(node): NodeID) {
+		match self.instructions.get_mut(node as usize) {
+			Some(Node::EnvAR(env)) => env.retrigger(),
+			Some(Node::EnvADSR(env)) => env.retrigger(),
+			_ => {}
+		}
+	}
+
+	// Checks every reference in the graph, so that bad graphs are rejected on the control side
+	// rather than panicking or reading stale values on the evaluation thread
+	pub(crate) fn validate(&self, shared_buffers: &BufferAllocator) -> SynthResult<()> {
+		self.check_references(Some(shared_buffers))
+	}
+
+	// Everything a patch refers to has to be in it, except shared buffers, which are checked
+	// when the synth is pushed to a context. Patches may also be compiled after they're loaded
+	pub(crate) fn validate_patch(&self) -> SynthResult<()> {
+		self.check_references(None)
+	}
+
+	// Without shared buffers the synth isn't about to be evaluated, so nodes may read later nodes
+	fn check_references(&self, shared_buffers: Option<&BufferAllocator>) -> SynthResult<()> {
+		let num_nodes = self.instructions.len();
+		let mut problems = Vec::new();
+
+		let check_parameter = |ParameterID{owner, id}: ParameterID| {
+			if owner != self.id {
+				Some(format!("uses parameter {} of {:?}, which belongs to another synth", id, owner))
+			} else if id as usize >= self.parameters.len() {
+				Some(format!("uses parameter {}, which doesn't exist", id))
+			} else {
+				None
+			}
+		};
+
+		let check_buffer = |id: BufferID| match id {
+			BufferID(BufferUsageType::Local, idx, _) if idx as usize >= self.local_buffers.len() =>
+				Some(format!("uses local buffer {}, which doesn't exist", idx)),
+
+			BufferID(BufferUsageType::Shared, ..) => shared_buffers?.validate(id).err()
+				.map(|e| format!("uses an invalid buffer: {}", e)),
+
+			_ => None,
+		};
+
+		for (idx, node) in self.instructions.iter().enumerate() {
+			let mut node_problems = Vec::new();
+
+			node.visit_inputs(|input| node_problems.extend(match input {
+				Input::Node(NodeID(dep)) if dep as usize >= num_nodes =>
+					Some(format!("reads node {}, which doesn't exist", dep)),
+
+				Input::Node(NodeID(dep)) if dep as usize >= idx && shared_buffers.is_some() =>
+					Some(format!("reads node {}, which is evaluated after it. Feedback has to go through a value store, or the synth compiled", dep)),
+
+				Input::Store(StoreID(store)) if store as usize >= self.value_store.len() =>
+					Some(format!("reads store {}, which doesn't exist", store)),
+
+				Input::Parameter(param) => check_parameter(param),
+
+				_ => None,
+			}));
+
+			node_problems.extend(match node {
+				Node::StoreWrite(StoreID(store), _) if *store as usize >= self.value_store.len() =>
+					Some(format!("writes store {}, which doesn't exist", store)),
+
+				Node::ParameterSampler(sampler) => check_parameter(sampler.parameter()),
+				Node::Sampler{sampler, ..} => check_buffer(sampler.seq.buffer_id),
+				Node::Sequencer{seq, ..} => check_buffer(seq.buffer_id),
+				Node::Wavetable{table, ..} => check_buffer(table.buffer),
+
+				_ => None,
+			});
+
+			for problem in node_problems {
+				problems.push(format!("node {} ({}) {}", idx, node.name(), problem));
+			}
+		}
+
+		for &output in self.output_nodes.iter().filter(|&&o| o >= num_nodes) {
+			problems.push(format!("output node {} doesn't exist", output));
+		}
+
+		for (label, node) in self.labels.iter().filter(|&(_, n)| *n >= num_nodes) {
+			problems.push(format!("label '{}' refers to node {}, which doesn't exist", label, node));
+		}
+
+		if !problems.is_empty() {
+			bail!("Invalid synth {:?}:\n\t{}", self.id, problems.join("\n\t"));
+		}
+
+		Ok(())
+	}
+
+	// Takes over the id of another synth, so that ParameterIDs from that synth refer to this one
+	pub(crate) fn adopt_id(&mut self, id: SynthID) {
+		let old_id = self.id;
+		self.id = id;
+
+		let rewrite = |param: &mut ParameterID| if param.owner == old_id { param.owner = id; };
+
+		for node in self.instructions.iter_mut() {
+			node.visit_inputs_mut(|input| if let Input::Parameter(param) = input { rewrite(param) });
+
+			if let Node::ParameterSampler(sampler) = node {
+				rewrite(sampler.parameter_mut());
+			}
+		}
+	}
+
+	// The node of old that node idx carries state over from. Labelled nodes are matched by label,
+	// and unlabelled nodes match unlabelled nodes at the same position
+	fn matching_node(&self, old: &Synth, idx: usize) -> Option<usize> {
+		match self.label_of(idx) {
+			Some(label) => old.get_labelled_node(label).map(|NodeID(n)| n as usize),
+			None if idx < old.instructions.len() && old.label_of(idx).is_none() => Some(idx),
+			None => None,
+		}
+	}
+
+	// Carries over oscillator phases, filter memory, envelope positions, stores and parameters
+	pub(crate) fn transfer_state_from(&mut self, old: &Synth) {
+		for idx in 0..self.instructions.len() {
+			if let Some(old_idx) = self.matching_node(old, idx) {
+				self.instructions[idx].transfer_state(&old.instructions[old_idx]);
+			}
+		}
+
+		for (value, old_value) in self.value_store.iter_mut().zip(old.value_store.iter()) {
+			*value = *old_value;
+		}
+
+		for (param, old_param) in self.parameters.iter_mut().zip(old.parameters.iter()) {
+			param.transfer_state(old_param);
+		}
+	}
 
+	// Carries over one-shot timing and a release in progress. Envelope releases move to the matching
+	// envelope. Fade releases go on from the level the old synth had reached, as do envelope releases
+	// without a matching envelope, which fade out over crossfade_time instead.
+	// Returns whether the synth was left fading out, in which case it shouldn't be crossfaded in
+	pub(crate) fn transfer_release_from(&mut self, old: &Synth, crossfade_time: f32, sample_rate: f32) -> bool {
+		if self.one_shot.is_none() {
+			self.one_shot = old.one_shot;
+		}
+
+		self.silent_time = old.silent_time;
+
+		let release = match old.release {
+			Some(release) => release,
+			None => return false,
+		};
+
+		if let Release::Envelope(NodeID(node)) = release {
+			let envelope = (0..self.instructions.len())
+				.find(|&idx| self.matching_node(old, idx) == Some(node as usize))
+				.filter(|&idx| matches!(self.instructions[idx], Node::EnvAR(_) | Node::EnvADSR(_)));
+
+			if let Some(idx) = envelope {
+				self.release = Some(Release::Envelope(NodeID(idx as u32)));
+				return false
+			}
+		}
+
+		self.fade_level = old.fade_level;
+		self.fade_step = old.fade_step;
+
+		match release {
+			Release::Fade(_) => self.release = Some(release),
+			Release::Envelope(_) => self.start_release(Release::Fade(crossfade_time), sample_rate),
+		}
+
+		true
+	}
+
+	pub(crate) fn set_fade_level(&mut self, level: f32) {
+		self.fade_level = level;
+		self.fade_step = 0.0;
+	}
+
+	// Fades gain to level over the given number of frames
+	pub(crate) fn start_fade(&mut self, level: f32, frames: f32) {
+		if frames < 1.0 {
+			self.fade_level = level;
+			self.fade_step = 0.0;
+		} else {
+			self.fade_step = (level - self.fade_level) / frames;
+		}
+	}
+
+	// Keeps a fade in progress the same length in seconds when the sample rate changes
+	pub(crate) fn rescale_fade(&mut self, old_sample_rate: f32, new_sample_rate: f32) {
+		self.fade_step *= old_sample_rate / new_sample_rate;
+	}
+
+	// Removes the synth automatically once its output stays below threshold for the given time in seconds,
+	// so fire and forget sounds don't accumulate
+	pub fn set_one_shot(&mut self, threshold: f32, time: f32) {
+		self.one_shot = Some((threshold, time));
+	}
+
+	// Makes evaluation panic, for testing how contexts recover
+	#[doc(hidden)]
+	pub fn set_panics_on_evaluation(&mut self) {
+		self.panics = true;
+	}
+
+	pub(crate) fn start_release(&mut self, release: Release, sample_rate: f32) {
+		if let Release::Fade(time) = release {
+			self.start_fade(0.0, time * sample_rate);
+		}
+
+		self.release = Some(release);
+	}
+
+	// Released synths that have finished, and one-shot synths that have gone silent
+	pub(crate) fn is_finished(&self) -> bool {
+		let released = match self.release {
+			Some(Release::Fade(_)) => self.is_faded_out(),
+			Some(Release::Envelope(NodeID(node))) => match self.instructions.get(node as usize) {
+				Some(Node::EnvAR(env)) => env.is_silent(),
+				Some(Node::EnvADSR(env)) => env.is_silent(),
+				_ => true,
+			}
+			None => false,
+		};
+
+		let expired = match self.one_shot {
+			Some((_, time)) => self.silent_time >= time,
+			None => false,
+		};
+
+		released || expired
+	}
+
+	fn track_silence(&mut self, level: f32, dt: f32) {
+		if let Some((threshold, _)) = self.one_shot {
+			if level < threshold {
+				self.silent_time += dt;
+			} else {
+				self.silent_time = 0.0;
+			}
+		}
+	}
+
+	pub(crate) fn is_faded_out(&self) -> bool {
+		self.fade_level <= 0.0 && self.fade_step <= 0.0
+	}
+
+	fn advance_fade(&mut self) -> f32 {
+		if self.fade_step != 0.0 {
+			self.fade_level = (self.fade_level + self.fade_step).clamp(0.0, 1.0);
+
+			if self.fade_level <= 0.0 || self.fade_level >= 1.0 {
+				self.fade_step = 0.0;
+			}
+		}
+
+		self.fade_level
+	}
+
+	// Mono synths are duplicated to every channel, and multichannel synths are averaged into mono buffers.
+	// Otherwise channels map one to one, and channels the synth doesn't have are left silent.
+	// The buffer is tagged with the rate it was rendered at, so it keeps its pitch when sampled at other rates
 	pub fn evaluate_into_buffer(&mut self, buffer: &mut Buffer, eval_ctx: &mut EvaluationContext) {
+		self.evaluate_into_slice(&mut buffer.data, buffer.channels, eval_ctx);
+		buffer.sample_rate = Some(eval_ctx.sample_rate);
+	}
+
+	// Accumulates into interleaved samples with buffer_channels channels
+	pub fn evaluate_into_slice(&mut self, data: &mut [f32], buffer_channels: usize, eval_ctx: &mut EvaluationContext) {
+		self.evaluate_frames(data, buffer_channels, eval_ctx);
+	}
+
+	// Also adds the time taken to this synth's stats. Only used for whole buffers or segments of them,
+	// since timing every frame would cost more than some synths take to evaluate
+	pub(crate) fn evaluate_into_slice_timed(&mut self, data: &mut [f32], buffer_channels: usize, eval_ctx: &mut EvaluationContext) {
+		let begin = time::Instant::now();
+		self.evaluate_frames(data, buffer_channels, eval_ctx);
+		self.add_eval_time(begin.elapsed());
+	}
+
+	pub(crate) fn add_eval_time(&mut self, time: time::Duration) {
+		self.eval_time += time.as_secs_f32() * 1000000.0;
+	}
+
+	pub(crate) fn take_eval_time(&mut self) -> f32 {
+		std::mem::replace(&mut self.eval_time, 0.0)
+	}
+
+	fn evaluate_frames(&mut self, data: &mut [f32], buffer_channels: usize, eval_ctx: &mut EvaluationContext) {
+		if self.panics {
+			panic!("Synth {} was set to panic", self.id.0);
+		}
+
 		if eval_ctx.sample_arena.len() < self.instructions.len() {
 			eval_ctx.sample_arena.resize(self.instructions.len(), 0.0);
 		}
 
-		for s in buffer.data.iter_mut() {
-			let value = self.evaluate_sample(eval_ctx) * self.gain;
-			*s += value;
+		if self.evaluation_mode == EvaluationMode::Block && !self.instructions.is_empty() {
+			self.evaluate_blocks_into_slice(data, buffer_channels, eval_ctx);
+			return
+		}
+
+		for frame in data.chunks_mut(buffer_channels) {
+			self.evaluate_sample(eval_ctx);
+
+			if self.instructions.is_empty() { continue }
+
+			let gain = self.gain * self.advance_fade();
+			let arena = &eval_ctx.sample_arena;
+			let level = self.accumulate_frame(frame, gain, |node| arena[node]);
+			self.track_silence(level, eval_ctx.sample_dt);
+		}
+	}
+
+	// Returns the largest magnitude added to any channel
+	fn accumulate_frame<F: Fn(usize) -> f32>(&self, frame: &mut [f32], gain: f32, node_value: F) -> f32 {
+		match (self.output_nodes.len(), frame.len()) {
+			(0, _) => {
+				let value = node_value(self.instructions.len() - 1) * gain;
+				for s in frame.iter_mut() { *s += value; }
+				value.abs()
+			}
+
+			(1, _) => {
+				let value = node_value(self.output_nodes[0]) * gain;
+				for s in frame.iter_mut() { *s += value; }
+				value.abs()
+			}
+
+			(n, 1) => {
+				let sum: f32 = self.output_nodes.iter().map(|&o| node_value(o)).sum();
+				let value = sum * gain / n as f32;
+				frame[0] += value;
+				value.abs()
+			}
+
+			_ => frame.iter_mut().zip(self.output_nodes.iter()).fold(0.0f32, |level, (s, &o)| {
+				let value = node_value(o) * gain;
+				*s += value;
+				level.max(value.abs())
+			}),
+		}
+	}
+
+	fn evaluate_blocks_into_slice(&mut self, data: &mut [f32], buffer_channels: usize, eval_ctx: &mut EvaluationContext) {
+		if self.block_plan.is_none() {
+			self.block_plan = Some(BlockPlan::new(self));
+		}
+
+		// Taken out of the context so spans can still evaluate against it
+		let mut block_arena = std::mem::take(&mut eval_ctx.block_arena);
+		let mut param_arena = std::mem::take(&mut eval_ctx.param_arena);
+
+		block_arena.resize(self.instructions.len() * BLOCK_SIZE, 0.0);
+		param_arena.resize(self.parameters.len() * BLOCK_SIZE, 0.0);
+
+		for block in data.chunks_mut(BLOCK_SIZE * buffer_channels) {
+			let block_len = block.len() / buffer_channels;
+			self.evaluate_block(block_len, &mut block_arena, &mut param_arena, eval_ctx);
+
+			for (sample, frame) in block.chunks_mut(buffer_channels).enumerate() {
+				let gain = self.gain * self.advance_fade();
+				let level = self.accumulate_frame(frame, gain, |node| block_arena[node * BLOCK_SIZE + sample]);
+				self.track_silence(level, eval_ctx.sample_dt);
+			}
+		}
+
+		eval_ctx.block_arena = block_arena;
+		eval_ctx.param_arena = param_arena;
+	}
+
+	fn evaluate_block(&mut self, block_len: usize, block_arena: &mut [f32], param_arena: &mut [f32], eval_ctx: &mut EvaluationContext) {
+		let mut plan = self.block_plan.take().expect("Block plan missing");
+
+		for sample in 0..block_len {
+			for (idx, parameter) in self.parameters.iter_mut().enumerate() {
+				parameter.update(eval_ctx);
+				param_arena[idx * BLOCK_SIZE + sample] = parameter.evaluate();
+			}
+		}
+
+		for step in plan.steps.iter() {
+			match step {
+				Step::Kernel{node, inputs} => {
+					let (prior, rest) = block_arena.split_at_mut(node * BLOCK_SIZE);
+					let out = &mut rest[..block_len];
+
+					block::evaluate_kernel(&mut self.instructions[*node], inputs, prior, param_arena,
+						&self.value_store, out, eval_ctx);
+				}
+
+				Step::Span{range, mirror_nodes, mirror_stores} => {
+					// Parameter samplers in the span read targets
+					for (span_param, param) in plan.span_parameters.iter_mut().zip(self.parameters.iter()) {
+						span_param.set_value(param.target());
+					}
+
+					std::mem::swap(&mut self.parameters, &mut plan.span_parameters);
+
+					for sample in 0..block_len {
+						for (idx, parameter) in self.parameters.iter_mut().enumerate() {
+							parameter.set_current(param_arena[idx * BLOCK_SIZE + sample]);
+						}
+
+						for &node in mirror_nodes.iter() {
+							eval_ctx.sample_arena[node] = block_arena[node * BLOCK_SIZE + sample];
+						}
+
+						for &(store, writer) in mirror_stores.iter() {
+							self.value_store[store] = block_arena[writer * BLOCK_SIZE + sample];
+						}
+
+						self.evaluate_instructions(range.clone(), eval_ctx);
+
+						for node in range.clone() {
+							block_arena[node * BLOCK_SIZE + sample] = eval_ctx.sample_arena[node];
+						}
+					}
+
+					std::mem::swap(&mut self.parameters, &mut plan.span_parameters);
+				}
+			}
+		}
+
+		for &(store, writer) in plan.store_writers.iter() {
+			self.value_store[store] = block_arena[writer * BLOCK_SIZE + block_len - 1];
 		}
+
+		self.block_plan = Some(plan);
 	}
 
 	pub fn prewarm(&mut self, num_samples: usize, eval_ctx: &mut EvaluationContext) {
@@ -110,27 +780,46 @@ impl Synth {
 	}
 
 
-	fn evaluate_sample(&mut self, eval_ctx: &mut EvaluationContext) -> f32 {
-		assert!(eval_ctx.sample_arena.len() >= self.instructions.len());
-
+	fn evaluate_sample(&mut self, eval_ctx: &mut EvaluationContext) {
 		for parameter in self.parameters.iter_mut() {
 			parameter.update(eval_ctx);
 		}
 
-		let instructions = &mut self.instructions;
+		self.evaluate_instructions(0..self.instructions.len(), eval_ctx);
+	}
+
+	fn evaluate_instructions(&mut self, range: Range<usize>, eval_ctx: &mut EvaluationContext) {
+		assert!(eval_ctx.sample_arena.len() >= range.end);
 
-		for (idx, inst) in instructions.iter_mut().enumerate() {
+		let instructions = &mut self.instructions[range.clone()];
+
+		for (idx, inst) in (range.start..).zip(instructions.iter_mut()) {
 			let sample = match inst {
 				Node::Sine(phase) => phase.advance(input_context!(self, eval_ctx)).sin(),
-				Node::Saw(phase) => phase.advance(input_context!(self, eval_ctx)) * 2.0 - 1.0,
-				Node::Square(phase) => 1.0 - (phase.advance(input_context!(self, eval_ctx)) + 0.5).floor() * 2.0,
-				Node::Triangle(phase) => {
-					let ph = phase.advance(input_context!(self, eval_ctx));
-					if ph <= 0.5 {
-						(ph - 0.25)*4.0
-					} else {
-						(0.75 - ph)*4.0
-					}
+				Node::Saw(phase) => saw_shape(phase.advance(input_context!(self, eval_ctx))),
+				Node::Square(phase) => square_shape(phase.advance(input_context!(self, eval_ctx))),
+				Node::Triangle(phase) => triangle_shape(phase.advance(input_context!(self, eval_ctx))),
+
+				Node::BandLimitedTriangle(phase) => {
+					let (ph, step) = phase.advance_stepped(input_context!(self, eval_ctx));
+					bl_triangle_shape(ph, step)
+				}
+
+				Node::BandLimitedSquare(phase) => {
+					let (ph, step) = phase.advance_stepped(input_context!(self, eval_ctx));
+					bl_square_shape(ph, step)
+				}
+
+				Node::BandLimitedSaw(phase) => {
+					let (ph, step) = phase.advance_stepped(input_context!(self, eval_ctx));
+					bl_saw_shape(ph, step)
+				}
+
+				Node::Wavetable{phase, position, table} => {
+					let ctx = input_context!(self, eval_ctx);
+					let (ph, step) = phase.advance_stepped(ctx);
+					let position = position.evaluate(ctx);
+					table.sample(sampler_context!(self, eval_ctx), ph, step, position)
 				}
 
 
@@ -138,30 +827,14 @@ impl Synth {
 					let ctx = input_context!(self, eval_ctx);
 					let sample = input.evaluate(ctx);
 					let cutoff = freq.evaluate(ctx);
-
-					if cutoff > 0.0 {
-						let dt = eval_ctx.sample_dt;
-						let a = dt / (dt + 1.0 / (2.0 * PI * cutoff));
-						*prev_result = lerp(*prev_result, sample, a);
-					} else {
-						*prev_result = 0.0;
-					}
-						
-					*prev_result
+					lowpass_step(prev_result, sample, cutoff, eval_ctx.sample_dt)
 				}
 
 				Node::HighPass{input, freq, prev_sample_diff} => {
 					let ctx = input_context!(self, eval_ctx);
 					let sample = input.evaluate(ctx);
 					let cutoff = freq.evaluate(ctx);
-
-					let rc = 1.0 / (2.0 * PI * cutoff);
-					let a = rc / (rc + eval_ctx.sample_dt);
-
-					let result = a * (*prev_sample_diff + sample);
-					*prev_sample_diff = result - sample;
-						
-					result
+					highpass_step(prev_sample_diff, sample, cutoff, eval_ctx.sample_dt)
 				}
 
 				Node::Clamp{input, lb, ub} => {
@@ -174,8 +847,12 @@ impl Synth {
 
 				Node::Remap{input, in_lb, in_ub, out_lb, out_ub} => {
 					let sample = input.evaluate(input_context!(self, eval_ctx));
-					let normalised = (sample - *in_lb) / (*in_ub - *in_lb);
-					normalised * (*out_ub - *out_lb) + *out_lb
+					remap(sample, *in_lb, *in_ub, *out_lb, *out_ub)
+				}
+
+				Node::Pan{input, pan, channel} => {
+					let ctx = input_context!(self, eval_ctx);
+					input.evaluate(ctx) * pan_gain(pan.evaluate(ctx), *channel)
 				}
 
 				Node::Mix{a, b, mix} => {
@@ -204,7 +881,7 @@ impl Synth {
 				}
 
 				Node::ParameterSampler(sampler) => {
-					sampler.sample(&self.parameters)
+					sampler.sample(&self.parameters, eval_ctx.sample_dt)
 				}
 
 				Node::Sequencer{seq, advance, reset} => {
@@ -224,22 +901,14 @@ impl Synth {
 
 				Node::EnvAR(env_ar) => env_ar.advance(input_context!(self, eval_ctx)),
 				Node::EnvADSR(env_adsr) => env_adsr.advance(input_context!(self, eval_ctx)),
+
+				Node::BusInput(channel) => eval_ctx.bus_frame.get(*channel as usize).cloned().unwrap_or(0.0),
 			};
 
 			unsafe {
 				*eval_ctx.sample_arena.get_unchecked_mut(idx) = sample;
 			}
 		}
-
-		if instructions.len() > 0 {
-			let output_node = self.output_node.unwrap_or(instructions.len() - 1);
-
-			unsafe {
-				*eval_ctx.sample_arena.get_unchecked(output_node)
-			}
-		} else {
-			0.0
-		}
 	}
 }
 
@@ -0,0 +1,242 @@
+use crate::SynthResult;
+use crate::context::Context;
+use crate::offline::OfflineContext;
+use crate::synth::{Synth, SynthID};
+use crate::node::NodeID;
+use crate::parameter::ParameterID;
+use crate::envelope::EnvelopeMonitor;
+
+use failure::ensure;
+use std::sync::Arc;
+
+// How a voice is chosen for a new note when none are free
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StealPolicy {
+	// Steal the voice whose note started longest ago
+	Oldest,
+	// Steal the voice whose envelope is lowest
+	Quietest,
+	// Retrigger the voice already playing the same key, otherwise steal the oldest
+	SameNoteRetrigger,
+}
+
+// Describes which parts of a template synth a VoicePool drives
+#[derive(Copy, Clone, Debug)]
+pub struct VoiceConfig {
+	// Set to the frequency of each note in Hz
+	pub pitch: ParameterID,
+	// Set to 1.0 while a key is held and 0.0 once released
+	pub gate: ParameterID,
+	// Set to velocity/127 if given
+	pub velocity: Option<ParameterID>,
+	// An EnvAR or EnvADSR node. A voice is free once this falls silent
+	pub envelope: NodeID,
+
+	pub policy: StealPolicy,
+}
+
+// What a VoicePool needs from a context, so that voices can be played offline as well as in realtime
+pub trait VoiceContext {
+	fn current_time(&self) -> u64;
+	fn push_synth(&mut self, synth: Synth) -> SynthResult<SynthID>;
+	fn set_parameter_at(&self, param_id: ParameterID, value: f32, time: u64);
+	fn retrigger_envelope_at(&self, synth_id: SynthID, envelope: NodeID, time: u64);
+}
+
+impl VoiceContext for Context {
+	fn current_time(&self) -> u64 { Context::current_time(self) }
+	fn push_synth(&mut self, synth: Synth) -> SynthResult<SynthID> { Context::push_synth(self, synth) }
+
+	fn set_parameter_at(&self, param_id: ParameterID, value: f32, time: u64) {
+		Context::set_parameter_at(self, param_id, value, time)
+	}
+
+	fn retrigger_envelope_at(&self, synth_id: SynthID, envelope: NodeID, time: u64) {
+		Context::retrigger_envelope_at(self, synth_id, envelope, time)
+	}
+}
+
+impl VoiceContext for OfflineContext {
+	fn current_time(&self) -> u64 { OfflineContext::current_time(self) }
+	fn push_synth(&mut self, synth: Synth) -> SynthResult<SynthID> { OfflineContext::push_synth(self, synth) }
+
+	fn set_parameter_at(&self, param_id: ParameterID, value: f32, time: u64) {
+		OfflineContext::set_parameter_at(self, param_id, value, time)
+	}
+
+	fn retrigger_envelope_at(&self, synth_id: SynthID, envelope: NodeID, time: u64) {
+		OfflineContext::retrigger_envelope_at(self, synth_id, envelope, time)
+	}
+}
+
+#[derive(Debug)]
+struct Voice {
+	synth_id: SynthID,
+
+	pitch: ParameterID,
+	gate: ParameterID,
+	velocity: Option<ParameterID>,
+	envelope: NodeID,
+	monitor: Arc<EnvelopeMonitor>,
+
+	key: Option<u8>,
+	held: bool,
+	// Note counter at the time the current note started
+	started: u64,
+	// Frame of the last note on. The envelope can't report it as active before then
+	triggered_at: u64,
+}
+
+impl Voice {
+	fn is_sounding(&self, now: u64) -> bool {
+		self.held || now <= self.triggered_at || self.monitor.is_active()
+	}
+}
+
+// Plays notes across a fixed number of copies of a template synth
+#[derive(Debug)]
+pub struct VoicePool {
+	voices: Vec<Voice>,
+	policy: StealPolicy,
+	note_counter: u64,
+}
+
+impl VoicePool {
+	// Pushes voice_count duplicates of template to the context. The template itself is left untouched
+	pub fn new<C: VoiceContext>(ctx: &mut C, template: &Synth, voice_count: usize, config: VoiceConfig) -> SynthResult<Self> {
+		ensure!(voice_count > 0, "VoicePool needs at least one voice");
+
+		let owned_by_template = |param: ParameterID| param.owner == template.id;
+		ensure!(owned_by_template(config.pitch) && owned_by_template(config.gate)
+			&& config.velocity.is_none_or(owned_by_template),
+			"VoicePool parameters must belong to the template synth");
+
+		let mut voices = Vec::with_capacity(voice_count);
+
+		for _ in 0..voice_count {
+			let mut synth = template.duplicate();
+			let monitor = synth.monitor_envelope(config.envelope)?;
+
+			// Set before pushing, since events for a synth the context hasn't seen yet are dropped
+			let gate = synth.parameter_of_duplicate(config.gate);
+			synth.get_parameter(gate).set_value(0.0);
+
+			voices.push(Voice {
+				synth_id: synth.id,
+
+				pitch: synth.parameter_of_duplicate(config.pitch),
+				gate,
+				velocity: config.velocity.map(|v| synth.parameter_of_duplicate(v)),
+				envelope: config.envelope,
+				monitor,
+
+				key: None,
+				held: false,
+				started: 0,
+				triggered_at: 0,
+			});
+
+			ctx.push_synth(synth)?;
+		}
+
+		Ok(VoicePool {
+			voices,
+			policy: config.policy,
+			note_counter: 0,
+		})
+	}
+
+	pub fn set_policy(&mut self, policy: StealPolicy) { self.policy = policy }
+	pub fn policy(&self) -> StealPolicy { self.policy }
+
+	pub fn voice_count(&self) -> usize { self.voices.len() }
+
+	pub fn synth_ids(&self) -> impl Iterator<Item=SynthID> + '_ {
+		self.voices.iter().map(|v| v.synth_id)
+	}
+
+	// Voices that are held or still releasing
+	pub fn active_voices<C: VoiceContext>(&self, ctx: &C) -> usize {
+		let now = ctx.current_time();
+		self.voices.iter().filter(|v| v.is_sounding(now)).count()
+	}
+
+	// Keys currently held down, in no particular order
+	pub fn held_keys(&self) -> impl Iterator<Item=u8> + '_ {
+		self.voices.iter().filter(|v| v.held).filter_map(|v| v.key)
+	}
+
+	pub fn note_on<C: VoiceContext>(&mut self, ctx: &C, key: u8, velocity: u8) {
+		let start = ctx.current_time();
+		let idx = self.allocate(key, start);
+
+		self.note_counter += 1;
+		let voice = &mut self.voices[idx];
+
+		let freq = 440.0 * 2.0f32.powf((key as f32 - 69.0) / 12.0);
+		ctx.set_parameter_at(voice.pitch, freq, start);
+
+		if let Some(param) = voice.velocity {
+			ctx.set_parameter_at(param, velocity as f32 / 127.0, start);
+		}
+
+		ctx.set_parameter_at(voice.gate, 1.0, start);
+
+		// Stolen and retriggered voices may still have their gate high, so the envelope is restarted
+		// explicitly rather than relying on the gate falling and rising again between events
+		ctx.retrigger_envelope_at(voice.synth_id, voice.envelope, start);
+
+		voice.key = Some(key);
+		voice.held = true;
+		voice.started = self.note_counter;
+		voice.triggered_at = start;
+	}
+
+	// Timed like note_on, so a note released before the next buffer isn't reordered before its start
+	pub fn note_off<C: VoiceContext>(&mut self, ctx: &C, key: u8) {
+		let now = ctx.current_time();
+
+		for voice in self.voices.iter_mut().filter(|v| v.held && v.key == Some(key)) {
+			ctx.set_parameter_at(voice.gate, 0.0, now);
+			voice.held = false;
+		}
+	}
+
+	pub fn all_notes_off<C: VoiceContext>(&mut self, ctx: &C) {
+		let now = ctx.current_time();
+
+		for voice in self.voices.iter_mut().filter(|v| v.held) {
+			ctx.set_parameter_at(voice.gate, 0.0, now);
+			voice.held = false;
+		}
+	}
+
+	fn allocate(&self, key: u8, now: u64) -> usize {
+		if self.policy == StealPolicy::SameNoteRetrigger {
+			let same_note = self.voices.iter()
+				.position(|v| v.key == Some(key) && v.is_sounding(now));
+
+			if let Some(idx) = same_note {
+				return idx
+			}
+		}
+
+		if let Some(idx) = self.voices.iter().position(|v| !v.is_sounding(now)) {
+			return idx
+		}
+
+		// Released voices are always stolen before held ones
+		let candidates = self.voices.iter().enumerate();
+
+		match self.policy {
+			StealPolicy::Oldest | StealPolicy::SameNoteRetrigger => candidates
+				.min_by_key(|(_, v)| (v.held, v.started))
+				.map(|(idx, _)| idx),
+
+			StealPolicy::Quietest => candidates
+				.min_by(|(_, a), (_, b)| (a.held, a.monitor.level()).partial_cmp(&(b.held, b.monitor.level()))
+					.unwrap_or(std::cmp::Ordering::Equal))
+				.map(|(idx, _)| idx),
+		}.unwrap()
+	}
+}
@@ -0,0 +1,456 @@
+use std::fs::File;
+use std::io::{Write, Seek, SeekFrom, BufWriter};
+use std::path::Path;
+use std::sync::mpsc::{SyncSender, Receiver, TrySendError, sync_channel};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+
+use crate::SynthResult;
+use crate::buffer::Buffer;
+use crate::loader::{AudioData, Endianness, decode_pcm, decode_float};
+
+use failure::{err_msg, bail, ensure};
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SampleFormat {
+	Int16,
+	Int24,
+	Float32,
+}
+
+impl SampleFormat {
+	fn bytes_per_sample(self) -> u16 {
+		match self {
+			SampleFormat::Int16 => 2,
+			SampleFormat::Int24 => 3,
+			SampleFormat::Float32 => 4,
+		}
+	}
+
+	fn format_tag(self) -> u16 {
+		match self {
+			SampleFormat::Float32 => WAVE_FORMAT_IEEE_FLOAT,
+			_ => WAVE_FORMAT_PCM,
+		}
+	}
+
+	fn is_float(self) -> bool { self.format_tag() == WAVE_FORMAT_IEEE_FLOAT }
+}
+
+
+// Writes interleaved samples to a RIFF/WAVE stream.
+// Chunk sizes are patched on finalize, or on drop if finalize was never called
+pub struct WavWriter<W: Write + Seek> {
+	writer: Option<W>,
+
+	format: SampleFormat,
+	channels: u16,
+	sample_rate: u32,
+
+	data_bytes: u32,
+	fact_offset: Option<u64>,
+	data_offset: u64,
+}
+
+impl WavWriter<BufWriter<File>> {
+	pub fn create<P: AsRef<Path>>(path: P, format: SampleFormat, sample_rate: u32, channels: u16) -> SynthResult<Self> {
+		let file = File::create(path)?;
+		WavWriter::new(BufWriter::new(file), format, sample_rate, channels)
+	}
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+	pub fn new(mut writer: W, format: SampleFormat, sample_rate: u32, channels: u16) -> SynthResult<Self> {
+		ensure!(channels > 0, "Wav files must have at least one channel");
+		ensure!(sample_rate > 0, "Wav files must have a non-zero sample rate");
+
+		let bytes_per_sample = format.bytes_per_sample();
+		let block_align = bytes_per_sample * channels;
+
+		writer.write_all(b"RIFF")?;
+		writer.write_all(&0u32.to_le_bytes())?;
+		writer.write_all(b"WAVE")?;
+
+		// Non-PCM formats require the extension size field and a fact chunk
+		let fmt_size: u32 = if format.is_float() { 18 } else { 16 };
+
+		writer.write_all(b"fmt ")?;
+		writer.write_all(&fmt_size.to_le_bytes())?;
+		writer.write_all(&format.format_tag().to_le_bytes())?;
+		writer.write_all(&channels.to_le_bytes())?;
+		writer.write_all(&sample_rate.to_le_bytes())?;
+		writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+		writer.write_all(&block_align.to_le_bytes())?;
+		writer.write_all(&(bytes_per_sample * 8).to_le_bytes())?;
+
+		let fact_offset = if format.is_float() {
+			writer.write_all(&0u16.to_le_bytes())?;
+
+			writer.write_all(b"fact")?;
+			writer.write_all(&4u32.to_le_bytes())?;
+			let offset = writer.stream_position()?;
+			writer.write_all(&0u32.to_le_bytes())?;
+			Some(offset)
+		} else {
+			None
+		};
+
+		writer.write_all(b"data")?;
+		let data_offset = writer.stream_position()?;
+		writer.write_all(&0u32.to_le_bytes())?;
+
+		Ok(WavWriter {
+			writer: Some(writer),
+
+			format,
+			channels,
+			sample_rate,
+
+			data_bytes: 0,
+			fact_offset,
+			data_offset,
+		})
+	}
+
+	pub fn format(&self) -> SampleFormat { self.format }
+	pub fn channels(&self) -> u16 { self.channels }
+	pub fn sample_rate(&self) -> u32 { self.sample_rate }
+
+	pub fn frames_written(&self) -> u32 {
+		self.data_bytes / (self.format.bytes_per_sample() * self.channels) as u32
+	}
+
+	// Samples are expected to be interleaved if the writer has more than one channel
+	pub fn write_samples(&mut self, samples: &[f32]) -> SynthResult<()> {
+		let format = self.format;
+		let writer = self.writer.as_mut()
+			.ok_or_else(|| err_msg("Tried to write to finalized wav writer"))?;
+
+		// Checked up front, so the header always matches what has been written
+		let new_bytes = samples.len() as u64 * format.bytes_per_sample() as u64;
+		ensure!(self.data_bytes as u64 + new_bytes < u32::MAX as u64, "Wav file exceeded 4GiB size limit");
+
+		for &sample in samples.iter() {
+			match format {
+				SampleFormat::Int16 => {
+					let v = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+					writer.write_all(&v.to_le_bytes())?;
+				}
+
+				SampleFormat::Int24 => {
+					let v = (sample.clamp(-1.0, 1.0) * 8388607.0).round() as i32;
+					writer.write_all(&v.to_le_bytes()[..3])?;
+				}
+
+				SampleFormat::Float32 => {
+					writer.write_all(&sample.to_le_bytes())?;
+				}
+			}
+		}
+
+		self.data_bytes += new_bytes as u32;
+
+		Ok(())
+	}
+
+	pub fn write_buffer(&mut self, buffer: &Buffer) -> SynthResult<()> {
+		ensure!(buffer.channels == self.channels as usize,
+			"Tried to write buffer with {} channels to wav file with {} channels", buffer.channels, self.channels);
+
+		self.write_samples(&buffer.data)
+	}
+
+	pub fn finalize(mut self) -> SynthResult<W> {
+		self.update_header()?;
+		Ok(self.writer.take().unwrap())
+	}
+
+	fn update_header(&mut self) -> SynthResult<()> {
+		let frames = self.frames_written();
+		let data_bytes = self.data_bytes;
+
+		let writer = match self.writer.as_mut() {
+			Some(w) => w,
+			None => return Ok(()),
+		};
+
+		// Chunks must be word aligned
+		if data_bytes & 1 == 1 {
+			writer.write_all(&[0])?;
+		}
+
+		let end = writer.stream_position()?;
+
+		writer.seek(SeekFrom::Start(4))?;
+		writer.write_all(&((end - 8) as u32).to_le_bytes())?;
+
+		if let Some(fact_offset) = self.fact_offset {
+			writer.seek(SeekFrom::Start(fact_offset))?;
+			writer.write_all(&frames.to_le_bytes())?;
+		}
+
+		writer.seek(SeekFrom::Start(self.data_offset))?;
+		writer.write_all(&data_bytes.to_le_bytes())?;
+
+		writer.seek(SeekFrom::Start(end))?;
+		writer.flush()?;
+
+		Ok(())
+	}
+}
+
+impl<W: Write + Seek> Drop for WavWriter<W> {
+	fn drop(&mut self) {
+		let _ = self.update_header();
+	}
+}
+
+
+
+// Samples are handed to the writer thread in buffers of up to this many samples,
+// which it sends back to be reused once they're written
+const SINK_BUFFER_SIZE: usize = 4096;
+const SINK_POOL_SIZE: usize = 32;
+
+enum SinkMessage {
+	Samples(Vec<f32>),
+	// Attached contexts keep the channel open, so the writer thread is told when to stop
+	Finish,
+}
+
+// Records everything pushed to it to a wav file on a separate thread,
+// so that the evaluation thread never waits on file io
+pub struct WavSink {
+	sample_tx: SyncSender<SinkMessage>,
+	writer_thread: JoinHandle<SynthResult<()>>,
+
+	channels: u16,
+	sample_rate: u32,
+
+	pool_tx: SyncSender<Vec<f32>>,
+	pool: Arc<Mutex<Receiver<Vec<f32>>>>,
+	dropped_samples: Arc<AtomicU64>,
+}
+
+impl WavSink {
+	pub fn create<P: AsRef<Path>>(path: P, format: SampleFormat, sample_rate: u32, channels: u16) -> SynthResult<Self> {
+		let mut writer = WavWriter::create(path, format, sample_rate, channels)?;
+		let (sample_tx, sample_rx) = sync_channel::<SinkMessage>(SINK_POOL_SIZE * 2);
+		let (pool_tx, pool_rx) = sync_channel::<Vec<f32>>(SINK_POOL_SIZE);
+
+		for _ in 0..SINK_POOL_SIZE {
+			pool_tx.send(Vec::with_capacity(SINK_BUFFER_SIZE))?;
+		}
+
+		let writer_pool_tx = pool_tx.clone();
+
+		let writer_thread = spawn(move || {
+			for message in sample_rx.iter() {
+				let samples = match message {
+					SinkMessage::Samples(samples) => samples,
+					SinkMessage::Finish => break,
+				};
+
+				writer.write_samples(&samples)?;
+
+				// Anything too small to reuse without reallocating is dropped, as are buffers once the pool is full
+				if samples.capacity() >= SINK_BUFFER_SIZE {
+					let _ = writer_pool_tx.try_send(samples);
+				}
+			}
+
+			writer.finalize()?;
+			Ok(())
+		});
+
+		Ok(WavSink {
+			sample_tx,
+			writer_thread,
+
+			channels,
+			sample_rate,
+
+			pool_tx,
+			pool: Arc::new(Mutex::new(pool_rx)),
+			dropped_samples: Arc::new(AtomicU64::new(0)),
+		})
+	}
+
+	pub fn channels(&self) -> u16 { self.channels }
+	pub fn sample_rate(&self) -> u32 { self.sample_rate }
+
+	// Contexts only accept sinks with their own channel count and sample rate
+	pub(crate) fn check_format(&self, channels: usize, sample_rate: f32) -> SynthResult<()> {
+		ensure!(self.channels as usize == channels,
+			"Tried to attach sink with {} channels to context with {} channels", self.channels, channels);
+		ensure!(self.sample_rate as f32 == sample_rate,
+			"Tried to attach sink at {}Hz to context at {}Hz", self.sample_rate, sample_rate);
+
+		Ok(())
+	}
+
+	// For attaching to a context. Offline contexts can afford to allocate and wait on the writer,
+	// but evaluation threads only use pooled buffers, and drop samples when there are none free
+	pub(crate) fn handle(&self, blocking: bool) -> SinkHandle {
+		SinkHandle {
+			sample_tx: self.sample_tx.clone(),
+			pool_tx: self.pool_tx.clone(),
+			pool: self.pool.clone(),
+			dropped_samples: self.dropped_samples.clone(),
+			blocking,
+		}
+	}
+
+	pub fn push_samples(&self, samples: &[f32]) {
+		// If the writer thread has failed the error is reported by finish
+		let _ = self.sample_tx.send(SinkMessage::Samples(samples.to_vec()));
+	}
+
+	// Samples an attached context couldn't record because the writer thread fell behind
+	pub fn dropped_samples(&self) -> u64 {
+		self.dropped_samples.load(Ordering::Relaxed)
+	}
+
+	// Waits for everything pushed so far to be written and the file to be finalized.
+	// Doesn't wait for contexts to detach, so anything they render after this isn't recorded
+	pub fn finish(self) -> SynthResult<()> {
+		let WavSink { sample_tx, writer_thread, .. } = self;
+
+		// Only fails if the writer thread has already stopped, which join reports
+		let _ = sample_tx.send(SinkMessage::Finish);
+
+		writer_thread.join()
+			.map_err(|_| err_msg("Wav writer thread panicked"))?
+	}
+}
+
+// A context's end of a WavSink. Samples are copied into buffers recycled by the writer thread,
+// so recording doesn't allocate on the evaluation thread
+pub(crate) struct SinkHandle {
+	sample_tx: SyncSender<SinkMessage>,
+	pool_tx: SyncSender<Vec<f32>>,
+	pool: Arc<Mutex<Receiver<Vec<f32>>>>,
+	dropped_samples: Arc<AtomicU64>,
+	blocking: bool,
+}
+
+impl SinkHandle {
+	pub(crate) fn push(&self, samples: &[f32]) {
+		if self.blocking {
+			// If the writer thread has failed the error is reported by finish
+			let _ = self.sample_tx.send(SinkMessage::Samples(samples.to_vec()));
+			return
+		}
+
+		// Only contended if the sink is attached to more than one context
+		let pool = match self.pool.try_lock() {
+			Ok(pool) => pool,
+			Err(_) => return self.drop_samples(samples.len()),
+		};
+
+		for chunk in samples.chunks(SINK_BUFFER_SIZE) {
+			let mut buffer = match pool.try_recv() {
+				Ok(buffer) => buffer,
+				Err(_) => {
+					self.drop_samples(chunk.len());
+					continue
+				}
+			};
+
+			buffer.clear();
+			buffer.extend_from_slice(chunk);
+
+			// Only fails once the writer thread has stopped, in which case the buffer goes back
+			// to the pool rather than being freed here
+			if let Err(TrySendError::Full(SinkMessage::Samples(buffer))) | Err(TrySendError::Disconnected(SinkMessage::Samples(buffer)))
+				= self.sample_tx.try_send(SinkMessage::Samples(buffer))
+			{
+				let _ = self.pool_tx.try_send(buffer);
+				self.drop_samples(chunk.len());
+			}
+		}
+	}
+
+	fn drop_samples(&self, count: usize) {
+		self.dropped_samples.fetch_add(count as u64, Ordering::Relaxed);
+	}
+}
+
+
+
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+	u16::from_le_bytes([bytes[offset], bytes[offset+1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+	u32::from_le_bytes([bytes[offset], bytes[offset+1], bytes[offset+2], bytes[offset+3]])
+}
+
+// Decodes the contents of a RIFF/WAVE file. Supports 8/16/24/32 bit pcm and 32/64 bit float
+pub(crate) fn decode_wav(bytes: &[u8]) -> SynthResult<AudioData> {
+	ensure!(bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WAVE", "Not a wav file");
+
+	let mut format = None;
+	let mut data = None;
+
+	let mut offset = 12;
+	while offset + 8 <= bytes.len() {
+		let chunk_id = &bytes[offset..offset+4];
+		let chunk_size = read_u32(bytes, offset+4) as usize;
+		let body_start = offset + 8;
+		let body_end = (body_start + chunk_size).min(bytes.len());
+		let body = &bytes[body_start..body_end];
+
+		match chunk_id {
+			b"fmt " => {
+				ensure!(body.len() >= 16, "Wav fmt chunk is truncated");
+
+				let mut format_tag = read_u16(body, 0);
+				let channels = read_u16(body, 2);
+				let sample_rate = read_u32(body, 4);
+				let bits = read_u16(body, 14);
+
+				if format_tag == WAVE_FORMAT_EXTENSIBLE {
+					ensure!(body.len() >= 26, "Wav extensible fmt chunk is truncated");
+					// First two bytes of the subformat guid are the actual format tag
+					format_tag = read_u16(body, 24);
+				}
+
+				format = Some((format_tag, channels, sample_rate, bits));
+			}
+
+			b"data" => data = Some(body),
+			_ => {}
+		}
+
+		// Chunks are word aligned
+		offset = body_start + chunk_size + (chunk_size & 1);
+	}
+
+	let (format_tag, channels, sample_rate, bits) = format.ok_or_else(|| err_msg("Wav file has no fmt chunk"))?;
+	let data = data.ok_or_else(|| err_msg("Wav file has no data chunk"))?;
+
+	ensure!(channels > 0, "Wav file has no channels");
+	ensure!(sample_rate > 0, "Wav file has a sample rate of zero");
+
+	let mut samples = match format_tag {
+		WAVE_FORMAT_PCM => decode_pcm(data, bits, Endianness::Little, true)?,
+		WAVE_FORMAT_IEEE_FLOAT => decode_float(data, bits, Endianness::Little)?,
+		_ => bail!("Unsupported wav encoding: format tag {:#06x}", format_tag),
+	};
+
+	let channels = channels as usize;
+	samples.truncate(samples.len() / channels * channels);
+
+	Ok(AudioData {
+		sample_rate: sample_rate as f32,
+		channels,
+		samples,
+	})
+}
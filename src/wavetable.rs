@@ -0,0 +1,239 @@
+use crate::synth::Synth;
+use crate::buffer::{Buffer, BufferID, SamplerContext};
+use crate::offline::OfflineContext;
+use crate::master::MasterConfig;
+use crate::SynthResult;
+
+use crate::lerp;
+
+use failure::ensure;
+
+use std::f64::consts::PI;
+
+// Frames this size only have room for the fundamental, so levels stop at the harmonics they have
+const MIN_FRAME_SIZE: usize = 4;
+
+// Levels are stored with this many times more samples than their harmonics need, up to the frame size,
+// so that interpolating between samples doesn't add much of its own aliasing
+const OVERSAMPLING: usize = 4;
+
+// Single cycle waveforms, one per frame, along with a copy for each octave up with half the
+// harmonics. Oscillators read the copy with the most harmonics that all stay below nyquist
+// at the pitch they are playing, so high notes don't alias
+#[derive(Clone, Debug)]
+pub struct Wavetable {
+	frame_size: usize,
+	frames: usize,
+	// Every frame of the first level, then every frame of the next level, and so on
+	data: Vec<f32>,
+}
+
+// Where a wavetable is stored, and how it is laid out
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct WavetableID {
+	pub(crate) buffer: BufferID,
+	pub(crate) frame_size: u32,
+	pub(crate) frames: u32,
+}
+
+fn check_layout(frame_size: usize, frames: usize) -> SynthResult<()> {
+	ensure!(frame_size.is_power_of_two() && frame_size >= MIN_FRAME_SIZE,
+		"Wavetable frame size must be a power of two, and at least {}, not {}", MIN_FRAME_SIZE, frame_size);
+	ensure!(frames > 0, "Wavetables need at least one frame");
+	Ok(())
+}
+
+// Each level has half the harmonics of the one before. Level 0 has every harmonic below
+// nyquist of the frame, leaving out nyquist itself, which can't be told apart from its alias
+fn level_harmonics(frame_size: usize, level: usize) -> usize {
+	(frame_size >> (level + 1)) - 1
+}
+
+fn num_levels(frame_size: usize) -> usize {
+	(frame_size / MIN_FRAME_SIZE).trailing_zeros() as usize + 1
+}
+
+fn level_size(frame_size: usize, level: usize) -> usize {
+	(OVERSAMPLING * (frame_size >> level)).min(frame_size)
+}
+
+fn level_offset(frame_size: usize, frames: usize, level: usize) -> usize {
+	(0..level).map(|l| frames * level_size(frame_size, l)).sum()
+}
+
+fn table_len(frame_size: usize, frames: usize) -> usize {
+	level_offset(frame_size, frames, num_levels(frame_size))
+}
+
+impl Wavetable {
+	// Frames are laid out one after another in a mono buffer
+	pub fn from_buffer(buffer: &Buffer, frame_size: usize) -> SynthResult<Self> {
+		ensure!(buffer.channels == 1, "Wavetables are built from mono buffers, not {} channels", buffer.channels);
+		ensure!(buffer.len().is_multiple_of(frame_size.max(1)),
+			"Buffer of {} samples doesn't divide into frames of {}", buffer.len(), frame_size);
+
+		let frames = buffer.len() / frame_size.max(1);
+		check_layout(frame_size, frames)?;
+
+		let levels = num_levels(frame_size);
+		let mut data = vec![0.0; table_len(frame_size, frames)];
+
+		for (frame, samples) in buffer.data.chunks(frame_size).enumerate() {
+			let mut re: Vec<f64> = samples.iter().map(|&s| s as f64).collect();
+			let mut im = vec![0.0; frame_size];
+			fft(&mut re, &mut im, false);
+
+			for level in 0..levels {
+				let size = level_size(frame_size, level);
+				let scale = size as f64 / frame_size as f64;
+
+				let mut level_re = vec![0.0; size];
+				let mut level_im = vec![0.0; size];
+				level_re[0] = re[0] * scale;
+
+				for harmonic in 1..=level_harmonics(frame_size, level) {
+					level_re[harmonic] = re[harmonic] * scale;
+					level_im[harmonic] = im[harmonic] * scale;
+					level_re[size - harmonic] = re[frame_size - harmonic] * scale;
+					level_im[size - harmonic] = im[frame_size - harmonic] * scale;
+				}
+
+				fft(&mut level_re, &mut level_im, true);
+
+				let start = level_offset(frame_size, frames, level) + frame * size;
+				for (out, &s) in data[start .. start + size].iter_mut().zip(level_re.iter()) {
+					*out = s as f32;
+				}
+			}
+		}
+
+		Ok(Wavetable { frame_size, frames, data })
+	}
+
+	// Renders frames one after another from a synth, at a sample rate of frame_size, so that an
+	// oscillator at 1Hz draws exactly one cycle per frame. Anything in the synth that changes over time,
+	// like an lfo or an envelope, morphs the waveform from one frame to the next
+	pub fn bake(synth: Synth, frame_size: usize, frames: usize) -> SynthResult<Self> {
+		check_layout(frame_size, frames)?;
+
+		let mut ctx = OfflineContext::new(frame_size as f32, frame_size);
+		ctx.set_master_config(MasterConfig::bypass());
+		ctx.push_synth(synth)?;
+
+		let buffer = ctx.render_frames(frame_size * frames);
+		Wavetable::from_buffer(&buffer, frame_size)
+	}
+
+	pub fn frame_size(&self) -> usize { self.frame_size }
+	pub fn frames(&self) -> usize { self.frames }
+	pub fn levels(&self) -> usize { num_levels(self.frame_size) }
+
+	pub(crate) fn into_buffer(self) -> Buffer {
+		Buffer::from_interleaved(self.data, 1)
+	}
+}
+
+impl WavetableID {
+	pub(crate) fn new(buffer: BufferID, frame_size: usize, frames: usize) -> SynthResult<Self> {
+		check_layout(frame_size, frames)?;
+		Ok(WavetableID { buffer, frame_size: frame_size as u32, frames: frames as u32 })
+	}
+
+	// Freeing this buffer frees the wavetable
+	pub fn buffer_id(&self) -> BufferID { self.buffer }
+	pub fn frame_size(&self) -> usize { self.frame_size as usize }
+	pub fn frames(&self) -> usize { self.frames as usize }
+
+	// Phase in [0, 1), step is how far it moves per sample, and position in [0, 1] picks
+	// where between the first and last frame to read. Reads silence if the buffer doesn't hold
+	// a table with this layout, like after it's been replaced with something else
+	pub(crate) fn sample(self, ctx: SamplerContext, ph: f32, step: f32, position: f32) -> f32 {
+		let frame_size = self.frame_size as usize;
+		let frames = self.frames as usize;
+
+		let data = match ctx.get_buffer(self.buffer) {
+			Some(buffer) if buffer.data.len() == table_len(frame_size, frames) => &buffer.data,
+			_ => return 0.0,
+		};
+
+		// Harmonics stay below nyquist as long as harmonics * step < 0.5
+		let levels = num_levels(frame_size);
+		let mut level = 0;
+		while level + 1 < levels && level_harmonics(frame_size, level) as f32 * step >= 0.5 {
+			level += 1;
+		}
+
+		let size = level_size(frame_size, level);
+		let start = level_offset(frame_size, frames, level);
+
+		let read_frame = |frame: usize| {
+			let frame = &data[start + frame * size .. start + (frame + 1) * size];
+			let pos = (ph - ph.floor()) * size as f32;
+			let idx = pos as usize;
+			lerp(frame[idx % size], frame[(idx + 1) % size], pos - idx as f32)
+		};
+
+		let position = position.clamp(0.0, 1.0) * (frames - 1) as f32;
+		let frame = position as usize;
+		let next = (frame + 1).min(frames - 1);
+
+		lerp(read_frame(frame), read_frame(next), position - frame as f32)
+	}
+}
+
+// In place radix 2 fft. The inverse is scaled by 1/n, so a round trip gives back the input
+fn fft(re: &mut [f64], im: &mut [f64], inverse: bool) {
+	let n = re.len();
+	debug_assert!(n.is_power_of_two() && im.len() == n);
+
+	// Bit reversed order, counting j up with the carry moving right
+	let mut j = 0;
+	for i in 0..n {
+		if j > i {
+			re.swap(i, j);
+			im.swap(i, j);
+		}
+
+		let mut bit = n >> 1;
+		while j & bit != 0 {
+			j ^= bit;
+			bit >>= 1;
+		}
+		j |= bit;
+	}
+
+	let sign = if inverse { 1.0 } else { -1.0 };
+	let mut len = 2;
+
+	while len <= n {
+		let (sin, cos) = (sign * 2.0 * PI / len as f64).sin_cos();
+
+		for chunk in (0..n).step_by(len) {
+			let (mut w_re, mut w_im) = (1.0, 0.0);
+
+			for k in 0..len/2 {
+				let (a, b) = (chunk + k, chunk + k + len/2);
+				let t_re = re[b] * w_re - im[b] * w_im;
+				let t_im = re[b] * w_im + im[b] * w_re;
+
+				re[b] = re[a] - t_re;
+				im[b] = im[a] - t_im;
+				re[a] += t_re;
+				im[a] += t_im;
+
+				let next_re = w_re * cos - w_im * sin;
+				w_im = w_re * sin + w_im * cos;
+				w_re = next_re;
+			}
+		}
+
+		len *= 2;
+	}
+
+	if inverse {
+		for (r, i) in re.iter_mut().zip(im.iter_mut()) {
+			*r /= n as f64;
+			*i /= n as f64;
+		}
+	}
+}
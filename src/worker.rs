@@ -0,0 +1,201 @@
+use std::sync::mpsc::{Sender, Receiver, SendError, channel};
+use std::sync::Arc;
+use std::thread::{spawn, JoinHandle};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::synth::Synth;
+use crate::buffer::SharedBuffer;
+use crate::context::{EvaluationContext, Garbage, panic_message};
+
+// Sent to a worker with a partition of synths to evaluate, and sent back once they have been
+// evaluated into output. Allocations travel with the job so they can be reused between buffers
+struct WorkerJob {
+	synths: Vec<Synth>,
+	output: Vec<f32>,
+	channels: usize,
+
+	sample_rate: f32,
+	shared_buffers: Arc<Vec<SharedBuffer>>,
+
+	// Set if a synth panicked, after which the worker stops
+	failure: Option<String>,
+}
+
+pub(crate) struct Worker {
+	job_tx: Option<Sender<WorkerJob>>,
+	result_rx: Receiver<WorkerJob>,
+	thread: Option<JoinHandle<()>>,
+
+	// Only None while the worker is busy
+	idle_job: Option<WorkerJob>,
+
+	// Swapped into idle jobs so they don't keep the real shared buffers alive
+	empty_buffers: Arc<Vec<SharedBuffer>>,
+}
+
+impl Worker {
+	fn new() -> Self {
+		let (job_tx, job_rx) = channel::<WorkerJob>();
+		let (result_tx, result_rx) = channel::<WorkerJob>();
+
+		let thread = spawn(move || {
+			// Each worker has its own arena
+			let mut eval_ctx = EvaluationContext::new(44100.0);
+
+			for mut job in job_rx.iter() {
+				eval_ctx.sample_rate = job.sample_rate;
+				eval_ctx.sample_dt = 1.0 / job.sample_rate;
+				std::mem::swap(&mut eval_ctx.shared_buffers, &mut job.shared_buffers);
+
+				for v in job.output.iter_mut() { *v = 0.0; }
+
+				let result = catch_unwind(AssertUnwindSafe(|| {
+					for synth in job.synths.iter_mut() {
+						synth.evaluate_into_slice_timed(&mut job.output, job.channels, &mut eval_ctx);
+					}
+				}));
+
+				// Give the shared buffers back, so the worker doesn't keep them alive between jobs
+				std::mem::swap(&mut eval_ctx.shared_buffers, &mut job.shared_buffers);
+
+				// The job still goes back, so its synths are dropped on the control side
+				let failed = result.is_err();
+				if let Err(payload) = result {
+					job.failure = Some(panic_message("Worker thread", &*payload));
+				}
+
+				if result_tx.send(job).is_err() || failed {
+					break
+				}
+			}
+		});
+
+		let empty_buffers = Arc::new(Vec::new());
+
+		let idle_job = WorkerJob {
+			synths: Vec::new(),
+			output: Vec::new(),
+			channels: 1,
+
+			sample_rate: 44100.0,
+			shared_buffers: empty_buffers.clone(),
+
+			failure: None,
+		};
+
+		Worker {
+			job_tx: Some(job_tx),
+			result_rx,
+			thread: Some(thread),
+
+			idle_job: Some(idle_job),
+			empty_buffers,
+		}
+	}
+}
+
+impl Drop for Worker {
+	fn drop(&mut self) {
+		// Closing the job channel ends the worker loop
+		self.job_tx.take();
+
+		if let Some(thread) = self.thread.take() {
+			let _ = thread.join();
+		}
+	}
+}
+
+
+// Evaluates synths in parallel, with synths partitioned into contiguous runs, one per worker.
+// Partial results are summed in worker order, so output is deterministic for a given worker count
+pub(crate) struct WorkerPool {
+	workers: Vec<Worker>,
+}
+
+impl WorkerPool {
+	pub(crate) fn new(worker_count: usize) -> Self {
+		WorkerPool {
+			workers: (0..worker_count).map(|_| Worker::new()).collect(),
+		}
+	}
+
+	pub(crate) fn worker_count(&self) -> usize { self.workers.len() }
+
+	// Accumulates all synths into data, leaving synths in their original order. Jobs for workers
+	// that have stopped are evaluated on the calling thread instead. Synths that panic are
+	// sent back as garbage along with the failure and their worker
+	pub(crate) fn evaluate(&mut self, synths: &mut Vec<Synth>, data: &mut [f32], channels: usize, eval_ctx: &mut EvaluationContext, garbage_tx: &Sender<Garbage>) {
+		if self.workers.is_empty() || synths.is_empty() {
+			return
+		}
+
+		let worker_count = self.workers.len().min(synths.len());
+		let per_worker = synths.len().div_ceil(worker_count);
+
+		let mut remaining = synths.drain(..);
+
+		for worker in self.workers[..worker_count].iter_mut() {
+			let mut job = worker.idle_job.take().expect("Worker job missing");
+
+			job.synths.extend(remaining.by_ref().take(per_worker));
+			job.output.resize(data.len(), 0.0);
+			job.channels = channels;
+			job.sample_rate = eval_ctx.sample_rate;
+			job.shared_buffers = eval_ctx.shared_buffers.clone();
+
+			if let Err(SendError(mut job)) = worker.job_tx.as_ref().unwrap().send(job) {
+				for v in job.output.iter_mut() { *v = 0.0; }
+
+				for synth in job.synths.iter_mut() {
+					synth.evaluate_into_slice_timed(&mut job.output, channels, eval_ctx);
+				}
+
+				// Collected below like any other result, so it's summed in the same order
+				worker.idle_job = Some(job);
+				worker.job_tx = None;
+			}
+		}
+
+		drop(remaining);
+
+		for worker in self.workers[..worker_count].iter_mut() {
+			let mut job = match worker.idle_job.take() {
+				Some(job) => job,
+				None => match worker.result_rx.recv() {
+					Ok(job) => job,
+
+					// Only if the worker stopped without sending the job back, taking its synths with it
+					Err(_) => {
+						worker.job_tx = None;
+						continue
+					}
+				}
+			};
+
+			// Synth state can't be trusted after a panic, so the job's output and synths are dropped
+			if let Some(failure) = job.failure.take() {
+				for synth in job.synths.drain(..) {
+					let _ = garbage_tx.send(Garbage::Synth(synth));
+				}
+
+				let _ = garbage_tx.send(Garbage::WorkerFailure(failure));
+				worker.job_tx = None;
+			} else {
+				for (v, partial) in data.iter_mut().zip(job.output.iter()) {
+					*v += *partial;
+				}
+			}
+
+			synths.append(&mut job.synths);
+			job.shared_buffers = worker.empty_buffers.clone();
+			worker.idle_job = Some(job);
+		}
+
+		// Stopped workers are joined when they're dropped, so that happens on the control side.
+		// Once none are left, the context evaluates synths itself
+		while let Some(idx) = self.workers.iter().position(|w| w.job_tx.is_none()) {
+			let worker = self.workers.remove(idx);
+			let _ = garbage_tx.send(Garbage::Worker(worker));
+		}
+	}
+}
@@ -331,6 +331,24 @@ impl<'a> EvaluationContext<'a> {
 				Ok(self.synth.new_saw(freq).into())
 			}
 
+			"bl-tri" | "bl-triangle" => {
+				ensure_args!(func_name, list == 1);
+				let freq = self.evaluate_sexpr(list.remove(0))?.to_input()?;
+				Ok(self.synth.new_bl_triangle(freq).into())
+			}
+
+			"bl-sqr" | "bl-square" => {
+				ensure_args!(func_name, list == 1);
+				let freq = self.evaluate_sexpr(list.remove(0))?.to_input()?;
+				Ok(self.synth.new_bl_square(freq).into())
+			}
+
+			"bl-saw" | "bl-sawtooth" => {
+				ensure_args!(func_name, list == 1);
+				let freq = self.evaluate_sexpr(list.remove(0))?.to_input()?;
+				Ok(self.synth.new_bl_saw(freq).into())
+			}
+
 			"lp" | "lowpass" => {
 				ensure_args!(func_name, list == 2);
 				let cutoff = self.evaluate_sexpr(list.remove(0))?.to_input()?;
@@ -369,7 +387,7 @@ impl<'a> EvaluationContext<'a> {
 					self.evaluate_sexpr(list.remove(0))?.to_input()?
 				} else { 1.0.into() };
 
-				let buf = self.synth.new_buffer(sequence);
+				let buf = self.synth.new_buffer(sequence)?;
 				Ok(self.synth.new_sequencer(buf, advance, reset).into())
 			}
 
@@ -386,7 +404,7 @@ impl<'a> EvaluationContext<'a> {
 				let mut eval_buffer = SynthBuffer::new(samples);
 
 				synth.evaluate_into_buffer(&mut eval_buffer, &mut eval_ctx);
-				let buffer_id = self.synth.new_buffer(eval_buffer.data);
+				let buffer_id = self.synth.add_buffer(eval_buffer)?;
 
 				Ok(self.synth.new_sampler(buffer_id, 0.0).into())
 			}
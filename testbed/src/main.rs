@@ -1,6 +1,3 @@
-#![feature(box_syntax)]
-#![feature(nll)]
-
 extern crate voi_synth;
 extern crate sdl2_sys as sdl;
 #[macro_use] extern crate failure;
@@ -32,7 +29,8 @@ fn main() -> SynthResult<()> {
 	std::env::set_var("RUST_BACKTRACE", "1");
 
 	let _window = Window::new().expect("Window open failed");
-	let mut synth_context = box voi_synth::Context::new(3, 256)?;
+	let mut synth_context = Box::new(voi_synth::Context::new(3, 256)?);
+	synth_context.set_worker_count(4);
 
 	// let midi_device = midi::init_device()?;
 
@@ -54,6 +52,7 @@ fn main() -> SynthResult<()> {
 	// test_sequencer(&mut synth_context)?;
 	// test_feedback(&mut synth_context)?;
 	// test_prebake(&mut synth_context)?;
+	// test_wavetable(&mut synth_context)?;
 
 	let voices = [
 		test_perf(&mut synth_context)?,
@@ -162,13 +161,18 @@ fn main() -> SynthResult<()> {
 			}
 		}
 
+		if let Some(error) = synth_context.evaluation_error() {
+			println!("{}", error);
+			break 'main_loop
+		}
+
 		let begin = time::Instant::now();
 		synth_context.dump_stats();
 		let end = time::Instant::now();
 
 		let diff = (end-begin).subsec_nanos() as f32 / 1000.0;
 
-		println!("lock time {}us", diff);
+		println!("stats time {}us", diff);
 
 		use std::thread::sleep;
 		use std::time::Duration;
@@ -195,21 +199,22 @@ fn init_audio(synth_context: &mut Box<voi_synth::Context>) -> SynthResult<AudioC
 	want.freq = 44100;
 	// want.freq = 22050;
 	want.format = AUDIO_F32SYS as u16;
-	want.channels = 1;
+	want.channels = 2;
 	want.samples = 256;
 	want.callback = Some(audio_callback);
 	want.userdata = unsafe{ transmute(&mut **synth_context) };
 
 	let device_id = unsafe {
-		SDL_OpenAudioDevice(null(), 0, &want, &mut have, SDL_AUDIO_ALLOW_FREQUENCY_CHANGE as i32)
+		SDL_OpenAudioDevice(null(), 0, &want, &mut have, (SDL_AUDIO_ALLOW_FREQUENCY_CHANGE | SDL_AUDIO_ALLOW_CHANNELS_CHANGE) as i32)
 	};
 	
 	ensure!(device_id != 0, "Failed to open audio: {}", unsafe { from_cstr!(SDL_GetError()) } );
-	ensure!(have.channels == 1, "Failed to get stereo audio");
+	ensure!(have.channels > 0, "Failed to get any output channels");
 	ensure!(have.format == AUDIO_F32SYS as _, "Failed to get wanted output format");
 
 	let buffer_size = have.samples as usize * have.channels as usize;
 	synth_context.set_buffer_size(buffer_size);
+	synth_context.set_channels(have.channels as usize);
 	synth_context.set_sample_rate(have.freq as f32);
 
 	Ok(AudioCtx { device_id })
@@ -223,14 +228,13 @@ fn stop_audio(audio_ctx: &mut AudioCtx) {
 }
 
 unsafe extern fn audio_callback(ud: *mut std::os::raw::c_void, stream: *mut u8, length: i32) {
-	use std::mem::transmute;
+	use std::mem::{transmute, size_of};
+	use std::slice;
 
 	let synth_context: &mut voi_synth::Context = transmute(ud);
-	let buffer = synth_context.get_ready_buffer().expect("Failed to get ready buffer");
+	let output = slice::from_raw_parts_mut(stream as *mut f32, length as usize / size_of::<f32>());
 
-	buffer.copy_to_raw(stream, length as usize);
-	// buffer.copy_to_stereo(stream, length as usize);
-	synth_context.queue_empty_buffer(buffer).unwrap();
+	synth_context.render(output);
 }
 
 
@@ -244,7 +248,8 @@ fn test_midi(synth_context: &mut voi_synth::Context) -> SynthResult<Vec<Paramete
 
 	let velocity_param = synth.new_parameter();
 	let freq_param = synth.new_parameter();
-	let freq = synth.new_lowpass(freq_param, 10.0);
+	synth.get_parameter(freq_param).set_sample_mode(SampleMode::Exponential(0.016));
+	let freq = freq_param;
 
 	let mod_param = synth.new_parameter();
 	let mod_amt = synth.new_remap(mod_param, 0.0, 1.0,   0.0, 880.0);
@@ -259,10 +264,10 @@ fn test_midi(synth_context: &mut voi_synth::Context) -> SynthResult<Vec<Paramete
 	let mod_a = synth.new_multiply(mod_osc, mod_amt);
 
 	let osc0_freq = synth.new_add(freq, mod_a);
-	let osc0 = synth.new_square(osc0_freq);
+	let osc0 = synth.new_bl_square(osc0_freq);
 
 	let osc1_freq = synth.new_multiply(osc0_freq, 0.5);
-	let osc1 = synth.new_saw(osc1_freq);
+	let osc1 = synth.new_bl_saw(osc1_freq);
 
 	let osc = synth.new_add(osc0, osc1);
 	
@@ -294,7 +299,8 @@ fn test_perf(synth_context: &mut voi_synth::Context) -> SynthResult<Vec<Paramete
 	synth.set_gain(0.3);
 
 	let freq_param = synth.new_parameter();
-	let freq = synth.new_lowpass(freq_param, 10.0);
+	synth.get_parameter(freq_param).set_sample_mode(SampleMode::Exponential(0.016));
+	let freq = freq_param;
 
 	let mut osc = synth.new_sine(freq);
 
@@ -347,7 +353,7 @@ fn test_sequencer(synth_context: &mut voi_synth::Context) -> SynthResult<()> {
 	let pulse2 = synth.new_signal_to_control(pulse2);
 	let pulse = synth.new_multiply(pulse, pulse2); // pulse shortening
 
-	let buf = synth.new_buffer(vec![55.0, 110.0, 220.0, 330.0, 220.0 * 5.0 / 4.0]);
+	let buf = synth.new_buffer(vec![55.0, 110.0, 220.0, 330.0, 220.0 * 5.0 / 4.0])?;
 	let seq = synth.new_sequencer(buf, pulse, 1.0);
 
 	let osc = synth.new_sine(seq);
@@ -360,6 +366,45 @@ fn test_sequencer(synth_context: &mut voi_synth::Context) -> SynthResult<()> {
 	Ok(())
 }
 
+fn test_wavetable(synth_context: &mut voi_synth::Context) -> SynthResult<()> {
+	// A triangle that sharpens into a detuned saw stack over 64 frames
+	let table = {
+		let mut synth = Synth::new();
+
+		let tri = synth.new_triangle(1.0);
+		let mut saws = synth.new_saw(1.0);
+		for i in 1..4 {
+			let saw = synth.new_saw(i as f32 + 1.0);
+			let saw = synth.new_multiply(saw, 1.0 / (i as f32 + 1.0));
+			saws = synth.new_add(saws, saw);
+		}
+
+		let sweep = synth.new_saw(1.0 / 64.0);
+		let sweep = synth.new_signal_to_control(sweep);
+		let mixed = synth.new_mix(tri, saws, sweep);
+		synth.set_output(mixed);
+
+		Wavetable::bake(synth, 2048, 64)?
+	};
+
+	let table = synth_context.add_wavetable(table)?;
+
+	let mut synth = Synth::new();
+	synth.set_gain(0.3);
+
+	let lfo = synth.new_sine(0.2);
+	let position = synth.new_signal_to_control(lfo);
+	let pitch = synth.new_square(0.5);
+	let freq = synth.new_remap(pitch, -1.0, 1.0, 55.0, 1760.0);
+
+	let osc = synth.new_wavetable(table, freq, position);
+	synth.set_output(osc);
+
+	synth_context.push_synth(synth)?;
+
+	Ok(())
+}
+
 
 #[allow(dead_code)]
 fn test_feedback(synth_context: &mut voi_synth::Context) -> SynthResult<()> {
@@ -466,7 +511,7 @@ fn test_prebake(synth_context: &mut voi_synth::Context) -> SynthResult<()> {
 		buffer
 	};
 
-	let test_buffer = synth_context.create_shared_buffer(prebaked_buffer.data)?;
+	let test_buffer = synth_context.add_shared_buffer(prebaked_buffer)?;
 
 	let mut synth = Synth::new();
 	synth.set_gain(1.0);
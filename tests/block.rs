@@ -0,0 +1,73 @@
+use voi_synth::*;
+
+// Two voices of feedback FM through a value store, each with an envelope, and a filter sweep
+// driven by smoothed parameters that are changed part way through buffers
+fn feedback_voices(mode: EvaluationMode) -> (Synth, [ParameterID; 3]) {
+	let mut synth = Synth::new();
+	synth.set_evaluation_mode(mode);
+
+	let freq = synth.new_parameter();
+	let cutoff = synth.new_parameter();
+	let fm_depth = synth.new_parameter();
+	synth.get_parameter(freq).set_value(220.0);
+	synth.get_parameter(cutoff).set_value(800.0);
+	synth.get_parameter(cutoff).set_sample_mode(SampleMode::Linear(0.01));
+	synth.get_parameter(fm_depth).set_sample_mode(SampleMode::Cubic(0.005));
+
+	let mut mix = synth.new_sine(0.0);
+
+	for i in 0..2 {
+		let feedback = synth.new_value_store();
+		let fm = synth.new_multiply(feedback, fm_depth);
+		let voice_freq = synth.new_multiply(freq, 1.0 + i as f32 * 0.5);
+		let voice_freq = synth.new_add(voice_freq, fm);
+		let osc = synth.new_sine(voice_freq);
+		synth.new_store_write(feedback, osc);
+
+		let gate = synth.new_square(7.0 + i as f32 * 3.0);
+		let env = synth.new_env_adsr(0.005, 0.02, 0.6, 0.03, gate);
+		let voice = synth.new_multiply(osc, env);
+		mix = synth.new_add(mix, voice);
+	}
+
+	let saw = synth.new_bl_saw(freq);
+	let saw = synth.new_lowpass(saw, cutoff);
+	let out = synth.new_mix(mix, saw, 0.3);
+	let out = synth.new_clamp(out, -1.0, 1.0);
+
+	let [left, right] = synth.new_pan(out, -0.2);
+	synth.set_outputs(&[left, right]);
+
+	(synth, [freq, cutoff, fm_depth])
+}
+
+fn render(mode: EvaluationMode) -> Vec<u32> {
+	let mut ctx = OfflineContext::new(44100.0, 300);
+	ctx.set_channels(2);
+
+	let (synth, [freq, cutoff, fm_depth]) = feedback_voices(mode);
+	ctx.push_synth(synth).unwrap();
+
+	// None of these fall on a block or buffer boundary
+	ctx.set_parameter_at(freq, 330.0, 1001);
+	ctx.set_parameter_at(cutoff, 3000.0, 2222);
+	ctx.set_parameter_at(fm_depth, 150.0, 3333);
+	ctx.set_parameter_at(freq, 110.0, 4097);
+	ctx.set_parameter_at(fm_depth, 20.0, 4123);
+
+	ctx.render_frames(8000).data.into_iter()
+		.map(f32::to_bits)
+		.collect()
+}
+
+#[test]
+fn block_evaluation_matches_per_sample() {
+	let per_sample = render(EvaluationMode::PerSample);
+	let block = render(EvaluationMode::Block);
+
+	assert!(per_sample.iter().any(|&s| f32::from_bits(s) != 0.0));
+
+	if let Some(frame) = per_sample.iter().zip(&block).position(|(a, b)| a != b) {
+		panic!("Output differs from frame {}", frame / 2);
+	}
+}
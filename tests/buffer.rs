@@ -0,0 +1,49 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+#[test]
+fn freed_ids_stay_invalid() {
+	let mut ctx = OfflineContext::new(44100.0, 64);
+
+	let first = ctx.create_shared_buffer(vec![1.0]).unwrap();
+	ctx.free_shared_buffer(first).unwrap();
+	assert!(ctx.free_shared_buffer(first).is_err());
+
+	// Enough to use up every generation of the slot, which is then retired rather than wrapping around
+	for _ in 0..u16::MAX as usize + 2 {
+		let id = ctx.create_shared_buffer(vec![1.0]).unwrap();
+		assert_ne!(id, first);
+		ctx.free_shared_buffer(id).unwrap();
+	}
+
+	assert!(ctx.replace_shared_buffer(first, Buffer::new(1)).is_err());
+}
+
+#[test]
+fn tagged_buffers_play_at_their_own_pitch() {
+	use std::f32::consts::TAU;
+
+	// Ten cycles of a 441Hz sine at 22050Hz, 50 samples per cycle
+	let cycle: Vec<f32> = (0..500).map(|i| (i as f32 / 50.0 * TAU).sin()).collect();
+
+	let mut ctx = bypass_context(44100.0, 64);
+	let buffer = ctx.add_shared_buffer(Buffer::from_interleaved(cycle.clone(), 1).with_sample_rate(22050.0)).unwrap();
+
+	let mut synth = Synth::new();
+	let sampler = synth.new_sampler(buffer, 0.0);
+	synth.set_output(sampler);
+	ctx.push_synth(synth).unwrap();
+
+	// Still 441Hz at 44100Hz, so twice as many samples per cycle
+	let output = ctx.render_frames(2000).data;
+	for (i, &s) in output.iter().enumerate() {
+		let expected = (i as f32 / 100.0 * TAU).sin();
+		assert!((s - expected).abs() < 0.01, "{}: {} != {}", i, s, expected);
+	}
+
+	// And read sample for sample once the context matches the buffer
+	ctx.set_sample_rate(22050.0);
+	assert_eq!(ctx.render_frames(500).data, cycle);
+}
@@ -0,0 +1,17 @@
+// Each test binary uses its own subset of these
+#![allow(dead_code)]
+
+use voi_synth::*;
+
+// Renders exactly what synths produce, without any master processing
+pub fn bypass_context(sample_rate: f32, buffer_size: usize) -> OfflineContext {
+	let mut ctx = OfflineContext::new(sample_rate, buffer_size);
+	ctx.set_master_config(MasterConfig::bypass());
+	ctx
+}
+
+pub fn render(synth: Synth, frames: usize) -> Vec<f32> {
+	let mut ctx = bypass_context(44100.0, 256);
+	ctx.push_synth(synth).unwrap();
+	ctx.render_frames(frames).data
+}
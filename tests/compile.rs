@@ -0,0 +1,67 @@
+mod common;
+
+use voi_synth::*;
+use common::render;
+
+#[test]
+fn orders_nodes_added_out_of_order() {
+	let mut synth = Synth::new();
+	let out = synth.new_multiply(0.0, 0.5);
+	let osc = synth.new_saw(220.0);
+	let filtered = synth.new_lowpass(osc, 800.0);
+	synth.set_input(out, 0, filtered).unwrap();
+	synth.set_output(out);
+	synth.set_label(osc, "osc");
+
+	let node_map = synth.compile().unwrap();
+
+	let mut expected = Synth::new();
+	let osc = expected.new_saw(220.0);
+	let filtered = expected.new_lowpass(osc, 800.0);
+	let expected_out = expected.new_multiply(filtered, 0.5);
+	expected.set_output(expected_out);
+
+	assert_eq!(node_map.get(out), Some(expected_out));
+	assert_eq!(synth.get_labelled_node("osc"), Some(osc));
+	assert!(render(synth, 2000) == render(expected, 2000));
+}
+
+#[test]
+fn delays_feedback_by_one_sample() {
+	// A sine modulating its own frequency
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(0.0);
+	let depth = synth.new_multiply(osc, 100.0);
+	let freq = synth.new_add(440.0, depth);
+	synth.set_input(osc, 0, freq).unwrap();
+	synth.set_output(osc);
+	synth.compile().unwrap();
+
+	// The same loop through a store written after the sine
+	let mut expected = Synth::new();
+	let previous = expected.new_value_store();
+	let depth = expected.new_multiply(previous, 100.0);
+	let freq = expected.new_add(440.0, depth);
+	let osc = expected.new_sine(freq);
+	expected.new_store_write(previous, osc);
+	expected.set_output(osc);
+
+	let (output, expected) = (render(synth, 2000), render(expected, 2000));
+	assert!(output.iter().any(|&s| s != 0.0));
+	assert!(output == expected);
+}
+
+#[test]
+fn leaves_ordered_graphs_alone() {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(330.0);
+	let out = synth.new_multiply(osc, 0.5);
+	synth.set_output(out);
+
+	let original = synth.duplicate();
+	let node_map = synth.compile().unwrap();
+
+	assert_eq!(node_map.get(osc), Some(osc));
+	assert_eq!(node_map.get(out), Some(out));
+	assert!(render(synth, 2000) == render(original, 2000));
+}
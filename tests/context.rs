@@ -0,0 +1,198 @@
+use voi_synth::*;
+
+#[test]
+fn queued_buffers_follow_channel_changes() {
+	// More than the 16 ready buffers the evaluation thread can fill ahead, so the last
+	// few are still queued as mono when the channel count changes
+	let buffer_count = 20;
+	let mut ctx = Context::new(buffer_count, 256).unwrap();
+
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(440.0);
+	let [left, right] = synth.new_pan(osc, -1.0);
+	synth.set_outputs(&[left, right]);
+	ctx.push_synth(synth).unwrap();
+
+	ctx.set_channels(2);
+
+	let buffers: Vec<_> = (0..buffer_count).map(|_| ctx.get_ready_buffer().unwrap()).collect();
+
+	for buffer in &buffers[17..] {
+		assert_eq!(buffer.channels, 2);
+		assert_eq!(buffer.len(), 256);
+
+		// Panned hard left
+		assert!(buffer.data.chunks(2).any(|frame| frame[0] != 0.0));
+		assert!(buffer.data.chunks(2).all(|frame| frame[1] == 0.0));
+	}
+}
+
+#[test]
+fn buffer_size_changes_apply_from_the_next_buffer() {
+	let buffer_count = 3;
+	let mut ctx = Context::new(buffer_count, 256).unwrap();
+	ctx.push_synth(sine_synth(440.0)).unwrap();
+
+	for _ in 0..buffer_count {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	ctx.set_buffer_size(100);
+
+	// Only buffers filled before the change can still be the old size
+	let sizes: Vec<_> = (0..10).map(|_| {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		let size = buffer.len();
+		ctx.queue_empty_buffer(buffer).unwrap();
+		size
+	}).collect();
+
+	let old_size = sizes.iter().take_while(|&&size| size == 256).count();
+	assert!(old_size <= buffer_count, "{:?}", sizes);
+	assert!(sizes[old_size..].iter().all(|&size| size == 100), "{:?}", sizes);
+}
+
+#[test]
+fn render_matches_offline_rendering() {
+	let buffer_size = 256;
+	let frames = 3000;
+
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(440.0);
+	let tremolo = synth.new_sine(5.0);
+	let out = synth.new_multiply(osc, tremolo);
+	synth.set_output(out);
+
+	let mut offline = OfflineContext::new(22050.0, buffer_size);
+	offline.push_synth(synth.duplicate()).unwrap();
+	let expected = offline.render_frames(frames).data;
+
+	// Nothing is queued until the synth is in, so no buffer is filled without it
+	let mut ctx = Context::new(0, buffer_size).unwrap();
+	assert_eq!(ctx.get_sample_rate(), offline.get_sample_rate());
+	ctx.push_synth(synth).unwrap();
+	for _ in 0..4 {
+		ctx.queue_empty_buffer(Buffer::new(buffer_size)).unwrap();
+	}
+
+	// Slices that don't line up with buffers, picking up after any underrun
+	let mut output = vec![0.0; frames];
+	let mut written = 0;
+	while written < frames {
+		let end = (written + 100).min(frames);
+		written += ctx.render(&mut output[written..end]);
+		std::thread::yield_now();
+	}
+
+	assert!(output == expected);
+}
+
+#[test]
+fn render_reports_underruns_instead_of_blocking() {
+	let mut ctx = Context::new(0, 256).unwrap();
+	ctx.push_synth(sine_synth(440.0)).unwrap();
+
+	let mut output = vec![1.0; 100];
+	assert_eq!(ctx.render(&mut output), 0);
+	assert!(output.iter().all(|&s| s == 0.0));
+	assert_eq!(ctx.stats().render_underruns, 1);
+}
+
+fn sine_synth(freq: f32) -> Synth {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(freq);
+	synth.set_output(osc);
+	synth
+}
+
+fn panicking_synth() -> Synth {
+	let mut synth = sine_synth(220.0);
+	synth.set_panics_on_evaluation();
+	synth
+}
+
+#[test]
+fn falls_back_to_silence_after_a_panic() {
+	let ctx = Context::new(3, 256).unwrap();
+	ctx.push_synth(sine_synth(440.0)).unwrap();
+
+	let buffer = ctx.get_ready_buffer().unwrap();
+	assert!(ctx.evaluation_error().is_none());
+	ctx.queue_empty_buffer(buffer).unwrap();
+
+	ctx.push_synth(panicking_synth()).unwrap();
+
+	// Buffers keep coming so the audio device stays fed, but nothing is evaluated
+	for _ in 0..8 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	ctx.push_synth(sine_synth(330.0)).unwrap();
+
+	for _ in 0..3 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		assert!(buffer.data.iter().all(|&v| v == 0.0));
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	let error = ctx.evaluation_error().unwrap();
+	assert!(error.contains("Evaluation thread panicked"), "{}", error);
+	assert!(error.contains("was set to panic"), "{}", error);
+}
+
+#[test]
+fn worker_panics_only_lose_their_synths() {
+	let ctx = Context::new(3, 256).unwrap();
+	ctx.set_worker_count(2);
+
+	// One synth per worker
+	let mut broken = panicking_synth();
+	let envelope = broken.new_env_ar(0.1, 0.1, 1.0);
+	let oscillator = broken.new_sine(110.0);
+	let broken_id = ctx.push_synth(broken).unwrap();
+	ctx.push_synth(sine_synth(440.0)).unwrap();
+
+	let mut error = None;
+	for _ in 0..100 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		ctx.queue_empty_buffer(buffer).unwrap();
+
+		error = ctx.evaluation_error();
+		if error.is_some() { break }
+	}
+
+	let error = error.expect("Worker panic wasn't reported");
+	assert!(error.contains("Worker thread panicked"), "{}", error);
+
+	// The other synth keeps playing
+	for _ in 0..3 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		assert!(buffer.data.iter().any(|&v| v != 0.0));
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	// The lost synth is forgotten, so releases on it are ignored rather than checked
+	assert!(ctx.release_synth(broken_id, Release::Envelope(envelope)).is_ok());
+	assert!(ctx.release_synth(broken_id, Release::Envelope(oscillator)).is_ok());
+}
+
+#[test]
+fn drops_with_a_full_ready_queue() {
+	// More buffers than fit in the ready queue, none of which are read back
+	let ctx = Context::new(20, 256).unwrap();
+	ctx.push_synth(sine_synth(440.0)).unwrap();
+
+	while ctx.current_time() < 16 * 256 {
+		std::thread::sleep(std::time::Duration::from_millis(1));
+	}
+
+	let (done_tx, done_rx) = std::sync::mpsc::channel();
+	std::thread::spawn(move || {
+		drop(ctx);
+		done_tx.send(()).unwrap();
+	});
+
+	assert!(done_rx.recv_timeout(std::time::Duration::from_secs(5)).is_ok(), "Dropping the context hung");
+}
@@ -0,0 +1,202 @@
+use voi_synth::*;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+fn wav_bytes(format_tag: u16, channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+	let block_align = channels * bits / 8;
+
+	let mut fmt = Vec::new();
+	fmt.extend_from_slice(&format_tag.to_le_bytes());
+	fmt.extend_from_slice(&channels.to_le_bytes());
+	fmt.extend_from_slice(&sample_rate.to_le_bytes());
+	fmt.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+	fmt.extend_from_slice(&block_align.to_le_bytes());
+	fmt.extend_from_slice(&bits.to_le_bytes());
+
+	riff(&[(b"fmt ", &fmt), (b"data", data)])
+}
+
+fn riff(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+	let mut body = b"WAVE".to_vec();
+
+	for (id, data) in chunks {
+		body.extend_from_slice(*id);
+		body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		body.extend_from_slice(data);
+		if data.len() % 2 == 1 { body.push(0); }
+	}
+
+	let mut bytes = b"RIFF".to_vec();
+	bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+	bytes.extend_from_slice(&body);
+	bytes
+}
+
+// 80 bit extended precision, as aiff stores sample rates
+fn extended(value: u32) -> [u8; 10] {
+	if value == 0 { return [0; 10]; }
+
+	let top_bit = 31 - value.leading_zeros();
+	let exponent = 16383 + top_bit as u16;
+	let mantissa = (value as u64) << (63 - top_bit);
+
+	let mut bytes = [0; 10];
+	bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+	bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+	bytes
+}
+
+// Compression is only written for aiff-c
+fn aiff_bytes(channels: u16, sample_rate: u32, bits: u16, compression: Option<&[u8; 4]>, data: &[u8]) -> Vec<u8> {
+	let frames = data.len() as u32 / (channels as u32 * bits as u32 / 8);
+
+	let mut comm = Vec::new();
+	comm.extend_from_slice(&channels.to_be_bytes());
+	comm.extend_from_slice(&frames.to_be_bytes());
+	comm.extend_from_slice(&bits.to_be_bytes());
+	comm.extend_from_slice(&extended(sample_rate));
+	if let Some(compression) = compression {
+		comm.extend_from_slice(compression);
+		comm.extend_from_slice(&[0, 0]);
+	}
+
+	let mut ssnd = vec![0; 8];
+	ssnd.extend_from_slice(data);
+
+	let mut body = if compression.is_some() { b"AIFC".to_vec() } else { b"AIFF".to_vec() };
+	for (id, chunk) in [(b"COMM", &comm), (b"SSND", &ssnd)] {
+		body.extend_from_slice(id);
+		body.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+		body.extend_from_slice(chunk);
+		if chunk.len() % 2 == 1 { body.push(0); }
+	}
+
+	let mut bytes = b"FORM".to_vec();
+	bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+	bytes.extend_from_slice(&body);
+	bytes
+}
+
+fn load(name: &str, bytes: &[u8]) -> SynthResult<AudioData> {
+	let path = std::env::temp_dir().join(format!("voi_loader_{}", name));
+	std::fs::write(&path, bytes).unwrap();
+	let audio = load_audio_file(&path);
+	std::fs::remove_file(&path).unwrap();
+	audio
+}
+
+#[test]
+fn decodes_8_bit_wav() {
+	let audio = load("pcm8.wav", &wav_bytes(WAVE_FORMAT_PCM, 1, 8000, 8, &[0x00, 0x80, 0xFF])).unwrap();
+
+	assert_eq!(audio.sample_rate, 8000.0);
+	assert_eq!(audio.channels, 1);
+	assert_eq!(audio.samples, [-1.0, 0.0, 127.0 / 128.0]);
+}
+
+#[test]
+fn decodes_16_bit_wav() {
+	let data: Vec<u8> = [i16::MIN, 0, 16384, -16384].iter().flat_map(|v| v.to_le_bytes()).collect();
+	let audio = load("pcm16.wav", &wav_bytes(WAVE_FORMAT_PCM, 2, 44100, 16, &data)).unwrap();
+
+	assert_eq!(audio.sample_rate, 44100.0);
+	assert_eq!(audio.channels, 2);
+	assert_eq!(audio.samples, [-1.0, 0.0, 0.5, -0.5]);
+}
+
+#[test]
+fn decodes_24_bit_wav() {
+	let data: Vec<u8> = [-8388608i32, 0, 4194304].iter().flat_map(|v| v.to_le_bytes()[..3].to_vec()).collect();
+	let audio = load("pcm24.wav", &wav_bytes(WAVE_FORMAT_PCM, 1, 48000, 24, &data)).unwrap();
+
+	assert_eq!(audio.sample_rate, 48000.0);
+	assert_eq!(audio.samples, [-1.0, 0.0, 0.5]);
+}
+
+#[test]
+fn decodes_32_bit_wav() {
+	let data: Vec<u8> = [i32::MIN, 0, 1 << 30].iter().flat_map(|v| v.to_le_bytes()).collect();
+	let audio = load("pcm32.wav", &wav_bytes(WAVE_FORMAT_PCM, 1, 96000, 32, &data)).unwrap();
+
+	assert_eq!(audio.sample_rate, 96000.0);
+	assert_eq!(audio.samples, [-1.0, 0.0, 0.5]);
+}
+
+#[test]
+fn decodes_float_wav() {
+	let data: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|v| v.to_le_bytes()).collect();
+	let audio = load("float32.wav", &wav_bytes(WAVE_FORMAT_IEEE_FLOAT, 1, 44100, 32, &data)).unwrap();
+	assert_eq!(audio.samples, [0.25, -0.75]);
+
+	let data: Vec<u8> = [0.25f64, -0.75].iter().flat_map(|v| v.to_le_bytes()).collect();
+	let audio = load("float64.wav", &wav_bytes(WAVE_FORMAT_IEEE_FLOAT, 1, 44100, 64, &data)).unwrap();
+	assert_eq!(audio.samples, [0.25, -0.75]);
+}
+
+#[test]
+fn decodes_aiff() {
+	let data: Vec<u8> = [i16::MIN, 0, 16384, -16384].iter().flat_map(|v| v.to_be_bytes()).collect();
+	let audio = load("pcm16.aiff", &aiff_bytes(2, 44100, 16, None, &data)).unwrap();
+
+	assert_eq!(audio.sample_rate, 44100.0);
+	assert_eq!(audio.channels, 2);
+	assert_eq!(audio.samples, [-1.0, 0.0, 0.5, -0.5]);
+
+	// Little endian pcm and float in aiff-c
+	let data: Vec<u8> = [16384i16, -16384].iter().flat_map(|v| v.to_le_bytes()).collect();
+	let audio = load("sowt.aifc", &aiff_bytes(1, 22050, 16, Some(b"sowt"), &data)).unwrap();
+	assert_eq!(audio.sample_rate, 22050.0);
+	assert_eq!(audio.samples, [0.5, -0.5]);
+
+	let data: Vec<u8> = [0.25f32, -0.75].iter().flat_map(|v| v.to_be_bytes()).collect();
+	let audio = load("fl32.aifc", &aiff_bytes(1, 48000, 32, Some(b"fl32"), &data)).unwrap();
+	assert_eq!(audio.samples, [0.25, -0.75]);
+}
+
+#[test]
+fn rejects_malformed_files() {
+	let data = [0u8; 8];
+
+	assert!(load("garbage.wav", b"RIFX0000WAVEfmt ").is_err());
+	assert!(load("short.wav", b"RIFF").is_err());
+
+	// Missing chunks
+	assert!(load("no_fmt.wav", &riff(&[(b"data", &data)])).is_err());
+	assert!(load("no_data.wav", &riff(&[(b"fmt ", &wav_bytes(WAVE_FORMAT_PCM, 1, 44100, 16, &data)[20..36])])).is_err());
+	assert!(load("short_fmt.wav", &riff(&[(b"fmt ", &[1, 0, 1, 0]), (b"data", &data)])).is_err());
+
+	// Unsupported or nonsensical formats
+	assert!(load("pcm12.wav", &wav_bytes(WAVE_FORMAT_PCM, 1, 44100, 12, &data)).is_err());
+	assert!(load("float16.wav", &wav_bytes(WAVE_FORMAT_IEEE_FLOAT, 1, 44100, 16, &data)).is_err());
+	assert!(load("adpcm.wav", &wav_bytes(2, 1, 44100, 16, &data)).is_err());
+	assert!(load("no_channels.wav", &wav_bytes(WAVE_FORMAT_PCM, 0, 44100, 16, &data)).is_err());
+	assert!(load("no_rate.wav", &wav_bytes(WAVE_FORMAT_PCM, 1, 0, 16, &data)).is_err());
+
+	assert!(load("ulaw.aifc", &aiff_bytes(1, 44100, 16, Some(b"ulaw"), &data)).is_err());
+	assert!(load("no_rate.aiff", &aiff_bytes(1, 0, 16, None, &data)).is_err());
+}
+
+#[test]
+fn resamples_to_the_context_rate() {
+	// A 441Hz sine, 100 samples per cycle
+	let samples: Vec<f32> = (0..4410).map(|i| (i as f32 / 100.0 * std::f32::consts::TAU).sin()).collect();
+	let audio = AudioData { sample_rate: 44100.0, channels: 1, samples };
+
+	let buffer = audio.to_buffer(ChannelMode::Interleaved, 22050.0).unwrap();
+	assert_eq!(buffer.sample_rate, Some(22050.0));
+	assert_eq!(buffer.len(), 2205);
+
+	// Same pitch at the new rate, 50 samples per cycle
+	for (i, &s) in buffer.data.iter().enumerate().skip(20).take(2000) {
+		let expected = (i as f32 / 50.0 * std::f32::consts::TAU).sin();
+		assert!((s - expected).abs() < 0.01, "{}: {} != {}", i, s, expected);
+	}
+
+	assert!(audio.to_buffer(ChannelMode::Interleaved, 0.0).is_err());
+	assert!(audio.to_buffer(ChannelMode::Interleaved, -44100.0).is_err());
+
+	// Not even a whole frame
+	let partial = AudioData { sample_rate: 44100.0, channels: 2, samples: vec![0.5] };
+	assert!(partial.to_buffer(ChannelMode::Interleaved, 22050.0).unwrap().is_empty());
+}
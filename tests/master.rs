@@ -0,0 +1,149 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+const SAMPLE_RATE: f32 = 44100.0;
+
+fn sine_synth(amplitude: f32) -> Synth {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(441.0);
+	let out = synth.new_multiply(osc, amplitude);
+	synth.set_output(out);
+	synth
+}
+
+// Output of the synth through the given master config, and without any processing
+fn render(synth: Synth, config: MasterConfig, seconds: f32) -> (OfflineContext, Vec<f32>, Vec<f32>) {
+	let mut reference = bypass_context(SAMPLE_RATE, 256);
+	reference.push_synth(synth.duplicate()).unwrap();
+	let expected = reference.render_seconds(seconds).data;
+
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	ctx.set_master_config(config);
+	ctx.push_synth(synth).unwrap();
+	let output = ctx.render_seconds(seconds).data;
+
+	(ctx, output, expected)
+}
+
+fn peak(samples: &[f32]) -> f32 {
+	samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()))
+}
+
+fn limiter() -> MasterConfig {
+	MasterConfig {
+		dynamics: Dynamics::Limiter { attack: 0.005, release: 0.2 },
+		..MasterConfig::bypass()
+	}
+}
+
+#[test]
+fn limiter_holds_loud_signals_at_full_scale() {
+	// Long enough for the envelope to release from where it starts
+	let (ctx, output, _) = render(sine_synth(4.0), limiter(), 3.0);
+
+	// Settled by the end, with the envelope sitting around the signal's peak
+	let end = &output[output.len() - 4410..];
+	assert!(peak(end) > 0.9 && peak(end) < 1.1, "{}", peak(end));
+
+	let stats = ctx.stats();
+	let expected_reduction = 20.0 * 4.0f32.log10();
+	assert!((stats.gain_reduction - expected_reduction).abs() < 0.5, "{} dB", stats.gain_reduction);
+	assert!(ctx.get_master_envelope() > 3.5);
+}
+
+#[test]
+fn limiter_leaves_quiet_signals_alone() {
+	let (ctx, output, expected) = render(sine_synth(0.5), limiter(), 3.0);
+
+	let end = output.len() - 4410;
+	assert!(output[end..] == expected[end..]);
+	assert_eq!(ctx.stats().gain_reduction, 0.0);
+	assert_eq!(ctx.get_master_envelope(), 1.0);
+}
+
+#[test]
+fn soft_clip_saturates() {
+	let config = MasterConfig { dynamics: Dynamics::SoftClip { drive: 2.0 }, gain: 0.5, ..MasterConfig::bypass() };
+	let (_, output, expected) = render(sine_synth(2.0), config, 0.1);
+
+	for (&out, &dry) in output.iter().zip(&expected) {
+		let clipped = (dry * 2.0).tanh() * 0.5;
+		assert!((out - clipped).abs() < 1e-6, "{} != {}", out, clipped);
+	}
+
+	// Rounded off just short of the ceiling
+	assert!(peak(&output) > 0.49 && peak(&output) < 0.5);
+}
+
+#[test]
+fn gain_then_hard_clip() {
+	let config = MasterConfig { gain: 0.5, clip: true, ..MasterConfig::bypass() };
+	let (_, output, expected) = render(sine_synth(3.0), config, 0.1);
+
+	assert!(output.iter().zip(&expected).all(|(&out, &dry)| out == (dry * 0.5).clamp(-1.0, 1.0)));
+	assert_eq!(peak(&output), 1.0);
+}
+
+#[test]
+fn dc_blocker_removes_offsets() {
+	let mut synth = Synth::new();
+	let out = synth.new_add(0.5, 0.0);
+	synth.set_output(out);
+
+	let config = MasterConfig { dc_blocker: Some(50.0), ..MasterConfig::bypass() };
+	let (ctx, output, _) = render(synth, config, 0.5);
+
+	assert!(output[0] > 0.49);
+	assert!(peak(&output[output.len() - 256..]) < 1e-4);
+	assert!((ctx.get_master_dc_offset() - 0.5).abs() < 1e-4);
+}
+
+#[test]
+fn master_effect_replaces_the_mix() {
+	let stereo_synth = || {
+		let mut synth = Synth::new();
+		let osc = synth.new_sine(441.0);
+		let [left, right] = synth.new_pan(osc, -0.5);
+		synth.set_outputs(&[left, right]);
+		synth
+	};
+
+	let mut reference = bypass_context(SAMPLE_RATE, 256);
+	reference.set_channels(2);
+	reference.push_synth(stereo_synth()).unwrap();
+	let expected = reference.render_frames(2000).data;
+
+	// Swaps the channels, halving the one that ends up on the right
+	let mut effect = Synth::new();
+	let left = effect.new_bus_input(0);
+	let right = effect.new_bus_input(1);
+	let halved = effect.new_multiply(left, 0.5);
+	effect.set_outputs(&[right, halved]);
+
+	// Bus inputs outside the master effect only read silence
+	let mut listener = Synth::new();
+	let bus = listener.new_bus_input(0);
+	listener.set_output(bus);
+
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	ctx.set_channels(2);
+	ctx.set_master_effect(Some(effect)).unwrap();
+	ctx.push_synth(stereo_synth()).unwrap();
+	ctx.push_synth(listener).unwrap();
+	let output = ctx.render_frames(2000).data;
+
+	for (out, dry) in output.chunks(2).zip(expected.chunks(2)) {
+		assert_eq!(out, [dry[1], dry[0] * 0.5]);
+	}
+
+	// And then the master processing applies to the effect's output
+	ctx.set_master_config(MasterConfig { gain: 2.0, ..MasterConfig::bypass() });
+	let output = ctx.render_frames(2000).data;
+	let expected = reference.render_frames(2000).data;
+
+	for (out, dry) in output.chunks(2).zip(expected.chunks(2)) {
+		assert_eq!(out, [dry[1] * 2.0, dry[0]]);
+	}
+}
@@ -0,0 +1,133 @@
+mod common;
+
+use voi_synth::*;
+use voi_synth::node::Input;
+use common::render;
+
+const MODULE_BUFFER: [f32; 4] = [0.25, -0.5, 0.75, 0.1];
+
+// A sine minus its previous sample through a store, plus a looped local buffer, all scaled by a parameter
+fn difference_module() -> Module {
+	let mut module = Module::new();
+	let freq = module.new_input("freq", 220.0).unwrap();
+	let level = module.new_parameter("level").unwrap();
+	module.get_parameter(level).set_value(0.5);
+
+	let previous = module.new_value_store();
+	let buffer = module.new_buffer(MODULE_BUFFER.to_vec()).unwrap();
+
+	let osc = module.new_sine(freq);
+	let difference = module.new_sub(osc, previous);
+	module.new_store_write(previous, osc);
+	let sample = module.new_sampler(buffer, 0.0);
+	let sum = module.new_add(difference, sample);
+	let out = module.new_multiply(sum, level);
+
+	module.set_output("out", out);
+	module.set_output("osc", osc);
+	module
+}
+
+// Nodes, a store, a parameter and a buffer for a module's ids to be offset past
+fn add_existing_parts(synth: &mut Synth) -> (NodeID, NodeID) {
+	let store = synth.new_value_store();
+	let gain = synth.new_parameter();
+	synth.get_parameter(gain).set_value(0.25);
+	let buffer = synth.new_buffer(vec![1.0, -1.0]).unwrap();
+
+	let lfo = synth.new_sine(3.0);
+	synth.new_store_write(store, lfo);
+	let sample = synth.new_sampler(buffer, 0.0);
+	let mixed = synth.new_add(store, sample);
+	let mixed = synth.new_multiply(mixed, gain);
+	let freq = synth.new_remap(lfo, -1.0, 1.0, 200.0, 300.0);
+
+	(mixed, freq)
+}
+
+#[test]
+fn remaps_ids_past_existing_parts() {
+	let mut synth = Synth::new();
+	let (mixed, freq) = add_existing_parts(&mut synth);
+	let instance = synth.add_module(&difference_module(), &[("freq", freq.into())]).unwrap();
+	let out = synth.new_add(mixed, instance.output("out").unwrap());
+	synth.set_output(out);
+
+	// The same nodes added by hand
+	let mut expected = Synth::new();
+	let (mixed, freq) = add_existing_parts(&mut expected);
+	let level = expected.new_parameter();
+	expected.get_parameter(level).set_value(0.5);
+	let previous = expected.new_value_store();
+	let buffer = expected.new_buffer(MODULE_BUFFER.to_vec()).unwrap();
+
+	let osc = expected.new_sine(freq);
+	let difference = expected.new_sub(osc, previous);
+	expected.new_store_write(previous, osc);
+	let sample = expected.new_sampler(buffer, 0.0);
+	let sum = expected.new_add(difference, sample);
+	let module_out = expected.new_multiply(sum, level);
+	let out = expected.new_add(mixed, module_out);
+	expected.set_output(out);
+
+	// Parameters are numbered after the synth's own, like they are when added by hand
+	assert_eq!(instance.parameter("level"), Some(synth.parameter_of_duplicate(level)));
+	assert!(render(synth, 2000) == render(expected, 2000));
+}
+
+#[test]
+fn instances_are_independent() {
+	let module = difference_module();
+
+	let mut synth = Synth::new();
+	let first = synth.add_module(&module, &[("freq", Input::Literal(220.0))]).unwrap();
+	let second = synth.add_module(&module, &[("freq", Input::Literal(330.0))]).unwrap();
+
+	assert_ne!(first.output("out"), second.output("out"));
+	assert_ne!(first.parameter("level"), second.parameter("level"));
+
+	// Silencing the first leaves the second as it would be alone, which only holds if
+	// each has its own store and buffer
+	synth.get_parameter(first.parameter("level").unwrap()).set_value(0.0);
+	let out = synth.new_add(first.output("out").unwrap(), second.output("out").unwrap());
+	synth.set_output(out);
+
+	let mut alone = Synth::new();
+	let instance = alone.add_module(&module, &[("freq", Input::Literal(330.0))]).unwrap();
+	alone.set_output(instance.output("out").unwrap());
+
+	assert!(render(synth, 2000) == render(alone, 2000));
+}
+
+#[test]
+fn binds_inputs_and_outputs_by_name() {
+	let module = difference_module();
+	let mut synth = Synth::new();
+
+	assert!(synth.add_module(&module, &[("pitch", Input::Literal(1.0))]).is_err());
+	assert!(synth.add_module(&module, &[("level", Input::Literal(1.0))]).is_err());
+	assert!(synth.add_module(&module, &[("freq", Input::Literal(1.0)), ("freq", Input::Literal(2.0))]).is_err());
+
+	// Unconnected inputs read their defaults
+	let instance = synth.add_module(&module, &[]).unwrap();
+	assert_eq!(instance.outputs(), [instance.output("out").unwrap(), instance.output("osc").unwrap()]);
+	assert_eq!(instance.output("missing"), None);
+	assert_eq!(instance.parameter("freq"), None);
+	synth.set_output(instance.output("osc").unwrap());
+
+	let mut expected = Synth::new();
+	let osc = expected.new_sine(220.0);
+	expected.set_output(osc);
+
+	assert!(render(synth, 2000) == render(expected, 2000));
+}
+
+#[test]
+fn rejects_duplicate_port_names() {
+	let mut module = Module::new();
+	module.new_input("freq", 220.0).unwrap();
+
+	assert!(module.new_input("freq", 110.0).is_err());
+	assert!(module.new_parameter("freq").is_err());
+	assert!(module.new_parameter("level").is_ok());
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+// A few synths with parameter changes, a release and a replacement part way through buffers
+fn bounce(worker_count: usize) -> Vec<u32> {
+	let mut ctx = OfflineContext::new(44100.0, 100);
+	ctx.set_channels(2);
+	ctx.set_worker_count(worker_count);
+
+	let mut ids = Vec::new();
+	let mut params = Vec::new();
+
+	for i in 0..4 {
+		let mut synth = Synth::new();
+		let freq = synth.new_parameter();
+		synth.get_parameter(freq).set_value(110.0 * (i + 1) as f32);
+		synth.get_parameter(freq).set_sample_mode(SampleMode::Exponential(0.02));
+
+		let gate = synth.new_square(3.0 + i as f32);
+		let env = synth.new_env_adsr(0.01, 0.1, 0.5, 0.2, gate);
+		let osc = synth.new_bl_saw(freq);
+		let lp = synth.new_lowpass(osc, 1800.0);
+		let out = synth.new_multiply(lp, env);
+		let [left, right] = synth.new_pan(out, i as f32 / 2.0 - 0.75);
+		synth.set_outputs(&[left, right]);
+
+		params.push(freq);
+		ids.push(ctx.push_synth(synth).unwrap());
+	}
+
+	ctx.set_parameter_at(params[0], 330.0, 1234);
+	ctx.set_parameter_at(params[2], 55.0, 2050);
+	let mut output = ctx.render_frames(3000).data;
+
+	ctx.release_synth(ids[1], Release::Fade(0.05)).unwrap();
+
+	let mut replacement = Synth::new();
+	let osc = replacement.new_triangle(220.0);
+	replacement.set_output(osc);
+	ctx.replace_synth(ids[3], replacement).unwrap();
+
+	output.extend(ctx.render_frames(5000).data);
+	output.into_iter().map(f32::to_bits).collect()
+}
+
+#[test]
+fn bounces_are_bit_identical() {
+	let serial = bounce(0);
+	assert!(serial == bounce(0), "bounces differ without workers");
+
+	for &workers in [1, 3].iter() {
+		let parallel = bounce(workers);
+		assert!(parallel == bounce(workers), "bounces differ with {} workers", workers);
+
+		// The same as rendering on the evaluation thread alone, up to the order partial mixes are summed in
+		for (&a, &b) in serial.iter().zip(&parallel) {
+			let (a, b) = (f32::from_bits(a), f32::from_bits(b));
+			assert!((a - b).abs() < 1e-6, "{} != {} with {} workers", a, b, workers);
+		}
+	}
+
+	// With one worker every synth is mixed in the same order as without any
+	assert!(serial == bounce(1));
+}
+
+fn enveloped_synth() -> (Synth, NodeID, NodeID) {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(220.0);
+	let env = synth.new_env_ar(0.01, 0.01, 1.0);
+	let out = synth.new_multiply(osc, env);
+	synth.set_output(out);
+	(synth, osc, env)
+}
+
+#[test]
+fn releases_on_other_nodes_are_rejected() {
+	let mut ctx = OfflineContext::new(44100.0, 100);
+	let (synth, osc, env) = enveloped_synth();
+	let id = ctx.push_synth(synth).unwrap();
+
+	// Used to be treated as already silent, removing the synth straight away
+	assert!(ctx.release_synth(id, Release::Envelope(osc)).is_err());
+	assert!(ctx.render_frames(1000).data.iter().any(|&s| s != 0.0));
+
+	ctx.release_synth(id, Release::Envelope(env)).unwrap();
+	ctx.render_frames(2000);
+	assert_eq!(ctx.stats().synth_count, 0);
+
+	let ctx = Context::new(2, 256).unwrap();
+	let (synth, osc, env) = enveloped_synth();
+	let id = ctx.push_synth(synth).unwrap();
+
+	assert!(ctx.release_synth(id, Release::Envelope(osc)).is_err());
+	ctx.release_synth(id, Release::Envelope(env)).unwrap();
+}
+
+#[test]
+fn parameter_changes_land_on_their_frame() {
+	let mut ctx = bypass_context(44100.0, 256);
+
+	let mut synth = Synth::new();
+	let level = synth.new_parameter();
+	let out = synth.new_multiply(level, 1.0);
+	synth.set_output(out);
+	ctx.push_synth(synth).unwrap();
+
+	ctx.render_frames(300);
+
+	// An absolute frame, part way through the second buffer from now
+	ctx.set_parameter_at(level, 0.5, 700);
+	let output = ctx.render_frames(1000).data;
+
+	let first_changed = output.iter().position(|&s| s != 0.0).unwrap();
+	assert_eq!(ctx.current_time() - 1000 + first_changed as u64, 700);
+	assert!(output[first_changed..].iter().all(|&s| s == 0.5));
+}
+
+#[test]
+fn events_beyond_the_pending_limit_still_land() {
+	let mut ctx = bypass_context(44100.0, 256);
+
+	let mut synth = Synth::new();
+	let level = synth.new_parameter();
+	let out = synth.new_multiply(level, 1.0);
+	synth.set_output(out);
+	ctx.push_synth(synth).unwrap();
+
+	// Far more than the evaluation side holds at once, sent in time order.
+	// Changes sent together for the same frame apply in the order they were sent
+	for frame in 0..5000 {
+		ctx.set_parameter_at(level, -1.0, frame);
+		ctx.set_parameter_at(level, frame as f32, frame);
+	}
+
+	let output = ctx.render_frames(5000).data;
+	assert!(output.iter().enumerate().all(|(frame, &s)| s == frame as f32));
+}
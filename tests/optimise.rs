@@ -0,0 +1,78 @@
+mod common;
+
+use voi_synth::*;
+use common::render;
+
+// Renders a synth before and after optimising it
+fn render_optimised(synth: Synth) -> (Vec<f32>, Vec<f32>, OptimiseReport) {
+	let mut optimised = synth.duplicate();
+	let report = optimised.optimise().unwrap();
+	(render(synth, 2000), render(optimised, 2000), report)
+}
+
+#[test]
+fn folds_constants() {
+	let mut synth = Synth::new();
+	let freq = synth.new_multiply(110.0, 2.0);
+	let freq = synth.new_add(freq, 0.0);
+	let osc = synth.new_sine(freq);
+	let gain = synth.new_divide(1.0, 4.0);
+	let out = synth.new_multiply(osc, gain);
+	let out = synth.new_multiply(out, 1.0);
+	synth.set_output(out);
+
+	let (before, after, report) = render_optimised(synth);
+	assert_eq!(report.folded, 4);
+	assert_eq!(report.nodes_after, 2);
+	assert!(before == after);
+}
+
+#[test]
+fn merges_remaps() {
+	let mut synth = Synth::new();
+	let osc = synth.new_triangle(220.0);
+	let unipolar = synth.new_remap(osc, -1.0, 1.0, 0.0, 1.0);
+	let out = synth.new_remap(unipolar, 0.0, 1.0, -0.5, 0.25);
+	synth.set_output(out);
+
+	let (before, after, report) = render_optimised(synth);
+	assert_eq!(report.remaps_collapsed, 1);
+	assert_eq!(report.nodes_after, 2);
+
+	// Merged remaps are only equal up to rounding
+	for (a, b) in before.iter().zip(&after) {
+		assert!((a - b).abs() < 1e-6, "{} != {}", a, b);
+	}
+}
+
+#[test]
+fn removes_unused_nodes() {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(330.0);
+	let unused = synth.new_saw(440.0);
+	synth.new_lowpass(unused, 1000.0);
+	let out = synth.new_multiply(osc, 0.5);
+	synth.set_output(out);
+
+	let (before, after, report) = render_optimised(synth);
+	assert_eq!(report.eliminated(), 2);
+	assert!(report.node_map.get(unused).is_none());
+	assert!(before == after);
+}
+
+#[test]
+fn keeps_store_reads_before_writes() {
+	let mut synth = Synth::new();
+	let previous = synth.new_value_store();
+	let osc = synth.new_sine(440.0);
+
+	// Reads the previous sample. Folding it into the subtract would read the one just written
+	let delayed = synth.new_multiply(previous, 1.0);
+	synth.new_store_write(previous, osc);
+	let out = synth.new_sub(osc, delayed);
+	synth.set_output(out);
+
+	let (before, after, _) = render_optimised(synth);
+	assert!(before.iter().any(|&s| s != 0.0));
+	assert!(before == after);
+}
@@ -0,0 +1,141 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+const SAMPLE_RATE: f32 = 44100.0;
+const WINDOW: usize = 4096;
+
+// Frequencies that land exactly on a bin, so harmonics are easy to separate from aliases
+fn bin_frequency(bin: usize) -> f32 {
+	bin as f32 * SAMPLE_RATE / WINDOW as f32
+}
+
+fn render<F: FnOnce(&mut Synth) -> NodeID>(build: F, mode: EvaluationMode) -> Vec<f32> {
+	let mut synth = Synth::new();
+	let osc = build(&mut synth);
+	synth.set_output(osc);
+	synth.set_evaluation_mode(mode);
+
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	ctx.push_synth(synth).unwrap();
+
+	// Skip the first buffers, so the window starts mid waveform
+	ctx.render_frames(1000);
+	ctx.render_frames(WINDOW).data
+}
+
+// Energy in each bin up to nyquist, Hann windowed
+fn spectrum(samples: &[f32]) -> Vec<f64> {
+	use std::f64::consts::PI;
+
+	let n = samples.len();
+	let windowed: Vec<f64> = samples.iter().enumerate()
+		.map(|(i, &s)| s as f64 * (0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()))
+		.collect();
+
+	let twiddles: Vec<(f64, f64)> = (0..n)
+		.map(|i| (2.0 * PI * i as f64 / n as f64).sin_cos())
+		.collect();
+
+	(0..n/2).map(|bin| {
+		let (mut re, mut im) = (0.0, 0.0);
+
+		for (i, &s) in windowed.iter().enumerate() {
+			let (sin, cos) = twiddles[bin * i % n];
+			re += s * cos;
+			im -= s * sin;
+		}
+
+		re * re + im * im
+	}).collect()
+}
+
+// Energy near the fundamental, near any harmonic including the fundamental, and everywhere else
+fn harmonic_and_alias_energy(samples: &[f32], fundamental_bin: usize) -> (f64, f64, f64) {
+	let spectrum = spectrum(samples);
+	let mut fundamental = 0.0;
+	let mut harmonic = 0.0;
+	let mut alias = 0.0;
+
+	for (bin, &energy) in spectrum.iter().enumerate().skip(1) {
+		let nearest = (bin + fundamental_bin / 2) / fundamental_bin * fundamental_bin;
+
+		if nearest > 0 && (bin as isize - nearest as isize).abs() <= 3 {
+			harmonic += energy;
+
+			if nearest == fundamental_bin {
+				fundamental += energy;
+			}
+		} else {
+			alias += energy;
+		}
+	}
+
+	(fundamental, harmonic, alias)
+}
+
+fn compare_aliasing<N, B>(naive: N, band_limited: B)
+	where N: Fn(&mut Synth, f32) -> NodeID, B: Fn(&mut Synth, f32) -> NodeID
+{
+	for &bin in [97, 211, 389].iter() {
+		let freq = bin_frequency(bin);
+
+		let naive = render(|s| naive(s, freq), EvaluationMode::PerSample);
+		let band_limited = render(|s| band_limited(s, freq), EvaluationMode::PerSample);
+
+		let (naive_fundamental, naive_harmonic, naive_alias) = harmonic_and_alias_energy(&naive, bin);
+		let (bl_fundamental, bl_harmonic, bl_alias) = harmonic_and_alias_energy(&band_limited, bin);
+
+		let naive_ratio = 10.0 * (naive_alias / naive_harmonic).log10();
+		let bl_ratio = 10.0 * (bl_alias / bl_harmonic).log10();
+
+		assert!(bl_ratio < naive_ratio - 10.0,
+			"{} Hz: aliasing is {:.1} dB below harmonics band limited, against {:.1} dB naive", freq, -bl_ratio, -naive_ratio);
+
+		// Band limiting rolls off the top of the spectrum, but should leave the fundamental close to as it was
+		let fundamental_change = 10.0 * (bl_fundamental / naive_fundamental).log10();
+		assert!(fundamental_change.abs() < 0.5, "{} Hz: fundamental changed by {:.2} dB", freq, fundamental_change);
+	}
+}
+
+#[test]
+fn saw_aliases_less() {
+	compare_aliasing(|s, f| s.new_saw(f), |s, f| s.new_bl_saw(f));
+}
+
+#[test]
+fn square_aliases_less() {
+	compare_aliasing(|s, f| s.new_square(f), |s, f| s.new_bl_square(f));
+}
+
+#[test]
+fn triangle_aliases_less() {
+	compare_aliasing(|s, f| s.new_triangle(f), |s, f| s.new_bl_triangle(f));
+}
+
+#[test]
+fn block_evaluation_matches_per_sample() {
+	let build = |s: &mut Synth| {
+		let lfo = s.new_sine(3.0);
+		let freq = s.new_remap(lfo, -1.0, 1.0, 200.0, 6000.0);
+		let tri = s.new_bl_triangle(freq);
+		let square = s.new_bl_square(freq);
+		let saw = s.new_bl_saw(freq);
+		let sum = s.new_add(tri, square);
+		s.new_add(sum, saw)
+	};
+
+	assert_eq!(render(build, EvaluationMode::PerSample), render(build, EvaluationMode::Block));
+}
+
+#[test]
+fn silent_at_zero_frequency() {
+	for samples in [
+		render(|s| s.new_bl_saw(0.0), EvaluationMode::PerSample),
+		render(|s| s.new_bl_square(0.0), EvaluationMode::PerSample),
+		render(|s| s.new_bl_triangle(0.0), EvaluationMode::PerSample),
+	].iter() {
+		assert!(samples.iter().all(|&s| s == samples[0] && s.is_finite()));
+	}
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+const RAMP_TIME: f32 = 0.1;
+
+// Output of a parameter from the frame it's set from 0 to 1, for twice the ramp time
+fn ramp(mode: SampleMode, sample_rate: f32) -> Vec<f32> {
+	let mut synth = Synth::new();
+	let param = synth.new_parameter();
+	synth.get_parameter(param).set_sample_mode(mode);
+	let out = synth.new_multiply(param, 1.0);
+	synth.set_output(out);
+
+	let mut ctx = bypass_context(sample_rate, 64);
+	ctx.push_synth(synth).unwrap();
+	ctx.render_frames(100);
+
+	ctx.set_parameter_at(param, 1.0, 150);
+	let output = ctx.render_frames(50 + (2.0 * RAMP_TIME * sample_rate) as usize).data;
+
+	assert!(output[..50].iter().all(|&s| s == 0.0));
+	output[50..].to_vec()
+}
+
+// Checks against the expected curve, where t is how far through the ramp a frame is, counting
+// the frame the change lands on as the first step
+fn assert_follows<F: Fn(f32) -> f32>(output: &[f32], sample_rate: f32, curve: F) {
+	for (frame, &s) in output.iter().enumerate() {
+		let t = (frame + 1) as f32 / (RAMP_TIME * sample_rate);
+		let expected = curve(t);
+		assert!((s - expected).abs() < 1e-4, "frame {}: {} != {}", frame, s, expected);
+	}
+}
+
+const SAMPLE_RATES: [f32; 2] = [1000.0, 48000.0];
+
+#[test]
+fn step_jumps_straight_to_the_value() {
+	for &rate in &SAMPLE_RATES {
+		assert!(ramp(SampleMode::Step, rate).iter().all(|&s| s == 1.0));
+	}
+}
+
+#[test]
+fn linear_ramps_at_a_constant_rate() {
+	for &rate in &SAMPLE_RATES {
+		let output = ramp(SampleMode::Linear(RAMP_TIME), rate);
+		assert_follows(&output, rate, |t| t.min(1.0));
+
+		// Arrives exactly after the ramp time, whatever the sample rate
+		let ramp_frames = (RAMP_TIME * rate) as usize;
+		assert!(output[ramp_frames + 1..].iter().all(|&s| s == 1.0));
+	}
+}
+
+#[test]
+fn cubic_eases_in_and_out() {
+	for &rate in &SAMPLE_RATES {
+		let output = ramp(SampleMode::Cubic(RAMP_TIME), rate);
+		assert_follows(&output, rate, |t| { let t = t.min(1.0); t * t * (3.0 - 2.0 * t) });
+
+		// Slow at both ends, fastest through the middle
+		let ramp_frames = (RAMP_TIME * rate) as usize;
+		let step = |frame: usize| output[frame + 1] - output[frame];
+		assert!(step(ramp_frames / 2) > step(1) && step(ramp_frames / 2) > step(ramp_frames - 3));
+		assert!(output[ramp_frames + 1..].iter().all(|&s| s == 1.0));
+	}
+}
+
+#[test]
+fn exponential_approaches_with_its_time_constant() {
+	for &rate in &SAMPLE_RATES {
+		let output = ramp(SampleMode::Exponential(RAMP_TIME), rate);
+		assert_follows(&output, rate, |t| 1.0 - (-t).exp());
+
+		// About 63% of the way after one time constant, and nearly there after two
+		let ramp_frames = (RAMP_TIME * rate) as usize;
+		assert!((output[ramp_frames - 1] - 0.632).abs() < 0.001);
+		assert!(output[output.len() - 1] > 0.86 && output[output.len() - 1] < 1.0);
+	}
+}
+
+#[test]
+fn zero_times_behave_like_step() {
+	for mode in [SampleMode::Linear(0.0), SampleMode::Exponential(0.0), SampleMode::Cubic(0.0)] {
+		assert!(ramp(mode, 1000.0).iter().all(|&s| s == 1.0), "{:?}", mode);
+	}
+}
+
+#[test]
+fn retargeting_starts_from_where_the_ramp_got_to() {
+	let mut synth = Synth::new();
+	let param = synth.new_parameter();
+	synth.get_parameter(param).set_sample_mode(SampleMode::Linear(RAMP_TIME));
+	let out = synth.new_multiply(param, 1.0);
+	synth.set_output(out);
+
+	let mut ctx = bypass_context(1000.0, 64);
+	ctx.push_synth(synth).unwrap();
+
+	// Halfway up, then back down to 0 over another full ramp
+	ctx.set_parameter_at(param, 1.0, 100);
+	ctx.set_parameter_at(param, 0.0, 150);
+	let output = ctx.render_frames(300).data;
+
+	assert!((output[149] - 0.5).abs() < 1e-4);
+	for (frame, &s) in output[150..250].iter().enumerate() {
+		let expected = 0.5 * (1.0 - (frame + 1) as f32 / 100.0);
+		assert!((s - expected).abs() < 1e-4, "frame {}: {} != {}", frame, s, expected);
+	}
+	assert!(output[250..].iter().all(|&s| s == 0.0));
+}
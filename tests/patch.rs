@@ -0,0 +1,230 @@
+mod common;
+
+use voi_synth::*;
+use common::render;
+
+// Uses every node type
+fn build_synth() -> Synth {
+	let mut synth = Synth::new();
+	synth.set_gain(0.5);
+
+	let freq = synth.new_parameter();
+	synth.get_parameter(freq).set_value(220.0);
+	synth.get_parameter(freq).set_sample_mode(SampleMode::Linear(0.01));
+
+	let store = synth.new_value_store();
+	let buffer = synth.add_buffer(Buffer::from_interleaved(vec![0.0, 0.5, 1.0, 0.5, -0.25, -0.5], 2).with_sample_rate(22050.0)).unwrap();
+	let steps = synth.new_buffer(vec![1.0, 1.5, 2.0]).unwrap();
+	let frames = Buffer::from_interleaved(vec![0.0, 1.0, 0.0, -1.0, 1.0, 1.0, -1.0, -1.0], 1);
+	let table = synth.add_wavetable(Wavetable::from_buffer(&frames, 4).unwrap()).unwrap();
+
+	let freq_sampler = synth.new_param_sampler(freq, SampleMode::Exponential(0.05));
+	let gate = synth.new_square(2.0);
+	let gate = synth.new_signal_to_control(gate);
+	let step = synth.new_sequencer(steps, gate, 0.0);
+	let freq_mod = synth.new_multiply(freq_sampler, step);
+
+	let sine = synth.new_sine(freq_mod);
+	let tri = synth.new_triangle(freq);
+	let saw = synth.new_saw(110.0);
+	let bl_saw = synth.new_bl_saw(110.0);
+	let bl_square = synth.new_bl_square(55.0);
+	let bl_tri = synth.new_bl_triangle(freq);
+	let bl = synth.new_add(bl_saw, bl_square);
+	let bl = synth.new_add(bl, bl_tri);
+	let wavetable = synth.new_wavetable(table, freq, gate);
+	let bl = synth.new_add(bl, wavetable);
+	let square = synth.new_square(store);
+	let sampler = synth.new_sampler_channel(buffer, 1, 0.0);
+
+	let mix = synth.new_mix(sine, tri, 0.25);
+	let sum = synth.new_add(mix, saw);
+	let sum = synth.new_add(sum, bl);
+	let diff = synth.new_sub(sum, square);
+	let quot = synth.new_divide(diff, 4.0);
+	let pow = synth.new_power(2.0, sampler);
+	let lp = synth.new_lowpass(quot, 2000.0);
+	let hp = synth.new_highpass(lp, 20.0);
+	let clamped = synth.new_clamp(hp, -0.8, 0.8);
+
+	let ar = synth.new_env_ar(0.01, 0.1, gate);
+	let adsr = synth.new_env_adsr(0.01, 0.05, 0.6, 0.2, gate);
+	let env = synth.new_multiply(ar, adsr);
+	let out = synth.new_multiply(clamped, env);
+	let out = synth.new_add(out, pow);
+	let out = synth.new_multiply(out, 0.1);
+	synth.new_store_write(store, out);
+
+	let bus = synth.new_bus_input(1);
+	let out = synth.new_add(out, bus);
+	let [left, right] = synth.new_pan(out, -0.3);
+
+	synth.set_outputs(&[left, right]);
+	synth.set_label(sine, "lead \"osc\"");
+	synth.set_label(lp, "filter #1");
+	synth
+}
+
+#[test]
+fn text_round_trip() {
+	let synth = build_synth();
+	let text = synth.to_patch_text().unwrap();
+	let loaded = Synth::from_patch_text(&text).unwrap();
+
+	assert_eq!(loaded.to_patch_text().unwrap(), text);
+	assert_eq!(loaded.get_labelled_node("lead \"osc\""), synth.get_labelled_node("lead \"osc\""));
+	assert_eq!(loaded.get_labelled_node("filter #1"), synth.get_labelled_node("filter #1"));
+	assert_eq!(loaded.channels(), 2);
+	assert_eq!(render(loaded, 4096), render(synth, 4096));
+}
+
+#[test]
+fn binary_round_trip() {
+	let synth = build_synth();
+	let bytes = synth.to_patch_bytes().unwrap();
+	let loaded = Synth::from_patch_bytes(&bytes).unwrap();
+
+	assert_eq!(loaded.to_patch_bytes().unwrap(), bytes);
+	assert_eq!(loaded.to_patch_text().unwrap(), synth.to_patch_text().unwrap());
+	assert_eq!(render(loaded, 4096), render(synth, 4096));
+}
+
+#[test]
+fn text_and_binary_agree() {
+	let synth = build_synth();
+	let from_text = Synth::from_patch_text(&synth.to_patch_text().unwrap()).unwrap();
+	let from_binary = Synth::from_patch_bytes(&synth.to_patch_bytes().unwrap()).unwrap();
+
+	assert_eq!(from_text.to_patch_bytes().unwrap(), from_binary.to_patch_bytes().unwrap());
+}
+
+#[test]
+fn files_round_trip() {
+	let synth = build_synth();
+	let dir = std::env::temp_dir();
+
+	for (name, format) in [("voi_patch_test.txt", PatchFormat::Text), ("voi_patch_test.bin", PatchFormat::Binary)].iter() {
+		let path = dir.join(name);
+		synth.save_patch(&path, *format).unwrap();
+		let loaded = Synth::load_patch(&path).unwrap();
+		std::fs::remove_file(&path).unwrap();
+
+		assert_eq!(loaded.to_patch_text().unwrap(), synth.to_patch_text().unwrap());
+	}
+}
+
+#[test]
+fn loaded_parameters_belong_to_the_loaded_synth() {
+	let mut synth = Synth::new();
+	let param = synth.new_parameter();
+	let osc = synth.new_sine(param);
+	synth.set_output(osc);
+
+	let mut loaded = Synth::from_patch_text(&synth.to_patch_text().unwrap()).unwrap();
+	let loaded_param = loaded.parameter_of_duplicate(param);
+	loaded.get_parameter(loaded_param).set_value(440.0);
+
+	let mut ctx = OfflineContext::new(44100.0, 64);
+	ctx.push_synth(loaded).unwrap();
+}
+
+#[test]
+fn hand_written_text() {
+	let text = "
+		voi-synth-patch 1
+		# A quiet sine
+		param 0 330.0 step
+		node 0 sine param:0
+		node 1 multiply node:0 0.25   # gain
+		output 1
+	";
+
+	let loaded = Synth::from_patch_text(text).unwrap();
+
+	let mut synth = Synth::new();
+	let freq = synth.new_parameter();
+	synth.get_parameter(freq).set_value(330.0);
+	let osc = synth.new_sine(freq);
+	let out = synth.new_multiply(osc, 0.25);
+	synth.set_output(out);
+
+	assert_eq!(render(loaded, 4096), render(synth, 4096));
+}
+
+#[test]
+fn version_mismatch() {
+	let err = Synth::from_patch_text("voi-synth-patch 2\n").unwrap_err().to_string();
+	assert!(err.contains("version 2"), "{}", err);
+
+	let mut bytes = build_synth().to_patch_bytes().unwrap();
+	bytes[4] = 7;
+	let err = Synth::from_patch_bytes(&bytes).unwrap_err().to_string();
+	assert!(err.contains("version 7"), "{}", err);
+}
+
+#[test]
+fn not_a_patch() {
+	assert!(Synth::from_patch_text("node 0 sine 440.0\n").is_err());
+	assert!(Synth::from_patch_bytes(b"RIFF....").is_err());
+}
+
+#[test]
+fn dangling_references() {
+	let text = "
+		voi-synth-patch 1
+		node 0 sine node:5
+		node 1 add store:0 param:2
+		node 2 sampler local:1 0 0.0
+		output 3
+		label 4 \"gone\"
+	";
+
+	let err = Synth::from_patch_text(text).unwrap_err().to_string();
+
+	for problem in &["reads node 5", "reads store 0", "uses parameter 2", "uses local buffer 1", "output node 3", "refers to node 4"] {
+		assert!(err.contains(problem), "'{}' missing from: {}", problem, err);
+	}
+}
+
+#[test]
+fn malformed_text_reports_line() {
+	let err = Synth::from_patch_text("voi-synth-patch 1\ngain 1.0\nnode 0 sine node:x\n").unwrap_err().to_string();
+	assert!(err.starts_with("Line 3:"), "{}", err);
+
+	let err = Synth::from_patch_text("voi-synth-patch 1\nnode 1 sine 1.0\n").unwrap_err().to_string();
+	assert!(err.contains("out of order"), "{}", err);
+
+	let err = Synth::from_patch_text("voi-synth-patch 1\nnode 0 wobble 1.0\n").unwrap_err().to_string();
+	assert!(err.contains("wobble"), "{}", err);
+
+	let err = Synth::from_patch_text("voi-synth-patch 1\ngain 1.0 2.0\n").unwrap_err().to_string();
+	assert!(err.contains("Unexpected '2.0'"), "{}", err);
+
+	// Labels have to be quoted, and nothing else can be
+	let err = Synth::from_patch_text("voi-synth-patch 1\nnode 0 sine 1.0\nlabel 0 osc\n").unwrap_err().to_string();
+	assert!(err.contains("Expected a quoted string, found 'osc'"), "{}", err);
+
+	let err = Synth::from_patch_text("voi-synth-patch 1\nnode 0 sine \"1.0\"\n").unwrap_err().to_string();
+	assert!(err.starts_with("Line 2:"), "{}", err);
+	assert!(Synth::from_patch_text("voi-synth-patch 1\n\"gain\" 1.0\n").is_err());
+}
+
+#[test]
+fn truncated_binary() {
+	let bytes = build_synth().to_patch_bytes().unwrap();
+
+	for len in (8..bytes.len()).step_by(7) {
+		assert!(Synth::from_patch_bytes(&bytes[..len]).is_err());
+	}
+}
+
+#[test]
+fn foreign_parameters_are_rejected() {
+	let mut owner = Synth::new();
+	let param = owner.new_parameter();
+
+	let mut synth = Synth::new();
+	synth.new_sine(param);
+
+	assert!(synth.to_patch_text().is_err());
+}
@@ -0,0 +1,121 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+fn sine_synth(freq: f32) -> Synth {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(freq);
+	synth.set_output(osc);
+	synth
+}
+
+fn silent_synth() -> Synth {
+	let mut synth = Synth::new();
+	let out = synth.new_multiply(0.0, 0.0);
+	synth.set_output(out);
+	synth
+}
+
+fn is_silent(samples: &[f32]) -> bool {
+	samples.iter().all(|&s| s == 0.0)
+}
+
+#[test]
+fn crossfades_into_the_new_graph_keeping_state() {
+	let mut ctx = bypass_context(44100.0, 64);
+	let id = ctx.push_synth(sine_synth(440.0)).unwrap();
+	ctx.render_frames(1000);
+
+	// The same graph, so the crossfade is between two sines in phase
+	ctx.replace_synth_with_crossfade(id, sine_synth(440.0), 0.01).unwrap();
+	let replaced = ctx.render_frames(2000).data;
+
+	let mut reference = bypass_context(44100.0, 64);
+	reference.push_synth(sine_synth(440.0)).unwrap();
+	reference.render_frames(1000);
+	let expected = reference.render_frames(2000).data;
+
+	for (a, b) in replaced.iter().zip(&expected) {
+		assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+	}
+
+	// Then only the new graph plays
+	ctx.replace_synth_with_crossfade(id, silent_synth(), 0.01).unwrap();
+	ctx.render_frames(1000);
+	assert!(is_silent(&ctx.render_frames(100).data));
+	assert_eq!(ctx.stats().synth_count, 1);
+}
+
+#[test]
+fn synths_that_have_gone_stay_gone() {
+	let mut ctx = bypass_context(44100.0, 64);
+
+	let removed = ctx.push_synth(sine_synth(440.0)).unwrap();
+	ctx.render_frames(100);
+	ctx.remove_synth(removed);
+	ctx.replace_synth(removed, sine_synth(330.0)).unwrap();
+
+	// Finishes as soon as it's rendered
+	let mut one_shot = silent_synth();
+	one_shot.set_one_shot(0.01, 0.0);
+	let finished = ctx.push_synth(one_shot).unwrap();
+	ctx.render_frames(100);
+	ctx.replace_synth(finished, sine_synth(220.0)).unwrap();
+
+	assert!(is_silent(&ctx.render_frames(1000).data));
+	assert_eq!(ctx.stats().synth_count, 0);
+}
+
+#[test]
+fn releases_carry_over() {
+	let mut ctx = bypass_context(44100.0, 64);
+
+	let faded = ctx.push_synth(sine_synth(440.0)).unwrap();
+	ctx.release_synth(faded, Release::Fade(0.01)).unwrap();
+	ctx.render_frames(200);
+
+	// Continues the fade from where it had got to, rather than fading back in
+	ctx.replace_synth(faded, sine_synth(330.0)).unwrap();
+	let fading = ctx.render_frames(300).data;
+	let peak = |samples: &[f32]| samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+	assert!(peak(&fading) < 0.6, "{}", peak(&fading));
+
+	ctx.render_frames(500);
+	assert_eq!(ctx.stats().synth_count, 0);
+
+	// Envelope releases follow the envelope into the new graph
+	let enveloped = || {
+		let mut synth = Synth::new();
+		let env = synth.new_env_ar(0.001, 0.01, 1.0);
+		let osc = synth.new_sine(440.0);
+		let out = synth.new_multiply(osc, env);
+		synth.set_output(out);
+		(synth, env)
+	};
+
+	let (synth, env) = enveloped();
+	let id = ctx.push_synth(synth).unwrap();
+	ctx.render_frames(500);
+	ctx.release_synth(id, Release::Envelope(env)).unwrap();
+	ctx.render_frames(100);
+	ctx.replace_synth(id, enveloped().0).unwrap();
+
+	ctx.render_frames(1000);
+	assert_eq!(ctx.stats().synth_count, 0);
+}
+
+#[test]
+fn one_shots_carry_over() {
+	let mut ctx = bypass_context(44100.0, 64);
+
+	let mut one_shot = sine_synth(440.0);
+	one_shot.set_one_shot(0.01, 0.01);
+	let id = ctx.push_synth(one_shot).unwrap();
+	ctx.render_frames(100);
+
+	// Silent and not a one-shot itself, but it takes over the original's one-shot timing
+	ctx.replace_synth(id, silent_synth()).unwrap();
+	ctx.render_frames(2000);
+	assert_eq!(ctx.stats().synth_count, 0);
+}
@@ -0,0 +1,78 @@
+use voi_synth::*;
+
+fn sine_synth() -> Synth {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(440.0);
+	let [left, right] = synth.new_pan(osc, 0.25);
+	synth.set_outputs(&[left, right]);
+	synth
+}
+
+#[test]
+fn offline_recording_matches_render() {
+	let path = std::env::temp_dir().join("voi_sink_offline.wav");
+
+	let mut ctx = OfflineContext::new(44100.0, 300);
+	ctx.set_channels(2);
+	ctx.push_synth(sine_synth()).unwrap();
+
+	// Enough to go through the writer's buffers several times over
+	let sink = WavSink::create(&path, SampleFormat::Float32, 44100, 2).unwrap();
+	assert!(ctx.attach_sink(sink).unwrap().is_none());
+	let rendered = ctx.render_frames(44100 * 4);
+
+	let sink = ctx.detach_sink().unwrap();
+	assert_eq!(sink.dropped_samples(), 0);
+	sink.finish().unwrap();
+
+	let recorded = load_audio_file(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(recorded.channels, 2);
+	assert!(recorded.samples == rendered.data);
+}
+
+#[test]
+fn realtime_sink_finishes_after_detach() {
+	let path = std::env::temp_dir().join("voi_sink_realtime.wav");
+
+	let mut ctx = Context::new(3, 256).unwrap();
+	ctx.push_synth(sine_synth()).unwrap();
+
+	let sample_rate = ctx.get_sample_rate() as u32;
+	let sink = WavSink::create(&path, SampleFormat::Float32, sample_rate, 1).unwrap();
+	assert!(ctx.attach_sink(sink).unwrap().is_none());
+
+	for _ in 0..16 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	// Used to wait for the detached handle to be dropped, which only happened on garbage collection
+	ctx.detach_sink().unwrap().finish().unwrap();
+
+	let recorded = load_audio_file(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert!(!recorded.samples.is_empty());
+}
+
+#[test]
+fn sinks_must_match_the_context() {
+	let path = std::env::temp_dir().join("voi_sink_mismatch.wav");
+
+	let mut ctx = OfflineContext::new(44100.0, 256);
+	ctx.set_channels(2);
+
+	let sink = WavSink::create(&path, SampleFormat::Int16, 44100, 1).unwrap();
+	assert!(ctx.attach_sink(sink).is_err());
+
+	let sink = WavSink::create(&path, SampleFormat::Int16, 48000, 2).unwrap();
+	assert!(ctx.attach_sink(sink).is_err());
+
+	let mut ctx = Context::new(3, 256).unwrap();
+	let sink = WavSink::create(&path, SampleFormat::Int16, 48000, 1).unwrap();
+	assert!(ctx.attach_sink(sink).is_err());
+
+	std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+fn sine_synth() -> Synth {
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(1.0);
+	synth.set_output(osc);
+	synth
+}
+
+#[test]
+fn records_each_buffer() {
+	let mut ctx = bypass_context(44100.0, 256);
+	let first = ctx.push_synth(sine_synth()).unwrap();
+	let second = ctx.push_synth(sine_synth()).unwrap();
+
+	assert_eq!(ctx.stats().buffers_filled, 0);
+
+	// Three full buffers and a partial one
+	ctx.render_frames(1000);
+	let stats = ctx.stats();
+
+	assert_eq!(stats.buffers_filled, 4);
+	assert_eq!(stats.buffer_deadline, 232.0 / 44100.0 * 1000000.0);
+
+	assert!(stats.median_fill_time > 0.0);
+	assert!(stats.median_fill_time <= stats.p95_fill_time);
+	assert!(stats.p95_fill_time <= stats.p99_fill_time);
+	assert!(stats.p99_fill_time <= stats.peak_fill_time);
+	assert!((stats.cpu_load - stats.average_fill_time / stats.buffer_deadline).abs() < 1e-6);
+
+	let ids: Vec<_> = stats.synth_times.iter().map(|&(id, _)| id).collect();
+	assert_eq!(ids, [first, second]);
+	assert_eq!(stats.synth_count, 2);
+
+	// Keeps counting once the fill time history wraps around
+	ctx.render_frames(256 * FILL_TIME_HISTORY);
+	assert_eq!(ctx.stats().buffers_filled, 4 + FILL_TIME_HISTORY as u64);
+}
+
+#[test]
+fn counts_deadline_misses() {
+	// At a billion frames per second a buffer of 64 frames has 64 nanoseconds to fill,
+	// which isn't enough for anything, so every buffer misses
+	let mut ctx = bypass_context(1.0e9, 64);
+	ctx.push_synth(sine_synth()).unwrap();
+
+	ctx.render_frames(640);
+	let stats = ctx.stats();
+
+	assert_eq!(stats.buffers_filled, 10);
+	assert_eq!(stats.deadline_misses, 10);
+	assert!(stats.cpu_load > 1.0);
+
+	// And at 100 frames per second the same buffer has 0.64 seconds, so none do
+	let mut ctx = bypass_context(100.0, 64);
+	ctx.push_synth(sine_synth()).unwrap();
+	ctx.render_frames(640);
+	let stats = ctx.stats();
+
+	assert_eq!(stats.buffers_filled, 10);
+	assert_eq!(stats.deadline_misses, 0);
+	assert!(stats.cpu_load < 1.0);
+}
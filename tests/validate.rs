@@ -0,0 +1,122 @@
+use voi_synth::*;
+
+fn push_error(ctx: &mut OfflineContext, synth: Synth) -> String {
+	ctx.push_synth(synth).unwrap_err().to_string()
+}
+
+#[test]
+fn accepts_valid_graphs() {
+	let mut ctx = OfflineContext::new(44100.0, 64);
+	let shared = ctx.create_shared_buffer(vec![0.5, -0.5]).unwrap();
+
+	let mut synth = Synth::new();
+	let freq = synth.new_parameter();
+	let feedback = synth.new_value_store();
+	let local = synth.new_buffer(vec![1.0, 0.0]).unwrap();
+
+	let osc = synth.new_sine(freq);
+	let fm = synth.new_add(osc, feedback);
+	synth.new_store_write(feedback, fm);
+	let a = synth.new_sampler(local, 0.0);
+	let b = synth.new_sampler(shared, 0.0);
+	let mix = synth.new_mix(a, b, fm);
+	synth.set_output(mix);
+	synth.set_label(osc, "osc");
+
+	ctx.push_synth(synth).unwrap();
+}
+
+// Ids can only be made by adding nodes, so ids that are out of place come from another synth
+fn other_node(idx: usize) -> NodeID {
+	let mut other = Synth::new();
+	(0..=idx).map(|_| other.new_sine(1.0)).last().unwrap()
+}
+
+#[test]
+fn rejects_dangling_nodes() {
+	let mut ctx = OfflineContext::new(44100.0, 64);
+
+	let missing = other_node(4);
+
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(missing);
+	synth.set_output(osc);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("reads node 4, which doesn't exist"), "{}", err);
+
+	// Reading a later node is only allowed once the synth has been compiled
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(other_node(1));
+	synth.new_sine(440.0);
+	synth.set_output(osc);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("reads node 1, which is evaluated after it"), "{}", err);
+
+	let mut synth = Synth::new();
+	let input = synth.new_sine(220.0);
+	synth.new_sine(missing);
+	let out = synth.new_add(input, input);
+	synth.set_output(out);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("node 1 (sine) reads node 4"), "{}", err);
+}
+
+#[test]
+fn rejects_out_of_range_stores_and_parameters() {
+	let mut ctx = OfflineContext::new(44100.0, 64);
+
+	let mut other = Synth::new();
+	other.new_value_store();
+	let store = other.new_value_store();
+	other.new_parameter();
+	let param = other.new_parameter();
+
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(store);
+	synth.new_store_write(store, osc);
+	synth.set_output(osc);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("reads store 1, which doesn't exist"), "{}", err);
+	assert!(err.contains("writes store 1, which doesn't exist"), "{}", err);
+
+	let mut synth = Synth::new();
+	let osc = synth.new_sine(param);
+	synth.set_output(osc);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("belongs to another synth"), "{}", err);
+
+	let mut synth = Synth::new();
+	let own_param = synth.parameter_of_duplicate(param);
+	let osc = synth.new_sine(own_param);
+	synth.set_output(osc);
+	let err = push_error(&mut ctx, synth);
+	assert!(err.contains("uses parameter 1, which doesn't exist"), "{}", err);
+}
+
+#[test]
+fn rejects_freed_and_stale_shared_buffers() {
+	let mut ctx = OfflineContext::new(44100.0, 64);
+
+	let freed = ctx.create_shared_buffer(vec![1.0]).unwrap();
+	ctx.free_shared_buffer(freed).unwrap();
+
+	let sampler_of = |buffer| {
+		let mut synth = Synth::new();
+		let sampler = synth.new_sampler(buffer, 0.0);
+		synth.set_output(sampler);
+		synth
+	};
+
+	let err = push_error(&mut ctx, sampler_of(freed));
+	assert!(err.contains("has been freed"), "{}", err);
+
+	// The slot is reused, but the old id still refers to the freed buffer
+	let reused = ctx.create_shared_buffer(vec![1.0]).unwrap();
+	let err = push_error(&mut ctx, sampler_of(freed));
+	assert!(err.contains("has been freed"), "{}", err);
+	ctx.push_synth(sampler_of(reused)).unwrap();
+
+	let mut other = OfflineContext::new(44100.0, 64);
+	let err = push_error(&mut other, sampler_of(reused));
+	assert!(err.contains("doesn't exist"), "{}", err);
+}
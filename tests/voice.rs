@@ -0,0 +1,126 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+const SAMPLE_RATE: f32 = 1000.0;
+
+// Outputs its envelope, so the output of a pool is the sum of its voices' envelopes
+fn voice_pool(ctx: &mut OfflineContext, voice_count: usize, policy: StealPolicy) -> VoicePool {
+	let mut synth = Synth::new();
+	let pitch = synth.new_parameter();
+	let gate = synth.new_parameter();
+	let envelope = synth.new_env_adsr(0.1, 0.01, 0.5, 0.05, gate);
+	synth.set_output(envelope);
+
+	let config = VoiceConfig { pitch, gate, velocity: None, envelope, policy };
+	VoicePool::new(ctx, &synth, voice_count, config).unwrap()
+}
+
+fn held_keys(pool: &VoicePool) -> Vec<u8> {
+	let mut keys: Vec<_> = pool.held_keys().collect();
+	keys.sort();
+	keys
+}
+
+#[test]
+fn retriggers_held_envelope() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 64);
+	let mut pool = voice_pool(&mut ctx, 1, StealPolicy::SameNoteRetrigger);
+
+	pool.note_on(&ctx, 60, 100);
+	let output = ctx.render_frames(300).data;
+	assert_eq!(output[299], 0.5);
+
+	// The gate is high the whole time, so only the retrigger can restart the attack
+	pool.note_on(&ctx, 60, 100);
+	let output = ctx.render_frames(100).data;
+
+	// Envelopes change state a frame after their gate, the same as for a note starting from silence
+	assert_eq!(output[1], 0.5);
+	assert!(output[2] > output[1]);
+	assert!(output.iter().any(|&s| s > 0.99));
+
+	assert_eq!(held_keys(&pool), [60]);
+	assert_eq!(pool.active_voices(&ctx), 1);
+}
+
+#[test]
+fn steals_oldest_voice() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 64);
+	let mut pool = voice_pool(&mut ctx, 2, StealPolicy::Oldest);
+
+	pool.note_on(&ctx, 60, 100);
+	ctx.render_frames(50);
+	pool.note_on(&ctx, 62, 100);
+	ctx.render_frames(10);
+	pool.note_on(&ctx, 64, 100);
+
+	assert_eq!(held_keys(&pool), [62, 64]);
+	assert_eq!(pool.active_voices(&ctx), 2);
+
+	// The stolen voice restarts its attack from where it was
+	let output = ctx.render_frames(3).data;
+	assert!(output[2] > output[1]);
+}
+
+#[test]
+fn steals_quietest_voice() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 64);
+	let mut pool = voice_pool(&mut ctx, 2, StealPolicy::Quietest);
+
+	pool.note_on(&ctx, 60, 100);
+	ctx.render_frames(50);
+	pool.note_on(&ctx, 62, 100);
+	ctx.render_frames(10);
+	pool.note_on(&ctx, 64, 100);
+
+	assert_eq!(held_keys(&pool), [60, 64]);
+}
+
+#[test]
+fn reuses_released_voices_first() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 64);
+	let mut pool = voice_pool(&mut ctx, 2, StealPolicy::Oldest);
+
+	pool.note_on(&ctx, 60, 100);
+	pool.note_on(&ctx, 62, 100);
+	ctx.render_frames(200);
+
+	// Still releasing, but stolen before the older held note
+	pool.note_off(&ctx, 62);
+	ctx.render_frames(10);
+	assert_eq!(pool.active_voices(&ctx), 2);
+
+	pool.note_on(&ctx, 64, 100);
+	assert_eq!(held_keys(&pool), [60, 64]);
+
+	// Once released voices go silent they are free again
+	pool.all_notes_off(&ctx);
+	ctx.render_frames(500);
+	assert_eq!(pool.active_voices(&ctx), 0);
+}
+
+#[test]
+fn voices_start_silent() {
+	let mut ctx = Context::new(3, 256).unwrap();
+
+	// A template with its gate left high still gives voices that wait for a note
+	let mut synth = Synth::new();
+	let pitch = synth.new_parameter();
+	let gate = synth.new_parameter();
+	synth.get_parameter(gate).set_value(1.0);
+	let envelope = synth.new_env_ar(0.01, 0.01, gate);
+	synth.set_output(envelope);
+
+	let config = VoiceConfig { pitch, gate, velocity: None, envelope, policy: StealPolicy::Oldest };
+	let pool = VoicePool::new(&mut ctx, &synth, 2, config).unwrap();
+
+	for _ in 0..4 {
+		let buffer = ctx.get_ready_buffer().unwrap();
+		assert!(buffer.data.iter().all(|&s| s == 0.0));
+		ctx.queue_empty_buffer(buffer).unwrap();
+	}
+
+	assert_eq!(pool.active_voices(&ctx), 0);
+}
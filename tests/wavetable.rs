@@ -0,0 +1,138 @@
+mod common;
+
+use voi_synth::*;
+use common::bypass_context;
+
+const SAMPLE_RATE: f32 = 44100.0;
+const WINDOW: usize = 4096;
+
+fn bin_frequency(bin: usize) -> f32 {
+	bin as f32 * SAMPLE_RATE / WINDOW as f32
+}
+
+fn render<F: FnOnce(&mut Synth) -> NodeID>(ctx: &mut OfflineContext, build: F, frames: usize) -> Vec<f32> {
+	let mut synth = Synth::new();
+	let osc = build(&mut synth);
+	synth.set_output(osc);
+
+	let id = ctx.push_synth(synth).unwrap();
+	let samples = ctx.render_frames(frames).data;
+	ctx.remove_synth(id);
+	samples
+}
+
+fn saw_table(frame_size: usize) -> Wavetable {
+	let saw = (0..frame_size).map(|i| i as f32 / frame_size as f32 * 2.0 - 1.0).collect();
+	Wavetable::from_buffer(&Buffer::from_interleaved(saw, 1), frame_size).unwrap()
+}
+
+// Share of the energy of a Hann windowed signal within a few bins of bin
+fn energy_near_bin(samples: &[f32], bin: usize) -> f64 {
+	use std::f64::consts::PI;
+
+	let n = samples.len();
+	let windowed: Vec<f64> = samples.iter().enumerate()
+		.map(|(i, &s)| s as f64 * (0.5 - 0.5 * (2.0 * PI * i as f64 / n as f64).cos()))
+		.collect();
+
+	let near: f64 = (bin-3 ..= bin+3).map(|bin| {
+		let (mut re, mut im) = (0.0, 0.0);
+
+		for (i, &s) in windowed.iter().enumerate() {
+			let (sin, cos) = (2.0 * PI * (bin * i % n) as f64 / n as f64).sin_cos();
+			re += s * cos;
+			im -= s * sin;
+		}
+
+		re * re + im * im
+	}).sum();
+
+	// Parseval, with the energy split evenly between positive and negative frequencies
+	let total: f64 = windowed.iter().map(|s| s * s).sum::<f64>() * n as f64 / 2.0;
+	near / total
+}
+
+#[test]
+fn baked_sine_plays_back_as_a_sine() {
+	let mut baker = Synth::new();
+	let sine = baker.new_sine(1.0);
+	baker.set_output(sine);
+
+	let table = Wavetable::bake(baker, 1024, 1).unwrap();
+	assert_eq!(table.frames(), 1);
+
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	let table = ctx.add_wavetable(table).unwrap();
+
+	// Baking starts a sample into the cycle, which shows up as a fixed phase offset
+	let offset = 2.0 * std::f32::consts::PI / 1024.0;
+	let freq = 440.0;
+	let played = render(&mut ctx, |s| s.new_wavetable(table, freq, 0.0), 2048);
+
+	for (i, &sample) in played.iter().enumerate() {
+		let expected = (2.0 * std::f32::consts::PI * freq * (i + 1) as f32 / SAMPLE_RATE + offset).sin();
+		assert!((sample - expected).abs() < 1e-3, "sample {} is {}, expected {}", i, sample, expected);
+	}
+}
+
+#[test]
+fn high_notes_only_keep_harmonics_below_nyquist() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	let table = ctx.add_wavetable(saw_table(2048)).unwrap();
+
+	// Only the fundamental of a saw this high fits below nyquist
+	let bin = 1114;
+	let freq = bin_frequency(bin);
+
+	let naive = render(&mut ctx, |s| s.new_saw(freq), WINDOW);
+	let played = render(&mut ctx, |s| s.new_wavetable(table, freq, 0.0), WINDOW);
+
+	assert!(energy_near_bin(&naive, bin) < 0.9);
+	assert!(energy_near_bin(&played, bin) > 0.999, "{}", energy_near_bin(&played, bin));
+}
+
+#[test]
+fn position_crossfades_between_frames() {
+	let frame_size = 64;
+	let sine: Vec<f32> = (0..frame_size).map(|i| (2.0 * std::f32::consts::PI * i as f32 / frame_size as f32).sin()).collect();
+	let frames: Vec<f32> = sine.iter().cloned().chain(sine.iter().map(|s| -s)).collect();
+
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	let table = Wavetable::from_buffer(&Buffer::from_interleaved(frames, 1), frame_size).unwrap();
+	assert_eq!(table.frames(), 2);
+	let table = ctx.add_wavetable(table).unwrap();
+
+	let first = render(&mut ctx, |s| s.new_wavetable(table, 220.0, 0.0), 512);
+	let middle = render(&mut ctx, |s| s.new_wavetable(table, 220.0, 0.5), 512);
+	let last = render(&mut ctx, |s| s.new_wavetable(table, 220.0, 1.0), 512);
+	let past_last = render(&mut ctx, |s| s.new_wavetable(table, 220.0, 3.0), 512);
+
+	assert!(first.iter().any(|&s| s > 0.9));
+	assert!(middle.iter().all(|&s| s.abs() < 1e-6));
+	assert!(first.iter().zip(&last).all(|(&a, &b)| (a + b).abs() < 1e-6));
+	assert_eq!(last, past_last);
+}
+
+#[test]
+fn invalid_layouts_are_rejected() {
+	assert!(Wavetable::from_buffer(&Buffer::new(3000), 1000).is_err());
+	assert!(Wavetable::from_buffer(&Buffer::new(1000), 256).is_err());
+	assert!(Wavetable::from_buffer(&Buffer::new(0), 256).is_err());
+	assert!(Wavetable::from_buffer(&Buffer::with_channels(512, 2), 256).is_err());
+	assert!(Wavetable::bake(Synth::new(), 256, 0).is_err());
+}
+
+#[test]
+fn freed_tables_read_silence() {
+	let mut ctx = bypass_context(SAMPLE_RATE, 256);
+	let table = ctx.add_wavetable(saw_table(256)).unwrap();
+
+	let mut synth = Synth::new();
+	let osc = synth.new_wavetable(table, 440.0, 0.0);
+	synth.set_output(osc);
+	ctx.push_synth(synth.clone()).unwrap();
+
+	ctx.free_shared_buffer(table.buffer_id()).unwrap();
+	assert!(ctx.render_frames(256).data.iter().all(|&s| s == 0.0));
+	assert!(ctx.push_synth(synth).is_err());
+}